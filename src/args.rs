@@ -21,11 +21,12 @@
  * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
  * DEALINGS IN THE SOFTWARE.
  */
-use clap::Parser;
+use crate::state::merger::MergeStrategy;
+use clap::{Parser, Subcommand};
 
 /// Isabelle - high-performant server for web applications
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, subcommand_negates_reqs = true)]
 pub struct Args {
     /// Data path
     #[arg(long, default_value("sample-data"))]
@@ -39,7 +40,7 @@ pub struct Args {
     #[arg(long, default_value("localhost"))]
     pub pub_fqdn: String,
 
-    /// Database URL
+    /// Database URL (mongodb://, file:// or sqlite://)
     #[arg(long, default_value("mongodb://127.0.0.1:27017"))]
     pub db_url: String,
 
@@ -48,8 +49,8 @@ pub struct Args {
     pub db_name: String,
 
     /// Plugins directory
-    #[arg(long)]
-    pub plugin_dir: String,
+    #[arg(long, required = true)]
+    pub plugin_dir: Option<String>,
 
     /// Google Calendar path
     #[arg(long, default_value(""))]
@@ -64,14 +65,59 @@ pub struct Args {
     pub bind_addr: String,
 
     /// Port number
-    #[arg(long, visible_alias("port"))]
-    pub bind_port: u16,
+    #[arg(long, visible_alias("port"), required = true)]
+    pub bind_port: Option<u16>,
 
     /// First run
     #[arg(long, default_value_t = false)]
     pub first_run: bool,
 
+    /// How first run handles items already present in the database
+    #[arg(long, value_enum, default_value_t = MergeStrategy::Overwrite)]
+    pub merge_strategy: MergeStrategy,
+
+    /// Print the first run difference without writing anything
+    #[arg(long, default_value_t = false)]
+    pub dry_run: bool,
+
     /// Set http-secure on cookies to false
     #[arg(long, default_value_t = false)]
     pub cookie_http_insecure: bool,
+
+    /// Maintenance command to run instead of the server
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Maintenance commands
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Database operations
+    Db {
+        #[command(subcommand)]
+        action: DbCommand,
+    },
+}
+
+/// Database operations
+#[derive(Subcommand, Debug)]
+pub enum DbCommand {
+    /// Export all collections, settings and internals to archive
+    Export {
+        /// Archive path
+        path: String,
+    },
+
+    /// Import archive created by export
+    Import {
+        /// Archive path
+        path: String,
+    },
+
+    /// Copy all collections, settings and internals into an empty local
+    /// data folder, which can be then used with file:// URL
+    ToLocal {
+        /// Data folder path
+        path: String,
+    },
 }
@@ -23,7 +23,6 @@
  */
 use crate::handler::route_call::*;
 use crate::handler::web_response::conv_response;
-use crate::state::store::Store;
 use crate::State;
 use actix_identity::Identity;
 use actix_multipart::Multipart;
@@ -21,8 +21,11 @@
  * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
  * DEALINGS IN THE SOFTWARE.
  */
+use crate::state::store::StoreError;
 use actix_web::HttpResponse;
+use isabelle_dm::data_model::process_result::ProcessResult;
 use isabelle_plugin_api::api::WebResponse;
+use std::collections::HashMap;
 use std::path::Path;
 
 /// Convert internal Web response to proper HttpResponse
@@ -65,3 +68,23 @@ pub async fn conv_response(resp: WebResponse) -> HttpResponse {
         WebResponse::NotImplemented => todo!(),
     }
 }
+
+/// Convert storage error to HttpResponse carrying failed process result.
+/// Details of internal errors are only logged, not sent to the client.
+pub fn conv_store_error(err: &StoreError) -> HttpResponse {
+    let (mut resp, msg) = match err {
+        StoreError::UnknownCollection(_) => (HttpResponse::BadRequest(), err.to_string()),
+        StoreError::NotConnected => (HttpResponse::ServiceUnavailable(), err.to_string()),
+        StoreError::Conflict(_) => (HttpResponse::Conflict(), err.to_string()),
+        _ => (
+            HttpResponse::InternalServerError(),
+            "storage error".to_string(),
+        ),
+    };
+    let pr = ProcessResult {
+        succeeded: false,
+        error: msg,
+        data: HashMap::new(),
+    };
+    return resp.body(serde_json::to_string(&pr).unwrap_or("{}".to_string()));
+}
@@ -21,7 +21,7 @@
  * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
  * DEALINGS IN THE SOFTWARE.
  */
-use crate::args::Args;
+use crate::args::{Args, Command, DbCommand};
 use chrono::Timelike;
 #[macro_use]
 extern crate lazy_static;
@@ -32,9 +32,10 @@ use std::{str::FromStr, time::Duration};
 
 use crate::notif::email::send_email;
 
-#[cfg(not(feature = "full_file_database"))]
-use crate::state::merger::merge_database;
-use crate::state::store::Store;
+use crate::state::archive::{export_database, import_database};
+use crate::state::merger::{merge_database, merge_to_local, print_merge_diff};
+use crate::state::migration::run_migrations;
+use crate::state::store::{new_store, Store, StoreKind};
 
 mod args;
 mod handler;
@@ -67,7 +68,7 @@ use actix_session::SessionMiddleware;
 use actix_web::web::Data;
 use actix_web::{cookie::Key, cookie::SameSite, rt, web, App, HttpServer};
 use clap::Parser;
-use log::info;
+use log::{error, info};
 use std::ops::DerefMut;
 use std::thread;
 
@@ -117,39 +118,105 @@ async fn main() -> std::io::Result<()> {
         srv.py_path = args.py_path.to_string();
         srv.data_path = args.data_path.to_string();
         srv.public_url = args.pub_url.to_string();
-        srv.port = args.bind_port;
+        srv.port = args.bind_port.unwrap_or_default();
 
-        info!("Data storage: connecting");
-        // Put options to internal structures and connect to database
-        #[cfg(not(feature = "full_file_database"))]
-        {
-            srv.file_rw.connect(&args.data_path, "").await;
-            srv.rw.database_name = args.db_name.clone();
-            srv.rw.connect(&args.db_url, &args.data_path).await;
+        // Pick the storage backend by database URL scheme
+        let store_kind = match StoreKind::from_url(&args.db_url) {
+            Some(kind) => kind,
+            None => {
+                error!("Unsupported database URL: {}", args.db_url);
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "unsupported database URL",
+                ));
+            }
+        };
+        info!("Data storage: connecting ({:?})", store_kind);
+
+        // Put options to internal structures and connect to database.
+        // File storage works on the data folder directly, so there is no
+        // need in separate file source for it.
+        if store_kind != StoreKind::Local {
+            if let Err(e) = srv.file_rw.connect(&args.data_path, "").await {
+                error!("Failed to open data folder {}: {}", args.data_path, e);
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    e.to_string(),
+                ));
+            }
+        }
+        srv.rw = new_store(store_kind, &args.db_name);
+        if let Err(e) = srv.rw.connect(&args.db_url, &args.data_path).await {
+            error!("Failed to connect to {}: {}", args.db_url, e);
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                e.to_string(),
+            ));
         }
 
         info!("Data storage: connected");
 
-        #[cfg(feature = "full_file_database")]
-        {
-            srv.rw.connect(&args.data_path, "").await;
+        // Maintenance commands work on storage only
+        if let Some(Command::Db { action }) = &args.command {
+            let res = match action {
+                DbCommand::Export { path } => export_database(srv.rw.as_mut(), path).await,
+                DbCommand::Import { path } => import_database(srv.rw.as_mut(), path).await,
+                DbCommand::ToLocal { path } => merge_to_local(srv.rw.as_mut(), path).await,
+            };
+            if let Err(e) = res {
+                error!("Database command failed: {}", e);
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    e.to_string(),
+                ));
+            }
+            return Ok(());
         }
 
         // Load plugins
         info!("Plugins: loading");
         {
             let s = &mut srv;
-            s.plugin_pool.load_plugins(&args.plugin_dir);
+            s.plugin_pool
+                .load_plugins(&args.plugin_dir.clone().unwrap_or_default());
             info!("Plugins: ensuring operation");
             s.plugin_pool.ping_plugins();
         }
         info!("Plugins: loaded");
 
+        // Bring stored data up to date before anything reads it. First run
+        // migrates the data once it is merged: applying migrations to empty
+        // storage would mark them done for merged data.
+        if !args.first_run {
+            info!("Flow: running migrations");
+            match run_migrations(srv.rw.as_mut()).await {
+                Ok(cnt) => {
+                    info!("Flow: applied {} migrations", cnt);
+                }
+                Err(e) => {
+                    error!("Failed to run migrations: {}", e);
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        e.to_string(),
+                    ));
+                }
+            }
+        }
+
         // Perform initialization checks, etc.
         info!("Flow: performing initialization checks");
         srv.init_checks().await;
         info!("Flow: performed initialization checks");
 
+        // Build full-text search index. It goes after migrations and
+        // initialization checks, which change items in bulk; later writes
+        // reindex items one by one.
+        info!("Flow: building search index");
+        if let Err(e) = srv.search.rebuild(srv.rw.as_mut()).await {
+            error!("Failed to build search index: {}", e);
+        }
+        info!("Flow: built search index");
+
         // Initialize Google Calendar
         info!("Flow: initializing Google Calendar");
         init_google(&mut srv).await;
@@ -194,11 +261,46 @@ async fn main() -> std::io::Result<()> {
         }
 
         // If it is a first run, merge database.
-        #[cfg(not(feature = "full_file_database"))]
-        if args.first_run {
+        if args.first_run && store_kind != StoreKind::Local {
             let m = &mut srv;
             info!("Flow: first run - merge database and exit");
-            merge_database(&mut m.file_rw, &mut m.rw).await;
+            match merge_database(
+                &mut m.file_rw,
+                m.rw.as_mut(),
+                args.merge_strategy,
+                args.dry_run,
+            )
+            .await
+            {
+                Ok(diffs) => {
+                    if args.dry_run {
+                        print_merge_diff(&diffs);
+                    }
+                }
+                Err(e) => {
+                    error!("Failed to merge database: {}", e);
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        e.to_string(),
+                    ));
+                }
+            }
+
+            if !args.dry_run {
+                info!("Flow: running migrations of merged data");
+                match run_migrations(m.rw.as_mut()).await {
+                    Ok(cnt) => {
+                        info!("Flow: applied {} migrations", cnt);
+                    }
+                    Err(e) => {
+                        error!("Failed to run migrations: {}", e);
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::Other,
+                            e.to_string(),
+                        ));
+                    }
+                }
+            }
         }
     }
 
@@ -224,6 +326,7 @@ async fn main() -> std::io::Result<()> {
         let expression = "*   *   *     *       *  *  *";
         let schedule = Schedule::from_str(expression).unwrap();
         let offset = Some(FixedOffset::east_opt(0)).unwrap();
+        let job_rt = tokio::runtime::Runtime::new().unwrap();
         loop {
             let mut upcoming = schedule.upcoming(offset.unwrap()).take(1);
             thread::sleep(Duration::from_millis(500));
@@ -236,6 +339,9 @@ async fn main() -> std::io::Result<()> {
                     let mut srv = srv_lock.borrow_mut();
                     if local.time().second() == 0 {
                         call_periodic_job_hook(&mut srv, "min");
+                        if local.time().minute() == 0 {
+                            job_rt.block_on(srv.purge_trash());
+                        }
                     }
                     call_periodic_job_hook(&mut srv, "sec");
                 }
@@ -258,6 +364,12 @@ async fn main() -> std::io::Result<()> {
             .route("/itm/edit", web::post().to(itm_edit))
             .route("/itm/del", web::post().to(itm_del))
             .route("/itm/list", web::get().to(itm_list))
+            .route("/itm/aggregate", web::get().to(itm_aggregate))
+            .route("/itm/search", web::get().to(itm_search))
+            .route("/itm/history", web::get().to(itm_history))
+            .route("/itm/revert", web::post().to(itm_revert))
+            .route("/itm/trash/list", web::get().to(itm_trash_list))
+            .route("/itm/trash/restore", web::post().to(itm_trash_restore))
             .route("/login", web::post().to(login))
             .route("/register", web::post().to(register))
             .route("/gen_otp", web::post().to(gen_otp))
@@ -296,7 +408,7 @@ async fn main() -> std::io::Result<()> {
         }
         app
     })
-    .bind((args.bind_addr, args.bind_port))?
+    .bind((args.bind_addr, args.bind_port.unwrap_or_default()))?
     .run();
     let th = rt::spawn(srv);
     let _ = th.await;
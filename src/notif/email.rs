@@ -22,7 +22,6 @@
  * DEALINGS IN THE SOFTWARE.
  */
 
-use crate::state::store::Store;
 use lettre::message::header::ContentType;
 use lettre::transport::smtp::authentication::Credentials;
 use lettre::{Message, SmtpTransport, Transport};
@@ -22,24 +22,322 @@
  * DEALINGS IN THE SOFTWARE.
  */
 use crate::handler::route_call::*;
+use crate::handler::web_response::conv_store_error;
 use crate::server::user_control::*;
+use crate::state::filter::{Filter, FilterPolicy};
+use crate::state::schema::{collection_schema, validate_item};
+use crate::state::search::{SearchHit, SEARCH_MAX_LIMIT, SEARCH_MAX_SKIP};
 use crate::state::state::*;
-use crate::state::store::Store;
+use crate::state::store::{
+    is_plain_field, item_revision, parse_aggregates, parse_fields, parse_sort, project_item,
+    HistoryEntry, ListCursor, OrderedList, StoreError, StoreResult, TrashEntry,
+};
 use actix_identity::Identity;
 use actix_multipart::Multipart;
+use actix_web::http::header::IF_MATCH;
 use actix_web::{web, HttpRequest, HttpResponse};
 use futures_util::TryStreamExt;
 use isabelle_dm::data_model::data_object_action::DataObjectAction;
 use isabelle_dm::data_model::item::Item;
 use isabelle_dm::data_model::list_query::ListQuery;
-use isabelle_dm::data_model::list_result::ListResult;
 use isabelle_dm::data_model::merge_coll::MergeColl;
 use isabelle_dm::data_model::process_result::ProcessResult;
-use log::{error, info};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
 use serde_qs;
 use std::collections::HashMap;
 use std::ops::DerefMut;
 
+/// Start transaction covering the write and the post edit hooks, so that
+/// plugins updating other items do it all or nothing. Stores that can't do
+/// transactions (like standalone Mongo) work without it.
+async fn begin_edit(srv: &mut crate::state::data::Data) -> bool {
+    match srv.rw.begin_transaction().await {
+        Ok(()) => {
+            return true;
+        }
+        Err(e) => {
+            debug!("Editing without transaction: {}", e);
+            return false;
+        }
+    }
+}
+
+/// Abort transaction started by begin_edit()
+async fn abort_edit(srv: &mut crate::state::data::Data, in_tx: bool) {
+    if in_tx {
+        if let Err(e) = srv.rw.abort_transaction().await {
+            error!("Failed to abort transaction: {}", e);
+        }
+    }
+}
+
+/// Get revision the client expects the item to have, from either `rev`
+/// query parameter or `If-Match` header. Returns Err on malformed value.
+fn edit_revision(req: &HttpRequest) -> Result<Option<u64>, ()> {
+    #[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+    pub struct EditRevision {
+        pub rev: Option<u64>,
+    }
+
+    let er = serde_qs::from_str::<EditRevision>(&req.query_string()).map_err(|_| ())?;
+    if er.rev.is_some() {
+        return Ok(er.rev);
+    }
+
+    if let Some(hdr) = req.headers().get(IF_MATCH) {
+        let val = hdr.to_str().map_err(|_| ())?.trim();
+        if val == "*" {
+            return Ok(None);
+        }
+        let val = val.strip_prefix("W/").unwrap_or(val).trim_matches('"');
+        return val.parse::<u64>().map(Some).map_err(|_| ());
+    }
+
+    return Ok(None);
+}
+
+/// Record previous version of the changed item within the transaction of
+/// the change, so that the change isn't committed without its history.
+/// On failure, the transaction is aborted.
+async fn record_history(
+    srv: &mut crate::state::data::Data,
+    login: &str,
+    collection: &str,
+    id: u64,
+    action: &str,
+    old_itm: Option<Item>,
+    in_tx: bool,
+) -> StoreResult<()> {
+    let entry = HistoryEntry {
+        collection: collection.to_string(),
+        id,
+        rev: item_revision(old_itm.as_ref()),
+        action: action.to_string(),
+        user: login.to_string(),
+        time: chrono::Utc::now().timestamp() as u64,
+        item: old_itm,
+    };
+    if let Err(e) = srv.rw.add_history(&entry).await {
+        error!(
+            "Failed to record history of {} element {}: {}",
+            collection, id, e
+        );
+        abort_edit(srv, in_tx).await;
+        return Err(e);
+    }
+    return Ok(());
+}
+
+/// Check if items removed from the collection are put to trash
+async fn soft_delete(srv: &mut crate::state::data::Data, collection: &str) -> bool {
+    let collections = srv
+        .rw
+        .get_internals()
+        .await
+        .safe_strstr("soft_delete", &HashMap::new());
+    return collections.values().any(|c| c == collection || c == "*");
+}
+
+/// Write the item through pre edit hooks, schema check and post edit
+/// hooks, record history and answer with the new ID and revision. This is
+/// the path of every change made on behalf of the user, so that none of
+/// the checks can be bypassed. `action` overrides the history action,
+/// which is `create` or `modify` otherwise. Restored items are removed
+/// from trash along with the change.
+async fn write_item(
+    srv_mut: &mut crate::state::data::Data,
+    usr: &Option<Item>,
+    login: &str,
+    collection: &str,
+    itm: Item,
+    merge: bool,
+    rev: Option<u64>,
+    action: Option<&str>,
+) -> HttpResponse {
+    let mut itm_clone = itm.clone();
+
+    let old_itm = match srv_mut.rw.get_item(collection, itm.id).await {
+        Ok(o) => o,
+        Err(e) => {
+            error!("Failed to read {} element {}: {}", collection, itm.id, e);
+            return conv_store_error(&e);
+        }
+    };
+    let obj_action = if old_itm.is_some() {
+        DataObjectAction::Modify
+    } else {
+        DataObjectAction::Create
+    };
+
+    /* call pre edit hooks */
+    {
+        let routes = srv_mut
+            .rw
+            .get_internals()
+            .await
+            .safe_strstr("item_pre_edit_hook", &HashMap::new());
+        for route in &routes {
+            let parts: Vec<&str> = route.1.split(":").collect();
+            if parts[0] == collection || parts[0] == "*" {
+                let res = call_item_pre_edit_hook(
+                    srv_mut,
+                    parts[1],
+                    usr,
+                    collection,
+                    old_itm.clone(),
+                    &mut itm_clone,
+                    obj_action.clone(),
+                    merge,
+                )
+                .await;
+                if !res.succeeded {
+                    info!("Item pre edit hook failed: {} - {}", parts[1], res.error);
+                    let s = serde_json::to_string(&res);
+                    return HttpResponse::Ok().body(s.unwrap_or("{}".to_string()));
+                }
+            }
+        }
+    }
+
+    /* check schema of the resulting item */
+    {
+        let internals = srv_mut.rw.get_internals().await;
+        let rules = collection_schema(&internals, collection);
+        if !rules.is_empty() {
+            let mut new_itm = itm_clone.clone();
+            if merge {
+                if let Some(o) = &old_itm {
+                    new_itm = o.clone();
+                    new_itm.merge(&itm_clone);
+                }
+            }
+            let violations = validate_item(&rules, &new_itm);
+            if !violations.is_empty() {
+                info!(
+                    "Schema violation in {} element {}: {:?}",
+                    collection, itm.id, violations
+                );
+                return HttpResponse::Ok().body(
+                    serde_json::to_string(&ProcessResult {
+                        succeeded: false,
+                        error: format!("{} schema violations", violations.len()),
+                        data: violations,
+                    })
+                    .unwrap(),
+                );
+            }
+        }
+    }
+
+    let in_tx = begin_edit(srv_mut).await;
+    let res = match rev {
+        Some(expected) => {
+            srv_mut
+                .rw
+                .set_item_rev(collection, &itm_clone, merge, expected)
+                .await
+        }
+        None => srv_mut.rw.set_item(collection, &itm_clone, merge).await,
+    };
+    let r = match res {
+        Ok(id) => id,
+        Err(StoreError::Conflict(e)) => {
+            info!("Conflict on {} element {}: {}", collection, itm.id, e);
+            abort_edit(srv_mut, in_tx).await;
+            return conv_store_error(&StoreError::Conflict(e));
+        }
+        Err(e) => {
+            error!("Failed to set {} element {}: {}", collection, itm.id, e);
+            abort_edit(srv_mut, in_tx).await;
+            return conv_store_error(&e);
+        }
+    };
+    info!("Collection {} element {} set", collection, r);
+
+    /* call hooks */
+    {
+        let routes = srv_mut
+            .rw
+            .get_internals()
+            .await
+            .safe_strstr("item_post_edit_hook", &HashMap::new());
+        for route in routes {
+            let parts: Vec<&str> = route.1.split(":").collect();
+            if parts[0] == collection || parts[0] == "*" {
+                call_item_post_edit_hook(
+                    srv_mut,
+                    &parts[1],
+                    collection,
+                    old_itm.clone(),
+                    itm.id,
+                    obj_action.clone(),
+                )
+                .await;
+            }
+        }
+    }
+
+    let history_action = match action {
+        Some(a) => a,
+        None if old_itm.is_some() => "modify",
+        None => "create",
+    };
+    if let Err(e) = record_history(
+        srv_mut,
+        login,
+        collection,
+        r,
+        history_action,
+        old_itm,
+        in_tx,
+    )
+    .await
+    {
+        return conv_store_error(&e);
+    }
+
+    if action == Some("restore") {
+        if let Err(e) = srv_mut.rw.del_trash(collection, r).await {
+            error!(
+                "Failed to remove {} element {} from trash: {}",
+                collection, r, e
+            );
+            abort_edit(srv_mut, in_tx).await;
+            return conv_store_error(&e);
+        }
+    }
+
+    if in_tx {
+        if let Err(e) = srv_mut.rw.commit_transaction().await {
+            error!("Failed to commit {} element {}: {}", collection, r, e);
+            return conv_store_error(&e);
+        }
+    }
+
+    srv_mut.reindex_item(collection, r).await;
+
+    let mut map = HashMap::new();
+    map.insert("id".to_string(), r.to_string());
+    match srv_mut.rw.get_item(collection, r).await {
+        Ok(Some(new_itm)) => {
+            map.insert("rev".to_string(), item_revision(Some(&new_itm)).to_string());
+        }
+        Ok(None) => {}
+        Err(e) => {
+            error!("Failed to read {} element {}: {}", collection, r, e);
+        }
+    }
+    return HttpResponse::Ok().body(
+        serde_json::to_string(&ProcessResult {
+            succeeded: true,
+            error: "".to_string(),
+            data: map,
+        })
+        .unwrap(),
+    );
+}
+
 /// Action that is called on editing items. This function unrolls the
 /// multipart data, all needed hooks, and eventually prepare response.
 pub async fn itm_edit(
@@ -54,6 +352,16 @@ pub async fn itm_edit(
 
     let mc = serde_qs::from_str::<MergeColl>(&req.query_string()).unwrap();
     let mut itm = serde_qs::from_str::<Item>(&req.query_string()).unwrap();
+    let rev = match edit_revision(&req) {
+        Ok(r) => r,
+        Err(()) => {
+            info!(
+                "Malformed revision for {} element {}",
+                mc.collection, itm.id
+            );
+            return HttpResponse::BadRequest().into();
+        }
+    };
 
     while let Ok(Some(mut field)) = payload.try_next().await {
         while let Ok(Some(chunk)) = field.try_next().await {
@@ -94,93 +402,23 @@ pub async fn itm_edit(
 
     itm.normalize_negated();
 
-    if srv.has_collection(&mc.collection) {
-        let srv_mut = unsafe { &mut (*srv_lock.as_ptr()) };
-        let mut itm_clone = itm.clone();
-
-        let old_itm = srv_mut.rw.get_item(&mc.collection, itm.id).await;
-        /* call pre edit hooks */
-        {
-            let routes = (*srv_mut)
-                .rw
-                .get_internals()
-                .await
-                .safe_strstr("item_pre_edit_hook", &HashMap::new());
-            for route in &routes {
-                let parts: Vec<&str> = route.1.split(":").collect();
-                if parts[0] == mc.collection || parts[0] == "*" {
-                    let res = call_item_pre_edit_hook(
-                        &mut (*srv_mut),
-                        parts[1],
-                        &usr,
-                        &mc.collection,
-                        old_itm.clone(),
-                        &mut itm_clone,
-                        if old_itm.is_some() {
-                            DataObjectAction::Modify
-                        } else {
-                            DataObjectAction::Create
-                        },
-                        mc.merge,
-                    )
-                    .await;
-                    if !res.succeeded {
-                        info!("Item pre edit hook failed: {} - {}", parts[1], res.error);
-                        let s = serde_json::to_string(&res);
-                        return HttpResponse::Ok().body(s.unwrap_or("{}".to_string()));
-                    }
-                }
-            }
-        }
-
-        let r = (*srv_mut)
-            .rw
-            .set_item(&mc.collection, &itm_clone, mc.merge)
-            .await;
-        info!("Collection {} element {} set", mc.collection, itm.id);
-
-        /* call hooks */
-        {
-            let routes = (*srv_mut)
-                .rw
-                .get_internals()
-                .await
-                .safe_strstr("item_post_edit_hook", &HashMap::new());
-            for route in routes {
-                let parts: Vec<&str> = route.1.split(":").collect();
-                if parts[0] == mc.collection || parts[0] == "*" {
-                    call_item_post_edit_hook(
-                        &mut (*srv_mut),
-                        &parts[1],
-                        &mc.collection,
-                        old_itm.clone(),
-                        itm.id,
-                        if old_itm.is_some() {
-                            DataObjectAction::Modify
-                        } else {
-                            DataObjectAction::Create
-                        },
-                    )
-                    .await;
-                }
-            }
-        }
-
-        let mut map = HashMap::new();
-        map.insert("id".to_string(), r.to_string());
-        return HttpResponse::Ok().body(
-            serde_json::to_string(&ProcessResult {
-                succeeded: true,
-                error: "".to_string(),
-                data: map,
-            })
-            .unwrap(),
-        );
-    } else {
+    if !srv.has_collection(&mc.collection) {
         error!("Collection {} doesn't exist", mc.collection);
+        return HttpResponse::BadRequest().into();
     }
 
-    return HttpResponse::BadRequest().into();
+    let srv_mut = unsafe { &mut (*srv_lock.as_ptr()) };
+    return write_item(
+        srv_mut,
+        &usr,
+        &user.id().unwrap_or_default(),
+        &mc.collection,
+        itm,
+        mc.merge,
+        rev,
+        None,
+    )
+    .await;
 }
 
 /// Action that is called on removing the item. This function calls
@@ -211,7 +449,13 @@ pub async fn itm_del(user: Identity, data: web::Data<State>, req: HttpRequest) -
 
     let srv_mut = srv.deref_mut();
     if srv_mut.has_collection(&mc.collection) {
-        let old_itm = srv_mut.rw.get_item(&mc.collection, itm.id).await;
+        let old_itm = match srv_mut.rw.get_item(&mc.collection, itm.id).await {
+            Ok(o) => o,
+            Err(e) => {
+                error!("Failed to read {} element {}: {}", mc.collection, itm.id, e);
+                return conv_store_error(&e);
+            }
+        };
         let mut new_itm = Item::new();
 
         /* call pre edit hooks before removal */
@@ -244,8 +488,41 @@ pub async fn itm_del(user: Identity, data: web::Data<State>, req: HttpRequest) -
             }
         }
 
-        if srv_mut.rw.del_item(&mc.collection, itm.id).await {
-            info!("Collection {} element {} removed", mc.collection, itm.id);
+        let in_tx = begin_edit(srv_mut).await;
+        if old_itm.is_some() && soft_delete(srv_mut, &mc.collection).await {
+            let entry = TrashEntry {
+                collection: mc.collection.clone(),
+                id: itm.id,
+                user: user.id().unwrap_or_default(),
+                time: chrono::Utc::now().timestamp() as u64,
+                item: old_itm.clone().unwrap(),
+            };
+            if let Err(e) = srv_mut.rw.add_trash(&entry).await {
+                error!(
+                    "Failed to put {} element {} to trash: {}",
+                    mc.collection, itm.id, e
+                );
+                abort_edit(srv_mut, in_tx).await;
+                return conv_store_error(&e);
+            }
+        }
+        match srv_mut.rw.del_item(&mc.collection, itm.id).await {
+            Ok(true) => {
+                info!("Collection {} element {} removed", mc.collection, itm.id);
+            }
+            Ok(false) => {
+                info!("Collection {} element {} not found", mc.collection, itm.id);
+                abort_edit(srv_mut, in_tx).await;
+                return HttpResponse::NotFound().into();
+            }
+            Err(e) => {
+                error!(
+                    "Failed to remove {} element {}: {}",
+                    mc.collection, itm.id, e
+                );
+                abort_edit(srv_mut, in_tx).await;
+                return conv_store_error(&e);
+            }
         }
 
         /* call hooks */
@@ -271,6 +548,32 @@ pub async fn itm_del(user: Identity, data: web::Data<State>, req: HttpRequest) -
             }
         }
 
+        if let Err(e) = record_history(
+            srv_mut,
+            &user.id().unwrap_or_default(),
+            &mc.collection,
+            itm.id,
+            "delete",
+            old_itm,
+            in_tx,
+        )
+        .await
+        {
+            return conv_store_error(&e);
+        }
+
+        if in_tx {
+            if let Err(e) = srv_mut.rw.commit_transaction().await {
+                error!(
+                    "Failed to commit removal of {} element {}: {}",
+                    mc.collection, itm.id, e
+                );
+                return conv_store_error(&e);
+            }
+        }
+
+        srv_mut.reindex_item(&mc.collection, itm.id).await;
+
         return HttpResponse::Ok().into();
     } else {
         error!("Collection {} doesn't exist", mc.collection);
@@ -279,28 +582,174 @@ pub async fn itm_del(user: Identity, data: web::Data<State>, req: HttpRequest) -
     return HttpResponse::BadRequest().into();
 }
 
+/// Listing parameters in addition to ListQuery
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug, Default)]
+struct ListExtraQuery {
+    /// Sort keys like `-u64s.date,strs.name`, replaces `sort_key`
+    #[serde(default)]
+    pub sort: String,
+
+    /// Opaque position from `next_cursor` of the previous page
+    #[serde(default)]
+    pub cursor: String,
+
+    /// Fields to return like `strs.name,u64s.date`, all if empty
+    #[serde(default)]
+    pub fields: String,
+}
+
+/// Fields to read from the storage for listing. List filter hooks see
+/// items before projection, so they get fields declared for them in
+/// `itm_list_hook_fields` of internals (`<collection>:<field>,...`), or
+/// all fields if the collection declares none.
+fn list_store_fields(internals: &Item, collection: &str, fields: &[String]) -> Vec<String> {
+    let hooks = internals.safe_strstr("itm_list_filter_hook", &HashMap::new());
+    if fields.is_empty() || hooks.is_empty() {
+        return fields.to_vec();
+    }
+
+    let mut res = fields.to_vec();
+    let mut declared = false;
+    let hook_fields = internals.safe_strstr("itm_list_hook_fields", &HashMap::new());
+    for decl in hook_fields.values() {
+        let (coll, list) = decl.split_once(':').unwrap_or(("", ""));
+        if coll != collection {
+            continue;
+        }
+        match parse_fields(list) {
+            Some(f) => {
+                declared = true;
+                for field in f {
+                    if !res.contains(&field) {
+                        res.push(field);
+                    }
+                }
+            }
+            None => {
+                error!("Bad list hook fields {}", decl);
+            }
+        }
+    }
+    if !declared {
+        return Vec::new();
+    }
+    return res;
+}
+
+/// Build the database filter of listing from the client filter and filters
+/// given by `itm_list_db_filter_hook` routes. Client filter and the other
+/// fields it uses must be allowed by the filter policy of the collection,
+/// rejections are logged as security events.
+async fn list_db_filter(
+    srv: &mut crate::state::data::Data,
+    login: &str,
+    usr: &Option<Item>,
+    collection: &str,
+    context: &str,
+    filter: &str,
+    fields: &[String],
+) -> Result<Filter, HttpResponse> {
+    let mut final_filter = match Filter::parse(filter) {
+        Ok(f) => f,
+        Err(e) => {
+            warn!(
+                target: "security",
+                "Rejected filter of user {} for {}: {}",
+                login,
+                collection,
+                e
+            );
+            return Err(HttpResponse::BadRequest().into());
+        }
+    };
+    let internals = srv.rw.get_internals().await;
+    if let Some(policy) = FilterPolicy::for_collection(&internals, collection) {
+        if let Err(e) = policy
+            .check(&final_filter)
+            .and_then(|_| policy.check_fields(fields))
+        {
+            warn!(
+                target: "security",
+                "Rejected filter of user {} for {}: {}",
+                login,
+                collection,
+                e
+            );
+            return Err(HttpResponse::Forbidden().into());
+        }
+    }
+
+    let routes = internals.safe_strstr("itm_list_db_filter_hook", &HashMap::new());
+    for route in routes {
+        let new_filters =
+            call_item_list_db_filter_hook(srv, &route.1, usr, collection, context, "mongo").await;
+        for filt in new_filters {
+            // Plugin filters restrict access, so they can't be dropped
+            match Filter::parse(&filt) {
+                Ok(f) => final_filter = final_filter.and(f),
+                Err(e) => {
+                    error!("Plugin gave bad filter for {}: {}", collection, e);
+                    return Err(HttpResponse::InternalServerError().into());
+                }
+            }
+        }
+    }
+    return Ok(final_filter);
+}
+
 /// Action that is called on any attempt to list database items.
 /// This function invokes all necessary hooks before giving away the list
-/// in form of json array.
+/// in form of json array. IDs in `order` follow the requested sorting,
+/// `next_cursor` continues the listing after the last item of the page.
 pub async fn itm_list(user: Identity, data: web::Data<State>, req: HttpRequest) -> HttpResponse {
     let srv_lock = data.server.lock();
     let mut srv = unsafe { &mut (*srv_lock.as_ptr()) };
     let usr = get_user(&mut srv, user.id().unwrap()).await;
 
-    let lq = serde_qs::from_str::<ListQuery>(&req.query_string()).unwrap();
+    let mut lq = serde_qs::from_str::<ListQuery>(&req.query_string()).unwrap();
+    let extra = serde_qs::from_str::<ListExtraQuery>(&req.query_string()).unwrap_or_default();
+    if extra.sort != "" {
+        lq.sort_key = extra.sort.clone();
+    }
 
     if !srv.has_collection(&lq.collection) {
         error!("Collection {} doesn't exist", lq.collection);
         return HttpResponse::BadRequest().into();
     }
 
-    let mut lr = ListResult {
-        map: HashMap::new(),
-        total_count: 0,
+    let cursor = if extra.cursor != "" {
+        match ListCursor::decode(&extra.cursor, &lq.sort_key) {
+            Some(c) => Some(c),
+            None => {
+                error!("Invalid cursor for collection {}", lq.collection);
+                return HttpResponse::BadRequest().into();
+            }
+        }
+    } else {
+        None
     };
 
+    let fields = match parse_fields(&extra.fields) {
+        Some(f) => f,
+        None => {
+            error!(
+                "Invalid fields {} for collection {}",
+                extra.fields, lq.collection
+            );
+            return HttpResponse::BadRequest().into();
+        }
+    };
+
+    let mut ol = OrderedList::new();
+
     if lq.id != u64::MAX {
-        let res = srv.rw.get_item(&lq.collection, lq.id).await;
+        let res = match srv.rw.get_item(&lq.collection, lq.id).await {
+            Ok(r) => r,
+            Err(e) => {
+                error!("Failed to read {} element {}: {}", lq.collection, lq.id, e);
+                return conv_store_error(&e);
+            }
+        };
         if res == None {
             error!(
                 "Collection {} requested element {} doesn't exist",
@@ -310,79 +759,360 @@ pub async fn itm_list(user: Identity, data: web::Data<State>, req: HttpRequest)
         }
 
         if lq.limit == u64::MAX || lq.limit >= 1 {
-            lr.map.insert(lq.id, res.unwrap());
-            lr.total_count = 1;
+            ol.push(res.unwrap());
+            ol.list.total_count = 1;
             info!(
                 "Collection {} requested element {} limit {}",
                 lq.collection, lq.id, lq.limit
             );
         }
-    } else if lq.id_min != u64::MAX || lq.id_max != u64::MAX || lq.sort_key != "" || lq.filter != ""
+    } else if lq.id_min != u64::MAX
+        || lq.id_max != u64::MAX
+        || lq.sort_key != ""
+        || lq.filter != ""
+        || cursor.is_some()
     {
         info!(
             "Collection {} requested range {} - {} sort {} skip {} limit {} filter {}",
             lq.collection, lq.id_min, lq.id_max, lq.sort_key, lq.skip, lq.limit, lq.filter
         );
 
-        let mut filters: Vec<String> = Vec::new();
-        let mut final_filter: String = "".to_string();
+        // Sorting reveals the order of values, so sort fields are checked
+        // like filter fields. ID is always known.
+        let used_fields: Vec<String> = parse_sort(&lq.sort_key)
+            .into_iter()
+            .map(|spec| spec.field)
+            .filter(|f| f != "id")
+            .collect();
 
-        if lq.filter != "" {
-            filters.push(lq.filter.to_string());
+        let internals = srv.rw.get_internals().await;
+        let final_filter = match list_db_filter(
+            &mut srv,
+            &user.id().unwrap_or_default(),
+            &usr,
+            &lq.collection,
+            &lq.context,
+            &lq.filter,
+            &used_fields,
+        )
+        .await
+        {
+            Ok(f) => f,
+            Err(resp) => {
+                return resp;
+            }
+        };
+
+        ol = match srv
+            .rw
+            .get_items_ordered(
+                &lq.collection,
+                lq.id_min,
+                lq.id_max,
+                &lq.sort_key,
+                &final_filter,
+                cursor.as_ref(),
+                &list_store_fields(&internals, &lq.collection, &fields),
+                lq.skip,
+                lq.limit,
+            )
+            .await
+        {
+            Ok(l) => l,
+            Err(e) => {
+                error!("Failed to list {}: {}", lq.collection, e);
+                return conv_store_error(&e);
+            }
+        };
+    } else if lq.id_list.len() > 0 {
+        for id in lq.id_list {
+            match srv.rw.get_item(&lq.collection, id).await {
+                Ok(Some(itm)) => {
+                    ol.push(itm);
+                    ol.list.total_count += 1;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    error!("Failed to read {} element {}: {}", lq.collection, id, e);
+                    return conv_store_error(&e);
+                }
+            }
         }
+        info!("Collection {} requested list of IDs", lq.collection);
+    } else {
+        info!("Collection {} unknown filter", lq.collection);
+    }
 
+    /* itm filter hooks */
+    {
         let routes = srv
             .rw
             .get_internals()
             .await
-            .safe_strstr("itm_list_db_filter_hook", &HashMap::new());
-        for route in routes {
-            let new_filters = call_item_list_db_filter_hook(
+            .safe_strstr("itm_list_filter_hook", &HashMap::new());
+        let mut sorted_routes: Vec<_> = routes.iter().collect();
+        sorted_routes.sort_by(|a, b| a.0.cmp(b.0));
+        for route in sorted_routes {
+            call_item_list_filter_hook(
                 &mut srv,
                 &route.1,
                 &usr,
                 &lq.collection,
                 &lq.context,
-                "mongo",
+                &mut ol.list.map,
             )
             .await;
-            filters.extend(new_filters);
         }
+        ol.sync_order();
+    }
+
+    for itm in ol.list.map.values_mut() {
+        project_item(itm, &fields);
+    }
+
+    HttpResponse::Ok().body(serde_json::to_string(&ol).unwrap())
+}
+
+/// Query of item history and revert actions
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+struct HistoryQuery {
+    /// Collection of the item
+    pub collection: String,
 
-        for filt in filters {
-            if final_filter == "" {
-                final_filter = filt;
-            } else {
-                final_filter = "{ \"$and\": [".to_owned() + &final_filter + ", " + &filt + "]}";
+    /// Item ID
+    pub id: u64,
+
+    /// Revision to revert to
+    pub rev: Option<u64>,
+}
+
+/// Action that is called to get recorded versions of the item along with
+/// who changed it and when. Access is checked the same way as for editing.
+pub async fn itm_history(user: Identity, data: web::Data<State>, req: HttpRequest) -> HttpResponse {
+    let srv_lock = data.server.lock();
+    let mut srv = unsafe { &mut (*srv_lock.as_ptr()) };
+    let usr = get_user(&mut srv, user.id().unwrap()).await;
+
+    let hq = match serde_qs::from_str::<HistoryQuery>(&req.query_string()) {
+        Ok(q) => q,
+        Err(e) => {
+            info!("Bad history query: {}", e);
+            return HttpResponse::BadRequest().into();
+        }
+    };
+
+    if !srv.has_collection(&hq.collection) {
+        error!("Collection {} doesn't exist", hq.collection);
+        return HttpResponse::BadRequest().into();
+    }
+
+    let entries = match srv.rw.get_history(&hq.collection, hq.id).await {
+        Ok(e) => e,
+        Err(e) => {
+            error!(
+                "Failed to read history of {} element {}: {}",
+                hq.collection, hq.id, e
+            );
+            return conv_store_error(&e);
+        }
+    };
+
+    // Auth hooks get the current item, or the last known one if removed
+    let cur_itm = match srv.rw.get_item(&hq.collection, hq.id).await {
+        Ok(Some(i)) => Some(i),
+        Ok(None) => entries.iter().rev().find_map(|e| e.item.clone()),
+        Err(e) => {
+            error!("Failed to read {} element {}: {}", hq.collection, hq.id, e);
+            return conv_store_error(&e);
+        }
+    };
+    if cur_itm.is_none() {
+        return HttpResponse::NotFound().into();
+    }
+
+    /* call auth hooks */
+    {
+        let routes = srv
+            .rw
+            .get_internals()
+            .await
+            .safe_strstr("itm_auth_hook", &HashMap::new());
+        for route in routes {
+            if !call_item_auth_hook(
+                &mut srv,
+                &route.1,
+                &usr,
+                &hq.collection,
+                hq.id,
+                cur_itm.clone(),
+                false,
+            )
+            .await
+            {
+                return HttpResponse::Forbidden().into();
             }
         }
+    }
+
+    info!(
+        "Collection {} element {} history: {} entries",
+        hq.collection,
+        hq.id,
+        entries.len()
+    );
+    return HttpResponse::Ok().body(serde_json::to_string(&entries).unwrap_or("[]".to_string()));
+}
+
+/// Action that is called to bring the item back to the recorded revision.
+/// The item is written as a whole through the same hooks and schema check
+/// as editing, since they may have changed since the version was written.
+pub async fn itm_revert(user: Identity, data: web::Data<State>, req: HttpRequest) -> HttpResponse {
+    let srv_lock = data.server.lock();
+    let mut srv = unsafe { &mut (*srv_lock.as_ptr()) };
+    let usr = get_user(&mut srv, user.id().unwrap()).await;
+
+    let hq = match serde_qs::from_str::<HistoryQuery>(&req.query_string()) {
+        Ok(q) => q,
+        Err(e) => {
+            info!("Bad revert query: {}", e);
+            return HttpResponse::BadRequest().into();
+        }
+    };
+    let rev = match hq.rev {
+        Some(r) => r,
+        None => {
+            info!(
+                "Revert of {} element {} without revision",
+                hq.collection, hq.id
+            );
+            return HttpResponse::BadRequest().into();
+        }
+    };
+
+    if !srv.has_collection(&hq.collection) {
+        error!("Collection {} doesn't exist", hq.collection);
+        return HttpResponse::BadRequest().into();
+    }
+
+    let entries = match srv.rw.get_history(&hq.collection, hq.id).await {
+        Ok(e) => e,
+        Err(e) => {
+            error!(
+                "Failed to read history of {} element {}: {}",
+                hq.collection, hq.id, e
+            );
+            return conv_store_error(&e);
+        }
+    };
+    let mut target = match entries
+        .iter()
+        .rev()
+        .find(|e| e.rev == rev && e.item.is_some())
+    {
+        Some(e) => e.item.clone().unwrap(),
+        None => {
+            info!(
+                "Collection {} element {} has no revision {}",
+                hq.collection, hq.id, rev
+            );
+            return HttpResponse::NotFound().into();
+        }
+    };
+    target.id = hq.id;
 
-        lr = srv
+    /* call auth hooks */
+    {
+        let routes = srv
             .rw
-            .get_items(
-                &lq.collection,
-                lq.id_min,
-                lq.id_max,
-                &lq.sort_key,
-                &final_filter,
-                lq.skip,
-                lq.limit,
+            .get_internals()
+            .await
+            .safe_strstr("itm_auth_hook", &HashMap::new());
+        for route in routes {
+            if !call_item_auth_hook(
+                &mut srv,
+                &route.1,
+                &usr,
+                &hq.collection,
+                hq.id,
+                Some(target.clone()),
+                false,
             )
-            .await;
-    } else if lq.id_list.len() > 0 {
-        for id in lq.id_list {
-            let res = srv.rw.get_item(&lq.collection, id).await;
-            if res != None {
-                lr.map.insert(id, res.unwrap());
-                lr.total_count += 1;
+            .await
+            {
+                return HttpResponse::Forbidden().into();
             }
         }
-        info!("Collection {} requested list of IDs", lq.collection);
-    } else {
-        info!("Collection {} unknown filter", lq.collection);
     }
 
+    info!(
+        "Collection {} element {} reverting to revision {}",
+        hq.collection, hq.id, rev
+    );
+    let srv_mut = srv.deref_mut();
+    return write_item(
+        srv_mut,
+        &usr,
+        &user.id().unwrap_or_default(),
+        &hq.collection,
+        target,
+        false,
+        None,
+        Some("revert"),
+    )
+    .await;
+}
+
+/// Query of trash actions
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+struct TrashQuery {
+    /// Collection of items
+    pub collection: String,
+
+    /// Item ID to restore
+    pub id: Option<u64>,
+
+    /// Context passed to list filter hooks
+    #[serde(default)]
+    pub context: String,
+}
+
+/// Action that is called to list removed items of the collection. Items
+/// go through the same list filter hooks as regular listing.
+pub async fn itm_trash_list(
+    user: Identity,
+    data: web::Data<State>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let srv_lock = data.server.lock();
+    let mut srv = unsafe { &mut (*srv_lock.as_ptr()) };
+    let usr = get_user(&mut srv, user.id().unwrap()).await;
+
+    let tq = match serde_qs::from_str::<TrashQuery>(&req.query_string()) {
+        Ok(q) => q,
+        Err(e) => {
+            info!("Bad trash query: {}", e);
+            return HttpResponse::BadRequest().into();
+        }
+    };
+
+    if !srv.has_collection(&tq.collection) {
+        error!("Collection {} doesn't exist", tq.collection);
+        return HttpResponse::BadRequest().into();
+    }
+
+    let entries = match srv.rw.get_trash(&tq.collection).await {
+        Ok(e) => e,
+        Err(e) => {
+            error!("Failed to read trash of {}: {}", tq.collection, e);
+            return conv_store_error(&e);
+        }
+    };
+
     /* itm filter hooks */
+    let mut map: HashMap<u64, Item> = HashMap::new();
+    for entry in &entries {
+        map.insert(entry.id, entry.item.clone());
+    }
     {
         let routes = srv
             .rw
@@ -396,13 +1126,391 @@ pub async fn itm_list(user: Identity, data: web::Data<State>, req: HttpRequest)
                 &mut srv,
                 &route.1,
                 &usr,
-                &lq.collection,
-                &lq.context,
-                &mut lr.map,
+                &tq.collection,
+                &tq.context,
+                &mut map,
             )
             .await;
         }
     }
 
-    HttpResponse::Ok().body(serde_json::to_string(&lr).unwrap())
+    let visible: Vec<TrashEntry> = entries
+        .into_iter()
+        .filter(|e| map.contains_key(&e.id))
+        .collect();
+    info!(
+        "Collection {} trash: {} items",
+        tq.collection,
+        visible.len()
+    );
+    return HttpResponse::Ok().body(serde_json::to_string(&visible).unwrap_or("[]".to_string()));
+}
+
+/// Action that is called to bring the removed item back from trash.
+/// Fails with conflict if the item with the same ID exists again.
+pub async fn itm_trash_restore(
+    user: Identity,
+    data: web::Data<State>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let srv_lock = data.server.lock();
+    let mut srv = unsafe { &mut (*srv_lock.as_ptr()) };
+    let usr = get_user(&mut srv, user.id().unwrap()).await;
+
+    let tq = match serde_qs::from_str::<TrashQuery>(&req.query_string()) {
+        Ok(q) => q,
+        Err(e) => {
+            info!("Bad trash query: {}", e);
+            return HttpResponse::BadRequest().into();
+        }
+    };
+    let id = match tq.id {
+        Some(i) => i,
+        None => {
+            info!("Restore from {} trash without ID", tq.collection);
+            return HttpResponse::BadRequest().into();
+        }
+    };
+
+    if !srv.has_collection(&tq.collection) {
+        error!("Collection {} doesn't exist", tq.collection);
+        return HttpResponse::BadRequest().into();
+    }
+
+    let entries = match srv.rw.get_trash(&tq.collection).await {
+        Ok(e) => e,
+        Err(e) => {
+            error!("Failed to read trash of {}: {}", tq.collection, e);
+            return conv_store_error(&e);
+        }
+    };
+    let mut itm = match entries.into_iter().find(|e| e.id == id) {
+        Some(e) => e.item,
+        None => {
+            info!(
+                "Collection {} has no element {} in trash",
+                tq.collection, id
+            );
+            return HttpResponse::NotFound().into();
+        }
+    };
+    itm.id = id;
+
+    /* call auth hooks */
+    {
+        let routes = srv
+            .rw
+            .get_internals()
+            .await
+            .safe_strstr("itm_auth_hook", &HashMap::new());
+        for route in routes {
+            if !call_item_auth_hook(
+                &mut srv,
+                &route.1,
+                &usr,
+                &tq.collection,
+                id,
+                Some(itm.clone()),
+                false,
+            )
+            .await
+            {
+                return HttpResponse::Forbidden().into();
+            }
+        }
+    }
+
+    let srv_mut = srv.deref_mut();
+    match srv_mut.rw.get_item(&tq.collection, id).await {
+        Ok(None) => {}
+        Ok(Some(_)) => {
+            info!(
+                "Collection {} element {} exists, not restoring",
+                tq.collection, id
+            );
+            return conv_store_error(&StoreError::Conflict(format!("item {} already exists", id)));
+        }
+        Err(e) => {
+            error!("Failed to read {} element {}: {}", tq.collection, id, e);
+            return conv_store_error(&e);
+        }
+    }
+
+    return write_item(
+        srv_mut,
+        &usr,
+        &user.id().unwrap_or_default(),
+        &tq.collection,
+        itm,
+        false,
+        None,
+        Some("restore"),
+    )
+    .await;
+}
+
+/// Query of item aggregates
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+struct AggregateQuery {
+    /// Collection of items
+    pub collection: String,
+
+    /// Context passed to list filter hooks
+    #[serde(default)]
+    pub context: String,
+
+    /// MongoDB-style filter of items
+    #[serde(default)]
+    pub filter: String,
+
+    /// `strs` field to group items by, like `strs.state`
+    #[serde(default)]
+    pub group: String,
+
+    /// Aggregates like `count,sum:u64s.amount,max:u64s.date`
+    #[serde(default)]
+    pub agg: String,
+}
+
+/// Action that is called to compute counts, sums, minimums and maximums
+/// over items, optionally grouped by a field. Items are limited by the
+/// same database filter hooks as regular listing.
+pub async fn itm_aggregate(
+    user: Identity,
+    data: web::Data<State>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let srv_lock = data.server.lock();
+    let mut srv = unsafe { &mut (*srv_lock.as_ptr()) };
+    let usr = get_user(&mut srv, user.id().unwrap()).await;
+
+    let aq = match serde_qs::from_str::<AggregateQuery>(&req.query_string()) {
+        Ok(q) => q,
+        Err(e) => {
+            info!("Bad aggregate query: {}", e);
+            return HttpResponse::BadRequest().into();
+        }
+    };
+
+    if !srv.has_collection(&aq.collection) {
+        error!("Collection {} doesn't exist", aq.collection);
+        return HttpResponse::BadRequest().into();
+    }
+
+    if aq.group != "" && !is_plain_field(&aq.group, "strs") {
+        error!("Can't group {} by {}", aq.collection, aq.group);
+        return HttpResponse::BadRequest().into();
+    }
+
+    let specs = match parse_aggregates(&aq.agg) {
+        Some(s) => s,
+        None => {
+            error!("Invalid aggregates {} for {}", aq.agg, aq.collection);
+            return HttpResponse::BadRequest().into();
+        }
+    };
+
+    // Grouping and aggregates reveal values just like filters do
+    let mut fields: Vec<String> = specs
+        .iter()
+        .filter(|s| s.field != "")
+        .map(|s| s.field.clone())
+        .collect();
+    if aq.group != "" {
+        fields.push(aq.group.clone());
+    }
+
+    let filter = match list_db_filter(
+        &mut srv,
+        &user.id().unwrap_or_default(),
+        &usr,
+        &aq.collection,
+        &aq.context,
+        &aq.filter,
+        &fields,
+    )
+    .await
+    {
+        Ok(f) => f,
+        Err(resp) => {
+            return resp;
+        }
+    };
+
+    info!(
+        "Collection {} requested aggregates {} group {} filter {}",
+        aq.collection, aq.agg, aq.group, aq.filter
+    );
+    let groups = match srv
+        .rw
+        .aggregate(&aq.collection, &filter, &aq.group, &specs)
+        .await
+    {
+        Ok(g) => g,
+        Err(e) => {
+            error!("Failed to aggregate {}: {}", aq.collection, e);
+            return conv_store_error(&e);
+        }
+    };
+
+    let mut res = HashMap::new();
+    res.insert("groups", groups);
+    HttpResponse::Ok().body(serde_json::to_string(&res).unwrap())
+}
+
+/// Query of full-text search
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+struct SearchQuery {
+    /// Search terms in tantivy query syntax
+    pub q: String,
+
+    /// Collection to search in, all searchable ones if empty
+    #[serde(default)]
+    pub collection: String,
+
+    /// Context passed to list filter hooks
+    #[serde(default)]
+    pub context: String,
+
+    /// Number of hits to skip
+    #[serde(default)]
+    pub skip: u64,
+
+    /// Maximum number of hits
+    #[serde(default = "search_default_limit")]
+    pub limit: u64,
+}
+
+/// Default number of search hits
+fn search_default_limit() -> u64 {
+    return 20;
+}
+
+/// Search hit along with the item
+#[derive(Serialize)]
+struct SearchResultHit {
+    #[serde(flatten)]
+    pub hit: SearchHit,
+
+    /// Found item
+    pub item: Item,
+}
+
+/// Action that is called to search items by text in fields declared in
+/// `search` of internals. Hits go through the same database filter hooks
+/// and list filter hooks as regular listing, so page may hold fewer hits
+/// than requested. Fields hidden or changed by the hooks aren't
+/// highlighted, and hits matching only such fields are dropped.
+pub async fn itm_search(user: Identity, data: web::Data<State>, req: HttpRequest) -> HttpResponse {
+    let srv_lock = data.server.lock();
+    let mut srv = unsafe { &mut (*srv_lock.as_ptr()) };
+    let usr = get_user(&mut srv, user.id().unwrap()).await;
+
+    let sq = match serde_qs::from_str::<SearchQuery>(&req.query_string()) {
+        Ok(q) => q,
+        Err(e) => {
+            info!("Bad search query: {}", e);
+            return HttpResponse::BadRequest().into();
+        }
+    };
+
+    if sq.q.trim() == "" {
+        info!("Empty search query");
+        return HttpResponse::BadRequest().into();
+    }
+
+    if sq.skip > SEARCH_MAX_SKIP {
+        info!("Search skip {} is too large", sq.skip);
+        return HttpResponse::BadRequest().into();
+    }
+
+    if sq.collection != "" && !srv.search.has_collection(&sq.collection) {
+        error!("Collection {} isn't searchable", sq.collection);
+        return HttpResponse::BadRequest().into();
+    }
+
+    info!(
+        "Search {} in collection {} skip {} limit {}",
+        sq.q, sq.collection, sq.skip, sq.limit
+    );
+    let hits = match srv.search.search(
+        &sq.q,
+        &sq.collection,
+        sq.skip,
+        sq.limit.min(SEARCH_MAX_LIMIT),
+    ) {
+        Ok(h) => h,
+        Err(e) => {
+            error!("Failed to search {}: {}", sq.q, e);
+            return HttpResponse::InternalServerError().into();
+        }
+    };
+
+    // Group hits, so that hooks are called once per collection
+    let mut by_collection: HashMap<String, HashMap<u64, Item>> = HashMap::new();
+    for hit in &hits {
+        by_collection.entry(hit.collection.clone()).or_default();
+    }
+    let routes = srv
+        .rw
+        .get_internals()
+        .await
+        .safe_strstr("itm_list_filter_hook", &HashMap::new());
+    let mut sorted_routes: Vec<_> = routes.iter().collect();
+    sorted_routes.sort_by(|a, b| a.0.cmp(b.0));
+    for (collection, map) in by_collection.iter_mut() {
+        let filter = match list_db_filter(
+            &mut srv,
+            &user.id().unwrap_or_default(),
+            &usr,
+            collection,
+            &sq.context,
+            "",
+            &[],
+        )
+        .await
+        {
+            Ok(f) => f,
+            Err(resp) => {
+                return resp;
+            }
+        };
+
+        for hit in hits.iter().filter(|h| &h.collection == collection) {
+            match srv.rw.get_item(collection, hit.id).await {
+                Ok(Some(itm)) => {
+                    let doc = serde_json::to_value(&itm).unwrap_or_default();
+                    if filter.matches(&doc) {
+                        map.insert(itm.id, itm);
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    error!("Failed to read {} element {}: {}", collection, hit.id, e);
+                    return conv_store_error(&e);
+                }
+            }
+        }
+
+        for route in &sorted_routes {
+            call_item_list_filter_hook(&mut srv, &route.1, &usr, collection, &sq.context, map)
+                .await;
+        }
+    }
+
+    let mut res_hits: Vec<SearchResultHit> = Vec::new();
+    for mut hit in hits {
+        let itm = by_collection
+            .get_mut(&hit.collection)
+            .and_then(|m| m.remove(&hit.id));
+        if let Some(itm) = itm {
+            if hit.restrict_to(&itm) {
+                res_hits.push(SearchResultHit { hit, item: itm });
+            }
+        }
+    }
+
+    let mut res = HashMap::new();
+    res.insert("hits", res_hits);
+    HttpResponse::Ok().body(serde_json::to_string(&res).unwrap())
 }
@@ -23,8 +23,9 @@
  */
 use crate::handler::route_call::*;
 use crate::server::user_control::*;
+use crate::state::filter::Filter;
 use crate::state::state::*;
-use crate::state::store::Store;
+use crate::state::store::StoreError;
 use crate::util::crypto::get_otp_code;
 use crate::util::crypto::verify_password;
 use actix_identity::Identity;
@@ -73,13 +74,35 @@ pub async fn gen_otp(
             data: HashMap::new(),
         });
     } else {
-        let mut new_usr_itm = srv
-            .rw
-            .get_item("user", usr.clone().unwrap().id)
-            .await
-            .unwrap();
+        let mut new_usr_itm = match srv.rw.get_item("user", usr.clone().unwrap().id).await {
+            Ok(Some(itm)) => itm,
+            Ok(None) => {
+                info!("User {} disappeared, couldn't otp", lu.username.clone());
+                return web::Json(ProcessResult {
+                    succeeded: false,
+                    error: "Invalid login".to_string(),
+                    data: HashMap::new(),
+                });
+            }
+            Err(e) => {
+                error!("Failed to read user {}: {}", lu.username.clone(), e);
+                return web::Json(ProcessResult {
+                    succeeded: false,
+                    error: "Internal error".to_string(),
+                    data: HashMap::new(),
+                });
+            }
+        };
         new_usr_itm.set_str("otp", &get_otp_code());
-        srv.rw.set_item("user", &new_usr_itm, false).await;
+        if let Err(e) = srv.rw.set_item("user", &new_usr_itm, false).await {
+            error!("Failed to store otp for {}: {}", lu.username.clone(), e);
+            return web::Json(ProcessResult {
+                succeeded: false,
+                error: "Internal error".to_string(),
+                data: HashMap::new(),
+            });
+        }
+        srv.reindex_item("user", new_usr_itm.id).await;
 
         let routes = srv
             .rw
@@ -159,7 +182,28 @@ pub async fn register(
         itm.set_str("email", &email);
         itm.set_bool("role_is_active", true);
 
-        srv.rw.set_item("user", &itm, false).await;
+        match srv.rw.set_item("user", &itm, false).await {
+            Ok(id) => {
+                srv.reindex_item("user", id).await;
+            }
+            Err(StoreError::Conflict(e)) => {
+                // Unique constraint of login or email
+                info!("Failed to register {}: {}", login, e);
+                return web::Json(ProcessResult {
+                    succeeded: false,
+                    error: "Login or email is already used".to_string(),
+                    data: HashMap::new(),
+                });
+            }
+            Err(e) => {
+                error!("Failed to register {}: {}", login, e);
+                return web::Json(ProcessResult {
+                    succeeded: false,
+                    error: "Internal error".to_string(),
+                    data: HashMap::new(),
+                });
+            }
+        }
     }
 
     return web::Json(ProcessResult {
@@ -236,7 +280,10 @@ pub async fn login(
             let mut logged = Item::new();
             logged.id = itm_real.id;
             logged.set_bool("logged_once", true);
-            srv.rw.set_item("user", &logged, true).await;
+            if let Err(e) = srv.rw.set_item("user", &logged, true).await {
+                error!("Failed to mark {} as logged once: {}", lu.username, e);
+            }
+            srv.reindex_item("user", logged.id).await;
             info!("Logged in as {}", lu.username);
         } else {
             // Password doesn't match - error out.
@@ -349,9 +396,15 @@ pub async fn is_logged_in(_user: Option<Identity>, data: web::Data<State>) -> im
         .safe_str("user_role_prefix", "role_is_");
     let email = _user.as_ref().unwrap().id().unwrap();
     if !login_has_bad_symbols(&email) {
-        let filter = "{ \"strs.email\": \"".to_owned() + &email + "\" }";
-        let all_users = srv.rw.get_all_items("user", "name", &filter).await;
-        for item in &all_users.map {
+        let filter = Filter::eq("strs.email", email.clone());
+        let all_users = match srv.rw.get_all_items("user", "name", &filter).await {
+            Ok(u) => u.map,
+            Err(e) => {
+                error!("Failed to look up user {}: {}", email, e);
+                HashMap::new()
+            }
+        };
+        for item in &all_users {
             if item.1.strs.contains_key("email") && item.1.strs["email"] == email {
                 user.username = _user.as_ref().unwrap().id().unwrap();
                 user.id = *item.0;
@@ -21,17 +21,17 @@
  * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
  * DEALINGS IN THE SOFTWARE.
  */
+use crate::handler::web_response::conv_store_error;
 use crate::notif::gcal::*;
 use crate::server::user_control::*;
 use crate::state::state::*;
-use crate::state::store::Store;
 use actix_identity::Identity;
 use actix_multipart::Multipart;
 use actix_web::{web, HttpRequest, HttpResponse};
 use futures_util::TryStreamExt;
 use isabelle_dm::data_model::item::Item;
 use isabelle_dm::data_model::process_result::ProcessResult;
-use log::info;
+use log::{error, info};
 use serde::{Deserialize, Serialize};
 use serde_qs;
 use serde_qs::Config;
@@ -67,10 +67,12 @@ pub async fn setting_edit(
         }
     }
 
-    info!("Settings edited");
-
     // Set settings
-    srv.rw.set_settings(itm.clone()).await;
+    if let Err(e) = srv.rw.set_settings(itm.clone()).await {
+        error!("Failed to store settings: {}", e);
+        return conv_store_error(&e);
+    }
+    info!("Settings edited");
 
     return HttpResponse::Ok().body(
         serde_json::to_string(&ProcessResult {
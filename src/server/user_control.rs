@@ -21,9 +21,9 @@
  * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
  * DEALINGS IN THE SOFTWARE.
  */
-use crate::state::store::Store;
+use crate::state::filter::Filter;
 use isabelle_dm::data_model::item::Item;
-use log::trace;
+use log::{error, trace};
 
 /// Check if login has bad symbols
 pub fn login_has_bad_symbols(login: &str) -> bool {
@@ -37,13 +37,17 @@ pub async fn get_user(srv: &mut crate::state::data::Data, login: String) -> Opti
         return None;
     }
 
-    let filter = "{ \"$or\": [ { \"strs.login\": \"".to_owned()
-        + &login
-        + "\" }, "
-        + "{ \"strs.email\": \""
-        + &login
-        + "\" } ]}";
-    let users = srv.rw.get_all_items("user", "name", &filter).await;
+    let filter = Filter::Or(vec![
+        Filter::eq("strs.login", login.clone()),
+        Filter::eq("strs.email", login.clone()),
+    ]);
+    let users = match srv.rw.get_all_items("user", "name", &filter).await {
+        Ok(u) => u,
+        Err(e) => {
+            error!("Failed to look up user {}: {}", login, e);
+            return None;
+        }
+    };
     let tmp_login = login.to_lowercase();
     trace!("Users: {}", users.map.len());
     for item in &users.map {
@@ -84,13 +88,17 @@ pub async fn clear_otp(srv: &mut crate::state::data::Data, login: String) {
         return;
     }
 
-    let filter = "{ \"$or\": [ { \"strs.login\": \"".to_owned()
-        + &login
-        + "\" }, "
-        + "{ \"strs.email\": \""
-        + &login
-        + "\" } ]}";
-    let users = srv.rw.get_all_items("user", "name", &filter).await;
+    let filter = Filter::Or(vec![
+        Filter::eq("strs.login", login.clone()),
+        Filter::eq("strs.email", login.clone()),
+    ]);
+    let users = match srv.rw.get_all_items("user", "name", &filter).await {
+        Ok(u) => u,
+        Err(e) => {
+            error!("Failed to look up user {}: {}", login, e);
+            return;
+        }
+    };
     let tmp_login = login.to_lowercase();
     for item in &users.map {
         if item.1.strs.contains_key("login")
@@ -100,7 +108,10 @@ pub async fn clear_otp(srv: &mut crate::state::data::Data, login: String) {
         {
             let mut itm = item.1.clone();
             itm.set_str("otp", "");
-            srv.rw.set_item("user", &itm, false).await;
+            if let Err(e) = srv.rw.set_item("user", &itm, false).await {
+                error!("Failed to clear otp for {}: {}", login, e);
+            }
+            srv.reindex_item("user", itm.id).await;
             return;
         }
     }
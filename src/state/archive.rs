@@ -0,0 +1,306 @@
+/*
+ * Isabelle project
+ *
+ * Copyright 2023-2025 Maxim Menshikov
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining
+ * a copy of this software and associated documentation files (the “Software”),
+ * to deal in the Software without restriction, including without limitation
+ * the rights to use, copy, modify, merge, publish, distribute, sublicense,
+ * and/or sell copies of the Software, and to permit persons to whom the
+ * Software is furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included
+ * in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS
+ * OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+ * DEALINGS IN THE SOFTWARE.
+ */
+use crate::state::filter::Filter;
+use crate::state::store::*;
+use crate::util::file::TMP_SUFFIX;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use isabelle_dm::data_model::item::Item;
+use log::{info, trace};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+
+/// Format name written to the manifest
+const ARCHIVE_FORMAT: &str = "isabelle-archive";
+
+/// Version of archive layout
+const ARCHIVE_VERSION: u64 = 1;
+
+/// Number of items written to the store at once on import
+const IMPORT_BATCH: usize = 1000;
+
+/// Archive manifest, always the first entry of the archive
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Manifest {
+    /// Format name
+    pub format: String,
+
+    /// Version of archive layout
+    pub version: u64,
+
+    /// Version of Isabelle Core that created the archive
+    pub core_version: String,
+
+    /// Unix time of export
+    pub created: u64,
+
+    /// Number of items in every collection
+    pub collections: HashMap<String, u64>,
+}
+
+/// Append single file to the archive
+fn append_file(
+    ar: &mut tar::Builder<GzEncoder<File>>,
+    name: &str,
+    data: &[u8],
+    mtime: u64,
+) -> StoreResult<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(mtime);
+    header.set_cksum();
+    ar.append_data(&mut header, name, data)?;
+    return Ok(());
+}
+
+/// Serialize values as JSON Lines
+fn to_jsonl<T: Serialize>(values: &[T]) -> StoreResult<Vec<u8>> {
+    let mut data: Vec<u8> = Vec::new();
+    for v in values {
+        data.extend(serde_json::to_vec(v)?);
+        data.push(b'\n');
+    }
+    return Ok(data);
+}
+
+/// Parse JSON Lines entry of the archive, skipping empty lines
+fn from_jsonl<T: for<'de> Deserialize<'de>, R: Read>(rd: R, name: &str) -> StoreResult<Vec<T>> {
+    let mut values: Vec<T> = Vec::new();
+    for (idx, line) in BufReader::new(rd).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<T>(&line) {
+            Ok(v) => values.push(v),
+            Err(e) => {
+                return Err(StoreError::Serialization(format!(
+                    "{} line {}: {}",
+                    name,
+                    idx + 1,
+                    e
+                )));
+            }
+        }
+    }
+    return Ok(values);
+}
+
+/// Export all collections along with settings, internals and applied
+/// migrations into the gzipped tar archive. Every collection is a JSON
+/// Lines file, history of its items and trash are stored alongside.
+/// The archive is written to the temporary file first, so that the
+/// previous archive is never left half-written.
+pub async fn export_database(st: &mut dyn Store, path: &str) -> StoreResult<()> {
+    let now = chrono::Utc::now().timestamp() as u64;
+    let mut collections = st.get_collections().await;
+    collections.sort();
+
+    let tmp_path = path.to_string() + TMP_SUFFIX;
+    let file = File::create(&tmp_path)?;
+    let mut ar = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+
+    // Collect everything first, since manifest goes first
+    let mut manifest = Manifest {
+        format: ARCHIVE_FORMAT.to_string(),
+        version: ARCHIVE_VERSION,
+        core_version: env!("CARGO_PKG_VERSION").to_string(),
+        created: now,
+        collections: HashMap::new(),
+    };
+    let mut files: Vec<(String, Vec<u8>)> = Vec::new();
+    for collection in &collections {
+        let lr = st.get_all_items(collection, "id", &Filter::all()).await?;
+        let mut items: Vec<Item> = lr.map.into_values().collect();
+        items.sort_by_key(|itm| itm.id);
+        info!("Export collection {}: {} items", collection, items.len());
+
+        let trash = st.get_trash(collection).await?;
+        let history = st.get_collection_history(collection).await?;
+        trace!(
+            "Collection {}: {} history entries, {} items in trash",
+            collection,
+            history.len(),
+            trash.len()
+        );
+
+        manifest
+            .collections
+            .insert(collection.to_string(), items.len() as u64);
+        files.push((
+            "collections/".to_owned() + collection + ".jsonl",
+            to_jsonl(&items)?,
+        ));
+        files.push((
+            "history/".to_owned() + collection + ".jsonl",
+            to_jsonl(&history)?,
+        ));
+        files.push((
+            "trash/".to_owned() + collection + ".jsonl",
+            to_jsonl(&trash)?,
+        ));
+    }
+
+    append_file(
+        &mut ar,
+        "manifest.json",
+        &serde_json::to_vec_pretty(&manifest)?,
+        now,
+    )?;
+    let internals = st.get_internals().await;
+    append_file(
+        &mut ar,
+        "internals.json",
+        &serde_json::to_vec(&internals)?,
+        now,
+    )?;
+    let settings = st.get_settings().await;
+    append_file(
+        &mut ar,
+        "settings.json",
+        &serde_json::to_vec(&settings)?,
+        now,
+    )?;
+    let migrations = st.get_migrations().await?;
+    append_file(&mut ar, "migrations.jsonl", &to_jsonl(&migrations)?, now)?;
+    for (name, data) in &files {
+        append_file(&mut ar, name, data, now)?;
+    }
+
+    let file = ar.into_inner()?.finish()?;
+    file.sync_all()?;
+    drop(file);
+    std::fs::rename(&tmp_path, path)?;
+    info!("Exported {} collections to {}", collections.len(), path);
+    return Ok(());
+}
+
+/// Import the archive created by export_database() into the store.
+/// Internals are written first and reloaded by the store, so that
+/// collections from the archive are registered. Items are written over
+/// the existing ones with the same IDs, other items are left intact.
+/// Migrations are recorded unless the store has them already.
+pub async fn import_database(st: &mut dyn Store, path: &str) -> StoreResult<()> {
+    let file = File::open(path)?;
+    let mut ar = tar::Archive::new(GzDecoder::new(file));
+    let mut manifest: Option<Manifest> = None;
+
+    for entry in ar.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().to_string();
+
+        if name == "manifest.json" {
+            let m: Manifest = serde_json::from_reader(&mut entry)?;
+            if m.format != ARCHIVE_FORMAT || m.version > ARCHIVE_VERSION {
+                return Err(StoreError::Serialization(format!(
+                    "unsupported archive {} version {}",
+                    m.format, m.version
+                )));
+            }
+            info!(
+                "Import archive created by {} at {}",
+                m.core_version, m.created
+            );
+            manifest = Some(m);
+            continue;
+        }
+
+        // Don't touch the store until the archive is known to be ours
+        let m = match &manifest {
+            Some(m) => m,
+            None => {
+                return Err(StoreError::Serialization(
+                    "archive doesn't start with manifest".to_string(),
+                ));
+            }
+        };
+
+        if name == "internals.json" {
+            let internals: Item = serde_json::from_reader(&mut entry)?;
+            st.set_internals(internals).await?;
+            st.reload_internals().await?;
+        } else if name == "settings.json" {
+            let settings: Item = serde_json::from_reader(&mut entry)?;
+            st.set_settings(settings).await?;
+        } else if name == "migrations.jsonl" {
+            let applied: Vec<u64> = st
+                .get_migrations()
+                .await?
+                .iter()
+                .map(|e| e.version)
+                .collect();
+            let migrations: Vec<MigrationEntry> = from_jsonl(&mut entry, &name)?;
+            for m in migrations.iter().filter(|m| !applied.contains(&m.version)) {
+                st.add_migration(m).await?;
+            }
+            trace!("{} migrations in archive", migrations.len());
+        } else if let Some(coll) = name
+            .strip_prefix("collections/")
+            .and_then(|n| n.strip_suffix(".jsonl"))
+        {
+            let items: Vec<Item> = from_jsonl(&mut entry, &name)?;
+            for batch in items.chunks(IMPORT_BATCH) {
+                st.set_items(coll, batch, false).await?;
+            }
+            info!(
+                "Import collection {}: {} items (manifest: {})",
+                coll,
+                items.len(),
+                m.collections.get(coll).unwrap_or(&0)
+            );
+        } else if let Some(coll) = name
+            .strip_prefix("history/")
+            .and_then(|n| n.strip_suffix(".jsonl"))
+        {
+            let history: Vec<HistoryEntry> = from_jsonl(&mut entry, &name)?;
+            for h in &history {
+                st.add_history(h).await?;
+            }
+            trace!("Collection {}: {} history entries", coll, history.len());
+        } else if let Some(coll) = name
+            .strip_prefix("trash/")
+            .and_then(|n| n.strip_suffix(".jsonl"))
+        {
+            let trash: Vec<TrashEntry> = from_jsonl(&mut entry, &name)?;
+            for t in &trash {
+                st.add_trash(t).await?;
+            }
+            trace!("Collection {}: {} items in trash", coll, trash.len());
+        } else {
+            info!("Skipping unknown archive entry {}", name);
+        }
+    }
+
+    if manifest.is_none() {
+        return Err(StoreError::Serialization(
+            "archive has no manifest".to_string(),
+        ));
+    }
+    info!("Imported {}", path);
+    return Ok(());
+}
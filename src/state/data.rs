@@ -27,10 +27,11 @@ use crate::get_password_hash;
 use crate::handler::route_call::call_collection_read_hook;
 use crate::init_google;
 use crate::send_email;
+use crate::state::filter::Filter;
+use crate::state::schema::check_schema;
+use crate::state::search::Search;
 use crate::state::store::Store;
 use crate::state::store_local::*;
-#[cfg(not(feature = "full_file_database"))]
-use crate::state::store_mongo::*;
 use crate::sync_with_google;
 use crate::verify_password;
 use crate::G_STATE;
@@ -39,6 +40,7 @@ use isabelle_dm::data_model::list_result::ListResult;
 use isabelle_dm::data_model::process_result::ProcessResult;
 use isabelle_plugin_api::api::*;
 use isabelle_plugin_api::plugin_pool::PluginPool;
+use log::error;
 use log::info;
 use log::trace;
 use std::any::Any;
@@ -75,17 +77,36 @@ impl PluginApi for IsabellePluginApi {
         let (sender, receiver) = mpsc::channel();
         let collection1 = collection.to_string().clone();
         let sort_key1 = sort_key.to_string().clone();
-        let filter1 = filter.to_string().clone();
+        let filter1 = match Filter::parse(filter) {
+            Ok(f) => f,
+            Err(e) => {
+                error!("Plugin gave bad filter for {}: {}", collection, e);
+                return ListResult {
+                    map: HashMap::new(),
+                    total_count: 0,
+                };
+            }
+        };
         let rt = Arc::clone(&self.runtime);
 
         self.thread_pool.execute(move || {
             sender
                 .send(rt.block_on(async {
                     let srv_mut = unsafe { G_STATE.server.data_ptr().as_mut().unwrap().get_mut() };
-                    srv_mut
+                    match srv_mut
                         .rw
                         .get_all_items(&collection1, &sort_key1, &filter1)
                         .await
+                    {
+                        Ok(lr) => lr,
+                        Err(e) => {
+                            error!("Plugin failed to list {}: {}", collection1, e);
+                            ListResult {
+                                map: HashMap::new(),
+                                total_count: 0,
+                            }
+                        }
+                    }
                 }))
                 .unwrap();
         });
@@ -108,14 +129,23 @@ impl PluginApi for IsabellePluginApi {
         let (sender, receiver) = mpsc::channel();
         let collection1 = collection.to_string().clone();
         let sort_key1 = sort_key.to_string().clone();
-        let filter1 = filter.to_string().clone();
+        let filter1 = match Filter::parse(filter) {
+            Ok(f) => f,
+            Err(e) => {
+                error!("Plugin gave bad filter for {}: {}", collection, e);
+                return ListResult {
+                    map: HashMap::new(),
+                    total_count: 0,
+                };
+            }
+        };
         let rt = Arc::clone(&self.runtime);
 
         self.thread_pool.execute(move || {
             sender
                 .send(rt.block_on(async {
                     let srv_mut = unsafe { G_STATE.server.data_ptr().as_mut().unwrap().get_mut() };
-                    srv_mut
+                    match srv_mut
                         .rw
                         .get_items(
                             &collection1,
@@ -127,6 +157,16 @@ impl PluginApi for IsabellePluginApi {
                             limit,
                         )
                         .await
+                    {
+                        Ok(lr) => lr,
+                        Err(e) => {
+                            error!("Plugin failed to list {}: {}", collection1, e);
+                            ListResult {
+                                map: HashMap::new(),
+                                total_count: 0,
+                            }
+                        }
+                    }
                 }))
                 .unwrap()
         });
@@ -145,7 +185,13 @@ impl PluginApi for IsabellePluginApi {
             sender
                 .send(rt.block_on(async {
                     let srv_mut = unsafe { G_STATE.server.data_ptr().as_mut().unwrap().get_mut() };
-                    srv_mut.rw.get_item(&collection1, id).await
+                    match srv_mut.rw.get_item(&collection1, id).await {
+                        Ok(itm) => itm,
+                        Err(e) => {
+                            error!("Plugin failed to read {} item {}: {}", collection1, id, e);
+                            None
+                        }
+                    }
                 }))
                 .unwrap()
         });
@@ -165,7 +211,19 @@ impl PluginApi for IsabellePluginApi {
             sender
                 .send(rt.block_on(async {
                     let srv_mut = unsafe { G_STATE.server.data_ptr().as_mut().unwrap().get_mut() };
-                    srv_mut.rw.set_item(&collection1, &itm1, merge).await
+                    match srv_mut.rw.set_item(&collection1, &itm1, merge).await {
+                        Ok(id) => {
+                            srv_mut.reindex_item(&collection1, id).await;
+                            id
+                        }
+                        Err(e) => {
+                            error!(
+                                "Plugin failed to set {} item {}: {}",
+                                collection1, itm1.id, e
+                            );
+                            u64::MAX
+                        }
+                    }
                 }))
                 .unwrap()
         });
@@ -184,7 +242,16 @@ impl PluginApi for IsabellePluginApi {
             sender
                 .send(rt.block_on(async {
                     let srv_mut = unsafe { G_STATE.server.data_ptr().as_mut().unwrap().get_mut() };
-                    srv_mut.rw.del_item(&collection1, id).await
+                    match srv_mut.rw.del_item(&collection1, id).await {
+                        Ok(removed) => {
+                            srv_mut.reindex_item(&collection1, id).await;
+                            removed
+                        }
+                        Err(e) => {
+                            error!("Plugin failed to remove {} item {}: {}", collection1, id, e);
+                            false
+                        }
+                    }
                 }))
                 .unwrap()
         });
@@ -461,14 +528,10 @@ impl PluginApi for IsabellePluginApi {
 pub struct Data {
     /// File-based read/write data, which is useful for initial propagation
     /// of database.
-    #[cfg(not(feature = "full_file_database"))]
     pub file_rw: StoreLocal,
 
-    /// Read database access struct.
-    #[cfg(feature = "full_file_database")]
-    pub rw: StoreLocal,
-    #[cfg(not(feature = "full_file_database"))]
-    pub rw: StoreMongo,
+    /// Read database access struct, selected by database URL at startup.
+    pub rw: Box<dyn Store>,
 
     /// Path to Google Calendar.
     pub gc_path: String,
@@ -491,6 +554,9 @@ pub struct Data {
     /// Plugin API instance
     pub plugin_api: Box<dyn PluginApi>,
 
+    /// Full-text search over declared collections
+    pub search: Search,
+
     /// Opaque data (mainly for plugins)
     pub opaque_data: HashMap<String, Option<Box<dyn Any + Send>>>,
 
@@ -500,15 +566,11 @@ pub struct Data {
 
 impl Data {
     pub fn new() -> Self {
-        #[cfg(feature = "full_file_database")]
-        let rw = StoreLocal::new();
-        #[cfg(not(feature = "full_file_database"))]
-        let rw = StoreMongo::new();
         Self {
-            #[cfg(not(feature = "full_file_database"))]
             file_rw: StoreLocal::new(),
 
-            rw: rw,
+            // Replaced with the configured store on startup
+            rw: Box::new(StoreLocal::new()),
 
             gc_path: "".to_string(),
             py_path: "".to_string(),
@@ -519,6 +581,7 @@ impl Data {
                 plugins: Vec::new(),
             },
             plugin_api: Box::new(IsabellePluginApi::new()),
+            search: Search::new(),
             opaque_data: HashMap::new(),
             none_object: None,
         }
@@ -526,25 +589,55 @@ impl Data {
 
     /// Check existence of collection
     pub fn has_collection(&mut self, collection: &str) -> bool {
-        return self.rw.collections.contains_key(collection);
+        return self.rw.has_collection(collection);
+    }
+
+    /// Bring search index up to date with the stored item. Every write
+    /// to storage should be followed by this. The change is already
+    /// stored at this point, so failure is only logged.
+    pub async fn reindex_item(&mut self, collection: &str, id: u64) {
+        if !self.search.has_collection(collection) {
+            return;
+        }
+        match self.rw.get_item(collection, id).await {
+            Ok(Some(itm)) => self.search.update(collection, &itm),
+            Ok(None) => self.search.remove(collection, id),
+            Err(e) => {
+                error!("Failed to reindex {} element {}: {}", collection, id, e);
+            }
+        }
     }
 
     /// Early initialization
     pub async fn init_checks(&mut self) {
         let internals = self.rw.get_internals().await;
+        for e in check_schema(&internals) {
+            error!("Malformed schema rule {}", e);
+        }
         let routes = internals.safe_strstr("collection_read_hook", &HashMap::new());
         let collections = self.rw.get_collections().await;
 
         // Load all collections
         for collection in &collections {
             // Load all items and resave them
-            let items = self.rw.get_item_ids(collection).await;
-            for itm in items {
-                let loaded_item_opt = self.rw.get_item(collection, itm.0).await;
-                if loaded_item_opt.is_none() {
+            let items = match self.rw.get_item_ids(collection).await {
+                Ok(ids) => ids,
+                Err(e) => {
+                    error!("Failed to load {}: {}", collection, e);
                     continue;
                 }
-                let mut loaded_item = loaded_item_opt.unwrap();
+            };
+            for itm in items {
+                let mut loaded_item = match self.rw.get_item(collection, itm.0).await {
+                    Ok(Some(i)) => i,
+                    Ok(None) => {
+                        continue;
+                    }
+                    Err(e) => {
+                        error!("Failed to load {} item {}: {}", collection, itm.0, e);
+                        continue;
+                    }
+                };
                 let mut should_be_saved = false;
                 for route in &routes {
                     if call_collection_read_hook(self, &route.1, collection, &mut loaded_item).await
@@ -553,12 +646,39 @@ impl Data {
                     }
                 }
                 if should_be_saved {
-                    self.rw.set_item(collection, &loaded_item, false).await;
+                    if let Err(e) = self.rw.set_item(collection, &loaded_item, false).await {
+                        error!("Failed to save {} item {}: {}", collection, itm.0, e);
+                    }
+                    self.reindex_item(collection, itm.0).await;
                 }
             }
         }
     }
 
+    /// Remove items kept in trash longer than the retention period
+    pub async fn purge_trash(&mut self) {
+        let days = self
+            .rw
+            .get_internals()
+            .await
+            .safe_u64("trash_retention_days", 30);
+        if days == 0 {
+            return;
+        }
+
+        let now = chrono::Utc::now().timestamp() as u64;
+        let before = now.saturating_sub(days.saturating_mul(24 * 3600));
+        match self.rw.purge_trash(before).await {
+            Ok(0) => {}
+            Ok(cnt) => {
+                info!("Purged {} items from trash", cnt);
+            }
+            Err(e) => {
+                error!("Failed to purge trash: {}", e);
+            }
+        }
+    }
+
     /// Initialize the data path for plugins
     pub async fn init_data_path(&mut self) {
         info!("Data path for plugins: {}", self.data_path);
@@ -0,0 +1,776 @@
+/*
+ * Isabelle project
+ *
+ * Copyright 2023-2025 Maxim Menshikov
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining
+ * a copy of this software and associated documentation files (the “Software”),
+ * to deal in the Software without restriction, including without limitation
+ * the rights to use, copy, modify, merge, publish, distribute, sublicense,
+ * and/or sell copies of the Software, and to permit persons to whom the
+ * Software is furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included
+ * in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS
+ * OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+ * DEALINGS IN THE SOFTWARE.
+ */
+use crate::state::store::{
+    parse_sort, project_item, AggregateGroup, AggregateOp, AggregateSpec, ListCursor, OrderedList,
+    SortSpec,
+};
+use isabelle_dm::data_model::item::Item;
+use log::error;
+use serde_json::{json, Map, Value};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+/// Find the value at the given dotted path (like `strs.login`)
+pub fn lookup_path<'a>(doc: &'a Value, path: &str) -> Option<&'a Value> {
+    let mut cur = doc;
+    for part in path.split('.') {
+        match cur {
+            Value::Object(m) => {
+                cur = m.get(part)?;
+            }
+            _ => {
+                return None;
+            }
+        }
+    }
+    return Some(cur);
+}
+
+/// Rank of the value type in Mongo comparison order
+fn type_rank(val: Option<&Value>) -> u8 {
+    match val {
+        None | Some(Value::Null) => 0,
+        Some(Value::Number(_)) => 1,
+        Some(Value::String(_)) => 2,
+        Some(Value::Object(_)) => 3,
+        Some(Value::Array(_)) => 4,
+        Some(Value::Bool(_)) => 5,
+    }
+}
+
+/// Compare two numbers without losing precision on large u64 values
+fn compare_numbers(a: &serde_json::Number, b: &serde_json::Number) -> Ordering {
+    if let (Some(x), Some(y)) = (a.as_u64(), b.as_u64()) {
+        return x.cmp(&y);
+    }
+    if let (Some(x), Some(y)) = (a.as_i64(), b.as_i64()) {
+        return x.cmp(&y);
+    }
+    let x = a.as_f64().unwrap_or(0.0);
+    let y = b.as_f64().unwrap_or(0.0);
+    return x.partial_cmp(&y).unwrap_or(Ordering::Equal);
+}
+
+/// Compare two values of the same type. Returns None when types differ,
+/// which is what Mongo does for range operators.
+fn compare_same_type(a: Option<&Value>, b: Option<&Value>) -> Option<Ordering> {
+    match (a, b) {
+        (Some(Value::Number(x)), Some(Value::Number(y))) => Some(compare_numbers(x, y)),
+        (Some(Value::String(x)), Some(Value::String(y))) => Some(x.cmp(y)),
+        (Some(Value::Bool(x)), Some(Value::Bool(y))) => Some(x.cmp(y)),
+        (None | Some(Value::Null), None | Some(Value::Null)) => Some(Ordering::Equal),
+        _ => None,
+    }
+}
+
+/// Compare two values for sorting, following Mongo type order
+pub fn compare_for_sort(a: Option<&Value>, b: Option<&Value>) -> Ordering {
+    match compare_same_type(a, b) {
+        Some(ord) => ord,
+        None => type_rank(a).cmp(&type_rank(b)),
+    }
+}
+
+/// Check equality the way Mongo does: missing fields equal null
+fn values_equal(a: Option<&Value>, b: &Value) -> bool {
+    match compare_same_type(a, Some(b)) {
+        Some(ord) => ord == Ordering::Equal,
+        None => a == Some(b),
+    }
+}
+
+/// Maximum nesting of logical operators in a filter
+pub const FILTER_MAX_DEPTH: usize = 16;
+
+/// Filter that can't be parsed or uses unsupported features
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilterError(pub String);
+
+impl fmt::Display for FilterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid filter: {}", self.0)
+    }
+}
+
+/// Comparison operator of a field condition
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FilterOp {
+    Eq,
+    Ne,
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+    In,
+    Nin,
+    Exists,
+}
+
+impl FilterOp {
+    /// Parse Mongo operator name like `$gte`
+    pub fn parse(name: &str) -> Option<Self> {
+        return match name {
+            "$eq" => Some(FilterOp::Eq),
+            "$ne" => Some(FilterOp::Ne),
+            "$gt" => Some(FilterOp::Gt),
+            "$gte" => Some(FilterOp::Gte),
+            "$lt" => Some(FilterOp::Lt),
+            "$lte" => Some(FilterOp::Lte),
+            "$in" => Some(FilterOp::In),
+            "$nin" => Some(FilterOp::Nin),
+            "$exists" => Some(FilterOp::Exists),
+            _ => None,
+        };
+    }
+
+    /// Mongo operator name
+    pub fn name(&self) -> &'static str {
+        return match self {
+            FilterOp::Eq => "$eq",
+            FilterOp::Ne => "$ne",
+            FilterOp::Gt => "$gt",
+            FilterOp::Gte => "$gte",
+            FilterOp::Lt => "$lt",
+            FilterOp::Lte => "$lte",
+            FilterOp::In => "$in",
+            FilterOp::Nin => "$nin",
+            FilterOp::Exists => "$exists",
+        };
+    }
+}
+
+/// Typed item filter. Clients and plugins send it as Mongo-style JSON,
+/// which is parsed and validated here, and every storage translates it
+/// to its own queries.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    /// All conditions hold, empty list matches everything
+    And(Vec<Filter>),
+
+    /// Any condition holds
+    Or(Vec<Filter>),
+
+    /// None of conditions holds
+    Nor(Vec<Filter>),
+
+    /// Condition doesn't hold, as for field `$not`
+    Not(Box<Filter>),
+
+    /// Condition on the field like `strs.login`
+    Cond {
+        field: String,
+        op: FilterOp,
+        value: Value,
+    },
+}
+
+impl Filter {
+    /// Filter matching every item
+    pub fn all() -> Self {
+        return Filter::And(Vec::new());
+    }
+
+    /// Check if the filter matches every item
+    pub fn is_all(&self) -> bool {
+        return *self == Filter::all();
+    }
+
+    /// Field equality condition
+    pub fn eq(field: &str, value: impl Into<Value>) -> Self {
+        return Filter::Cond {
+            field: field.to_string(),
+            op: FilterOp::Eq,
+            value: value.into(),
+        };
+    }
+
+    /// Join two filters so that both must hold
+    pub fn and(self, other: Filter) -> Self {
+        if self.is_all() {
+            return other;
+        }
+        if other.is_all() {
+            return self;
+        }
+        let mut list = match self {
+            Filter::And(l) => l,
+            f => vec![f],
+        };
+        match other {
+            Filter::And(l) => list.extend(l),
+            f => list.push(f),
+        }
+        return Filter::And(list);
+    }
+
+    /// Parse Mongo-style JSON filter. Empty text matches everything.
+    /// Supports `$and`, `$or`, `$nor`, field equality and `$eq`, `$ne`,
+    /// `$gt`, `$gte`, `$lt`, `$lte`, `$in`, `$nin`, `$exists` and `$not`.
+    pub fn parse(text: &str) -> Result<Self, FilterError> {
+        if text.trim() == "" {
+            return Ok(Filter::all());
+        }
+        let js: Value = match serde_json::from_str(text) {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(FilterError(format!("bad JSON: {}", e)));
+            }
+        };
+        return Filter::from_json(&js, 0);
+    }
+
+    /// Parse filter document at the given nesting level
+    fn from_json(js: &Value, depth: usize) -> Result<Self, FilterError> {
+        if depth > FILTER_MAX_DEPTH {
+            return Err(FilterError("filter is nested too deep".to_string()));
+        }
+        let cond = match js {
+            Value::Object(m) => m,
+            _ => {
+                return Err(FilterError("filter must be an object".to_string()));
+            }
+        };
+
+        let mut list: Vec<Filter> = Vec::new();
+        for (key, val) in cond {
+            match key.as_str() {
+                "$and" | "$or" | "$nor" => {
+                    let arr = match val {
+                        Value::Array(arr) if !arr.is_empty() => arr,
+                        _ => {
+                            return Err(FilterError(format!("{} needs non-empty list", key)));
+                        }
+                    };
+                    let mut sub: Vec<Filter> = Vec::new();
+                    for f in arr {
+                        sub.push(Filter::from_json(f, depth + 1)?);
+                    }
+                    list.push(match key.as_str() {
+                        "$and" => Filter::And(sub),
+                        "$or" => Filter::Or(sub),
+                        _ => Filter::Nor(sub),
+                    });
+                }
+                k if k.starts_with('$') => {
+                    return Err(FilterError(format!("unsupported operator {}", k)));
+                }
+                field => {
+                    check_field_name(field)?;
+                    list.extend(Filter::field_from_json(field, val, depth)?);
+                }
+            }
+        }
+
+        if list.len() == 1 {
+            return Ok(list.remove(0));
+        }
+        return Ok(Filter::And(list));
+    }
+
+    /// Parse condition of the field: either literal or operators
+    fn field_from_json(field: &str, val: &Value, depth: usize) -> Result<Vec<Self>, FilterError> {
+        let ops = match val {
+            Value::Object(m) if !m.is_empty() && m.keys().any(|k| k.starts_with('$')) => m,
+            _ => {
+                return Ok(vec![Filter::eq(field, val.clone())]);
+            }
+        };
+
+        let mut list: Vec<Filter> = Vec::new();
+        for (name, arg) in ops {
+            if name == "$not" {
+                if depth >= FILTER_MAX_DEPTH {
+                    return Err(FilterError("filter is nested too deep".to_string()));
+                }
+                match arg {
+                    Value::Object(m) if !m.is_empty() && m.keys().all(|k| k.starts_with('$')) => {
+                        let mut sub = Filter::field_from_json(field, arg, depth + 1)?;
+                        let inner = if sub.len() == 1 {
+                            sub.remove(0)
+                        } else {
+                            Filter::And(sub)
+                        };
+                        list.push(Filter::Not(Box::new(inner)));
+                    }
+                    _ => {
+                        return Err(FilterError(format!("$not of {} needs operators", field)));
+                    }
+                }
+                continue;
+            }
+
+            let op = match FilterOp::parse(name) {
+                Some(op) => op,
+                None => {
+                    return Err(FilterError(format!(
+                        "unsupported operator {} for {}",
+                        name, field
+                    )));
+                }
+            };
+            match (op, arg) {
+                (FilterOp::In | FilterOp::Nin, Value::Array(_)) => {}
+                (FilterOp::In | FilterOp::Nin, _) => {
+                    return Err(FilterError(format!("{} of {} needs list", name, field)));
+                }
+                (FilterOp::Exists, Value::Bool(_)) => {}
+                (FilterOp::Exists, _) => {
+                    return Err(FilterError(format!("$exists of {} needs boolean", field)));
+                }
+                _ => {}
+            }
+            list.push(Filter::Cond {
+                field: field.to_string(),
+                op,
+                value: arg.clone(),
+            });
+        }
+        return Ok(list);
+    }
+
+    /// Mongo-style JSON form of the filter
+    pub fn to_json(&self) -> Value {
+        return match self {
+            Filter::And(l) if l.is_empty() => json!({}),
+            Filter::And(l) => json!({ "$and": list_to_json(l) }),
+            Filter::Or(l) => json!({ "$or": list_to_json(l) }),
+            Filter::Nor(l) => json!({ "$nor": list_to_json(l) }),
+            Filter::Not(f) => json!({ "$nor": [f.to_json()] }),
+            Filter::Cond { field, op, value } => {
+                let mut ops = Map::new();
+                ops.insert(op.name().to_string(), value.clone());
+                let mut m = Map::new();
+                m.insert(field.clone(), Value::Object(ops));
+                Value::Object(m)
+            }
+        };
+    }
+
+    /// Evaluate the filter against the item converted to JSON
+    pub fn matches(&self, doc: &Value) -> bool {
+        return match self {
+            Filter::And(l) => l.iter().all(|f| f.matches(doc)),
+            Filter::Or(l) => l.iter().any(|f| f.matches(doc)),
+            Filter::Nor(l) => !l.iter().any(|f| f.matches(doc)),
+            Filter::Not(f) => !f.matches(doc),
+            Filter::Cond { field, op, value } => {
+                match_condition(lookup_path(doc, field), *op, value)
+            }
+        };
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_json())
+    }
+}
+
+/// Fields and operators that clients may use in filters of the collection
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilterPolicy {
+    /// Allowed operators by field, empty list allows all of them
+    pub fields: HashMap<String, Vec<FilterOp>>,
+}
+
+impl FilterPolicy {
+    /// Read the policy of the collection from internals `filter_allow`
+    /// string map. Every value is `<collection>:<field>[:<op>,<op>...]`,
+    /// like `job:u64s.date:$gte,$lt` or `job:strs.state`. Without
+    /// operators, all supported ones are allowed for the field. Returns
+    /// None if the collection has no policy, so any field may be used.
+    pub fn for_collection(internals: &Item, collection: &str) -> Option<Self> {
+        let declared = internals.safe_strstr("filter_allow", &HashMap::new());
+        let mut fields: HashMap<String, Vec<FilterOp>> = HashMap::new();
+        for decl in declared.values() {
+            let parts: Vec<&str> = decl.splitn(3, ':').collect();
+            if parts.len() < 2 || parts[0] != collection || parts[1] == "" {
+                continue;
+            }
+            let mut ops: Vec<FilterOp> = Vec::new();
+            for name in parts.get(2).unwrap_or(&"").split(',') {
+                match FilterOp::parse(name.trim()) {
+                    Some(op) => ops.push(op),
+                    None if name.trim() == "" => {}
+                    None => {
+                        error!("Unknown filter operator {} in {}", name, decl);
+                    }
+                }
+            }
+            fields.entry(parts[1].to_string()).or_default().extend(ops);
+        }
+        if fields.is_empty() {
+            return None;
+        }
+        return Some(Self { fields });
+    }
+
+    /// Check that the filter uses only allowed fields and operators
+    pub fn check(&self, filter: &Filter) -> Result<(), FilterError> {
+        match filter {
+            Filter::And(l) | Filter::Or(l) | Filter::Nor(l) => {
+                for f in l {
+                    self.check(f)?;
+                }
+            }
+            Filter::Not(f) => self.check(f)?,
+            Filter::Cond { field, op, .. } => match self.fields.get(field) {
+                Some(ops) if ops.is_empty() || ops.contains(op) => {}
+                Some(_) => {
+                    return Err(FilterError(format!(
+                        "operator {} is not allowed for {}",
+                        op.name(),
+                        field
+                    )));
+                }
+                None => {
+                    return Err(FilterError(format!("field {} is not allowed", field)));
+                }
+            },
+        }
+        return Ok(());
+    }
+
+    /// Check that fields used in other ways, like grouping, are allowed
+    pub fn check_fields(&self, fields: &[String]) -> Result<(), FilterError> {
+        for field in fields {
+            if !self.fields.contains_key(field) {
+                return Err(FilterError(format!("field {} is not allowed", field)));
+            }
+        }
+        return Ok(());
+    }
+}
+
+/// JSON form of filters joined by a logical operator
+fn list_to_json(list: &[Filter]) -> Vec<Value> {
+    return list.iter().map(|f| f.to_json()).collect();
+}
+
+/// Check that the field name is a dotted path without operators
+fn check_field_name(field: &str) -> Result<(), FilterError> {
+    if field.split('.').any(|p| p.is_empty() || p.starts_with('$')) {
+        return Err(FilterError(format!("bad field name {}", field)));
+    }
+    return Ok(());
+}
+
+/// Match a single field value against the condition
+fn match_condition(val: Option<&Value>, op: FilterOp, arg: &Value) -> bool {
+    return match op {
+        FilterOp::Eq => values_equal(val, arg),
+        FilterOp::Ne => !values_equal(val, arg),
+        FilterOp::Gt => compare_same_type(val, Some(arg)) == Some(Ordering::Greater),
+        FilterOp::Gte => matches!(
+            compare_same_type(val, Some(arg)),
+            Some(Ordering::Greater | Ordering::Equal)
+        ),
+        FilterOp::Lt => compare_same_type(val, Some(arg)) == Some(Ordering::Less),
+        FilterOp::Lte => matches!(
+            compare_same_type(val, Some(arg)),
+            Some(Ordering::Less | Ordering::Equal)
+        ),
+        FilterOp::In => match arg {
+            Value::Array(arr) => arr.iter().any(|a| values_equal(val, a)),
+            _ => false,
+        },
+        FilterOp::Nin => match arg {
+            Value::Array(arr) => !arr.iter().any(|a| values_equal(val, a)),
+            _ => false,
+        },
+        FilterOp::Exists => val.is_some() == (*arg == Value::Bool(true)),
+    };
+}
+
+/// Compare the item with the cursor position in sort order
+fn compare_to_cursor(doc: &Value, id: u64, specs: &[SortSpec], cursor: &ListCursor) -> Ordering {
+    for (i, spec) in specs.iter().enumerate() {
+        let ord = compare_for_sort(lookup_path(doc, &spec.field), cursor.values.get(i));
+        if ord != Ordering::Equal {
+            return if spec.descending { ord.reverse() } else { ord };
+        }
+    }
+    return id.cmp(&cursor.id);
+}
+
+/// Filter, sort and paginate items for stores that can't do it natively.
+/// Items are expected to come in ID order, which is kept for equal sort keys.
+pub fn select_items(
+    items: Vec<Item>,
+    sort_key: &str,
+    filter: &Filter,
+    cursor: Option<&ListCursor>,
+    fields: &[String],
+    skip: u64,
+    limit: u64,
+) -> OrderedList {
+    let mut ol = OrderedList::new();
+
+    let mut matched: Vec<(Value, Item)> = Vec::new();
+    for itm in items {
+        let doc = serde_json::to_value(&itm).unwrap_or(Value::Null);
+        if !filter.matches(&doc) {
+            continue;
+        }
+        matched.push((doc, itm));
+    }
+
+    let specs = parse_sort(sort_key);
+    if !specs.is_empty() {
+        matched.sort_by(|a, b| {
+            for spec in &specs {
+                let ord = compare_for_sort(
+                    lookup_path(&a.0, &spec.field),
+                    lookup_path(&b.0, &spec.field),
+                );
+                if ord != Ordering::Equal {
+                    return if spec.descending { ord.reverse() } else { ord };
+                }
+            }
+            return Ordering::Equal;
+        });
+    }
+
+    let eff_skip = if skip == u64::MAX { 0 } else { skip };
+    ol.list.total_count = matched.len() as u64;
+    let start = match cursor {
+        Some(c) => matched
+            .iter()
+            .position(|(doc, itm)| compare_to_cursor(doc, itm.id, &specs, c) == Ordering::Greater)
+            .unwrap_or(matched.len()),
+        None => 0,
+    };
+    let mut more = false;
+    for (_doc, itm) in matched.into_iter().skip(start).skip(eff_skip as usize) {
+        if ol.order.len() as u64 >= limit {
+            more = true;
+            break;
+        }
+        ol.push(itm);
+    }
+    if more {
+        if let Some(last) = ol.order.last().and_then(|id| ol.list.map.get(id)) {
+            ol.next_cursor = ListCursor::after_item(sort_key, last).encode();
+        }
+    }
+    for itm in ol.list.map.values_mut() {
+        project_item(itm, fields);
+    }
+
+    return ol;
+}
+
+/// Compute aggregates over items for stores that can't do it natively
+pub fn aggregate_items<'a>(
+    items: impl Iterator<Item = &'a Item>,
+    group_by: &str,
+    specs: &[AggregateSpec],
+) -> Vec<AggregateGroup> {
+    let group_key = group_by.strip_prefix("strs.");
+    let mut groups: BTreeMap<Option<String>, HashMap<String, u64>> = BTreeMap::new();
+    for itm in items {
+        let key = group_key.and_then(|k| itm.strs.get(k).cloned());
+        let values = groups.entry(key).or_default();
+        for spec in specs {
+            let name = spec.name();
+            let val = match spec.op {
+                AggregateOp::Count => Some(1),
+                _ => spec
+                    .field
+                    .strip_prefix("u64s.")
+                    .and_then(|f| itm.u64s.get(f).cloned()),
+            };
+            // Count and sum are zero when nothing is added, like in Mongo
+            if let AggregateOp::Count | AggregateOp::Sum = spec.op {
+                values.entry(name.clone()).or_insert(0);
+            }
+            if let Some(val) = val {
+                let new_val = match (spec.op, values.get(&name)) {
+                    (AggregateOp::Count | AggregateOp::Sum, Some(cur)) => cur.saturating_add(val),
+                    (AggregateOp::Min, Some(cur)) => val.min(*cur),
+                    (AggregateOp::Max, Some(cur)) => val.max(*cur),
+                    (_, None) => val,
+                };
+                values.insert(name, new_val);
+            }
+        }
+    }
+    return groups
+        .into_iter()
+        .map(|(key, values)| AggregateGroup { key, values })
+        .collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::store::parse_aggregates;
+
+    fn doc(n: u64, name: &str) -> Value {
+        return json!({ "id": 1, "u64s": { "n": n }, "strs": { "name": name } });
+    }
+
+    fn nested_and(levels: usize) -> String {
+        let mut text = r#"{"u64s.n":1}"#.to_string();
+        for _ in 0..levels {
+            text = format!(r#"{{"$and":[{}]}}"#, text);
+        }
+        return text;
+    }
+
+    #[test]
+    fn parse_operators() {
+        let f = Filter::parse(r#"{"u64s.n":{"$gte":2,"$lt":5},"strs.name":"a"}"#).unwrap();
+        assert!(f.matches(&doc(2, "a")));
+        assert!(f.matches(&doc(4, "a")));
+        assert!(!f.matches(&doc(5, "a")));
+        assert!(!f.matches(&doc(3, "b")));
+
+        let f = Filter::parse(r#"{"strs.name":{"$in":["a","b"]}}"#).unwrap();
+        assert!(f.matches(&doc(1, "b")));
+        assert!(!f.matches(&doc(1, "c")));
+
+        let f = Filter::parse(r#"{"strs.other":{"$exists":false}}"#).unwrap();
+        assert!(f.matches(&doc(1, "a")));
+
+        assert!(Filter::parse("").unwrap().is_all());
+        assert_eq!(
+            Filter::parse(r#"{"strs.name":"a"}"#).unwrap(),
+            Filter::eq("strs.name", "a")
+        );
+    }
+
+    #[test]
+    fn parse_rejects_bad_filters() {
+        assert!(Filter::parse(r#"{"u64s.n":{"$regex":"a"}}"#).is_err());
+        assert!(Filter::parse(r#"{"$where":"1"}"#).is_err());
+        assert!(Filter::parse(r#"{"strs.name":{"$in":"a"}}"#).is_err());
+        assert!(Filter::parse(r#"{"strs.name":{"$exists":1}}"#).is_err());
+        assert!(Filter::parse(r#"{"$or":[]}"#).is_err());
+        assert!(Filter::parse(r#"{"strs.$name":1}"#).is_err());
+        assert!(Filter::parse("[1]").is_err());
+        assert!(Filter::parse("{").is_err());
+    }
+
+    #[test]
+    fn parse_limits_depth() {
+        assert!(Filter::parse(&nested_and(FILTER_MAX_DEPTH)).is_ok());
+        assert_eq!(
+            Filter::parse(&nested_and(FILTER_MAX_DEPTH + 1)),
+            Err(FilterError("filter is nested too deep".to_string()))
+        );
+    }
+
+    #[test]
+    fn not_and_nor() {
+        let f = Filter::parse(r#"{"u64s.n":{"$not":{"$gt":5}}}"#).unwrap();
+        assert!(f.matches(&doc(3, "a")));
+        assert!(!f.matches(&doc(7, "a")));
+        // Like in Mongo, $not also matches items without the field
+        assert!(f.matches(&json!({ "id": 1 })));
+        assert!(Filter::parse(r#"{"u64s.n":{"$not":5}}"#).is_err());
+
+        let f = Filter::parse(r#"{"$nor":[{"strs.name":"a"},{"u64s.n":{"$lt":2}}]}"#).unwrap();
+        assert!(f.matches(&doc(3, "b")));
+        assert!(!f.matches(&doc(3, "a")));
+        assert!(!f.matches(&doc(1, "b")));
+
+        // JSON form keeps the meaning
+        let g = Filter::parse(&f.to_json().to_string()).unwrap();
+        assert!(g.matches(&doc(3, "b")));
+        assert!(!g.matches(&doc(1, "b")));
+    }
+
+    #[test]
+    fn policy_allows_and_denies() {
+        let mut internals = Item::new();
+        let mut allow = HashMap::new();
+        allow.insert("1".to_string(), "job:u64s.date:$gte,$lt".to_string());
+        allow.insert("2".to_string(), "job:strs.state".to_string());
+        allow.insert("3".to_string(), "user:strs.login".to_string());
+        internals.strstrs.insert("filter_allow".to_string(), allow);
+
+        assert!(FilterPolicy::for_collection(&internals, "other").is_none());
+        let policy = FilterPolicy::for_collection(&internals, "job").unwrap();
+        let check = |text: &str| policy.check(&Filter::parse(text).unwrap());
+        assert!(check(r#"{"u64s.date":{"$gte":1,"$lt":5}}"#).is_ok());
+        assert!(check(r#"{"strs.state":{"$ne":"done"}}"#).is_ok());
+        assert!(check(r#"{"$or":[{"strs.state":"a"},{"u64s.date":{"$lt":2}}]}"#).is_ok());
+        assert!(check(r#"{"u64s.date":5}"#).is_err());
+        assert!(check(r#"{"u64s.date":{"$not":{"$gt":5}}}"#).is_err());
+        assert!(check(r#"{"strs.login":"a"}"#).is_err());
+        assert!(policy.check_fields(&["strs.state".to_string()]).is_ok());
+        assert!(policy.check_fields(&["strs.login".to_string()]).is_err());
+    }
+
+    fn amount_item(kind: Option<&str>, amount: Option<u64>) -> Item {
+        let mut itm = Item::new();
+        if let Some(k) = kind {
+            itm.set_str("kind", k);
+        }
+        if let Some(a) = amount {
+            itm.set_u64("amount", a);
+        }
+        return itm;
+    }
+
+    #[test]
+    fn aggregate_groups() {
+        let items = vec![
+            amount_item(Some("a"), Some(5)),
+            amount_item(Some("a"), Some(2)),
+            amount_item(Some("b"), None),
+            amount_item(None, Some(7)),
+        ];
+        let specs =
+            parse_aggregates("count,sum:u64s.amount,min:u64s.amount,max:u64s.amount").unwrap();
+        let groups = aggregate_items(items.iter(), "strs.kind", &specs);
+        assert_eq!(groups.len(), 3);
+
+        // Items without the grouping field make their own group
+        assert_eq!(groups[0].key, None);
+        assert_eq!(groups[0].values["count"], 1);
+        assert_eq!(groups[0].values["sum:u64s.amount"], 7);
+
+        assert_eq!(groups[1].key, Some("a".to_string()));
+        assert_eq!(groups[1].values["count"], 2);
+        assert_eq!(groups[1].values["sum:u64s.amount"], 7);
+        assert_eq!(groups[1].values["min:u64s.amount"], 2);
+        assert_eq!(groups[1].values["max:u64s.amount"], 5);
+
+        // Sum is zero and min/max are missing when no item has the field
+        assert_eq!(groups[2].key, Some("b".to_string()));
+        assert_eq!(groups[2].values["count"], 1);
+        assert_eq!(groups[2].values["sum:u64s.amount"], 0);
+        assert!(!groups[2].values.contains_key("min:u64s.amount"));
+        assert!(!groups[2].values.contains_key("max:u64s.amount"));
+
+        let groups = aggregate_items(items.iter(), "", &specs);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].values["count"], 4);
+        assert_eq!(groups[0].values["sum:u64s.amount"], 14);
+        assert_eq!(groups[0].values["max:u64s.amount"], 7);
+    }
+}
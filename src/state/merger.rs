@@ -21,36 +21,387 @@
  * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
  * DEALINGS IN THE SOFTWARE.
  */
-#![cfg(not(feature = "full_file_database"))]
+use crate::state::filter::Filter;
 use crate::state::store::*;
-use crate::util::crypto::{get_new_salt, get_password_hash, is_hashed_password};
-use log::info;
+use crate::state::store_local::StoreLocal;
+use crate::util::crypto::{get_new_salt, get_password_hash, is_hashed_password, verify_password};
+use isabelle_dm::data_model::item::Item;
+use log::{debug, error, info};
+use std::collections::HashMap;
+
+/// Way of handling items that already exist in the target store
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum MergeStrategy {
+    /// Leave existing items untouched
+    SkipExisting,
+    /// Replace existing items
+    Overwrite,
+    /// Merge fields into existing items
+    Merge,
+    /// Don't write anything if some existing item differs
+    FailOnConflict,
+}
+
+/// Difference between the collection in source and target stores
+#[derive(Debug, Default)]
+pub struct MergeDiff {
+    /// Collection name
+    pub collection: String,
+
+    /// Items missing in the target store
+    pub created: Vec<u64>,
+
+    /// Items that differ, along with the differing fields
+    pub changed: Vec<(u64, Vec<String>)>,
+
+    /// Items that are the same in both stores
+    pub identical: Vec<u64>,
+
+    /// Differing items left untouched by skip-existing strategy
+    pub skipped: Vec<u64>,
+}
+
+/// Collect keys with different values in two field maps
+fn diff_fields<V: PartialEq>(
+    prefix: &str,
+    old: &HashMap<String, V>,
+    new: &HashMap<String, V>,
+    out: &mut Vec<String>,
+) {
+    for (k, v) in old {
+        if new.get(k) != Some(v) {
+            out.push(prefix.to_owned() + "." + k);
+        }
+    }
+    for k in new.keys() {
+        if !old.contains_key(k) {
+            out.push(prefix.to_owned() + "." + k);
+        }
+    }
+}
+
+/// Get fields that differ between two versions of the item. Revision is
+/// maintained by the store, so it doesn't count.
+fn changed_fields(old: &Item, new: &Item) -> Vec<String> {
+    let mut out: Vec<String> = Vec::new();
+    diff_fields("strs", &old.strs, &new.strs, &mut out);
+    diff_fields("bools", &old.bools, &new.bools, &mut out);
+    diff_fields("u64s", &old.u64s, &new.u64s, &mut out);
+    diff_fields("strstrs", &old.strstrs, &new.strstrs, &mut out);
+    out.retain(|f| *f != "u64s.".to_owned() + REVISION_KEY);
+    out.sort();
+    return out;
+}
 
 /// Merge collections from one store to another.
 /// This is done only once, so no need to optimize too much.
 ///
 /// Special handling for the `user` collection: any `password` field that is
 /// not already a PHC-format argon2 hash (i.e. plain-text from seed data) is
-/// hashed with argon2id before being written to the target store.
-pub async fn merge_database(st1: &mut dyn Store, st2: &mut dyn Store) {
+/// hashed with argon2id before being written to the target store, unless
+/// the target store already has the hash of the same password.
+///
+/// All collections are compared first and written only then, so that
+/// fail-on-conflict strategy and dry run don't change anything. Stops on
+/// the first storage error.
+pub async fn merge_database(
+    st1: &mut dyn Store,
+    st2: &mut dyn Store,
+    strategy: MergeStrategy,
+    dry_run: bool,
+) -> StoreResult<Vec<MergeDiff>> {
     let collections = st1.get_collections().await;
+    let mut diffs: Vec<MergeDiff> = Vec::new();
+    let mut batches: Vec<Vec<Item>> = Vec::new();
     for collection in &collections {
         info!("Merge collection: {}", &collection);
-        let items = st1.get_all_items(collection, "id", "").await;
+        let items = st1.get_all_items(collection, "id", &Filter::all()).await?;
+        let existing = st2.get_all_items(collection, "id", &Filter::all()).await?;
+        let mut diff = MergeDiff {
+            collection: collection.to_string(),
+            ..Default::default()
+        };
+        let mut batch: Vec<Item> = Vec::new();
         for item in &items.map {
-            info!("Setting {} item {}", &collection, &item.0);
+            debug!("Comparing {} item {}", &collection, &item.0);
             let mut itm = item.1.clone();
+            let old_itm = existing.map.get(item.0);
 						// TODO: Outfactor into a utility function?
             if collection == "user" {
                 let pw = itm.safe_str("password", "");
+                let old_pw = old_itm
+                    .map(|o| o.safe_str("password", ""))
+                    .unwrap_or_default();
                 if !pw.is_empty() && !is_hashed_password(&pw) {
-                    info!("Hashing plain-text password for user id {}", itm.id);
-                    let salt = get_new_salt();
-                    let hash = get_password_hash(&pw, &salt);
-                    itm.set_str("password", &hash);
+                    if is_hashed_password(&old_pw) && verify_password(&pw, &old_pw) {
+                        itm.set_str("password", &old_pw);
+                    } else {
+                        info!("Hashing plain-text password for user id {}", itm.id);
+                        let salt = get_new_salt();
+                        let hash = get_password_hash(&pw, &salt);
+                        itm.set_str("password", &hash);
+                    }
+                }
+            }
+
+            match old_itm {
+                None => {
+                    diff.created.push(itm.id);
+                    batch.push(itm);
+                }
+                Some(o) => {
+                    let mut new_itm = itm.clone();
+                    if strategy == MergeStrategy::Merge {
+                        new_itm = o.clone();
+                        new_itm.merge(&itm);
+                    }
+                    let fields = changed_fields(o, &new_itm);
+                    if fields.is_empty() {
+                        diff.identical.push(itm.id);
+                    } else if strategy == MergeStrategy::SkipExisting {
+                        diff.skipped.push(itm.id);
+                    } else {
+                        diff.changed.push((itm.id, fields));
+                        batch.push(new_itm);
+                    }
                 }
             }
-            st2.set_item(&collection, &itm, false).await;
         }
+
+        diff.created.sort();
+        diff.changed.sort();
+        diff.identical.sort();
+        diff.skipped.sort();
+        batch.sort_by_key(|itm| itm.id);
+        info!(
+            "Collection {}: {} created, {} changed, {} identical, {} skipped",
+            collection,
+            diff.created.len(),
+            diff.changed.len(),
+            diff.identical.len(),
+            diff.skipped.len()
+        );
+        diffs.push(diff);
+        batches.push(batch);
+    }
+
+    if dry_run {
+        info!("Dry run, nothing is written");
+        return Ok(diffs);
+    }
+
+    if strategy == MergeStrategy::FailOnConflict {
+        for diff in &diffs {
+            if !diff.changed.is_empty() {
+                error!(
+                    "Collection {} has {} conflicting items",
+                    diff.collection,
+                    diff.changed.len()
+                );
+                return Err(StoreError::Conflict(format!(
+                    "{} items of {} differ",
+                    diff.changed.len(),
+                    diff.collection
+                )));
+            }
+        }
+    }
+
+    // Write every collection at once
+    for (diff, batch) in diffs.iter().zip(batches.iter()) {
+        if batch.is_empty() {
+            continue;
+        }
+        if let Err(e) = st2.set_items(&diff.collection, batch, false).await {
+            error!("Failed to merge {}: {}", &diff.collection, e);
+            return Err(e);
+        }
+        info!("Merged {} items of {}", batch.len(), &diff.collection);
+    }
+    return Ok(diffs);
+}
+
+/// Print merge difference in human-readable form
+pub fn print_merge_diff(diffs: &[MergeDiff]) {
+    for diff in diffs {
+        println!(
+            "{}: {} created, {} changed, {} identical, {} skipped",
+            diff.collection,
+            diff.created.len(),
+            diff.changed.len(),
+            diff.identical.len(),
+            diff.skipped.len()
+        );
+        for id in &diff.created {
+            println!("  + {}", id);
+        }
+        for (id, fields) in &diff.changed {
+            println!("  ~ {}: {}", id, fields.join(", "));
+        }
+        for id in &diff.skipped {
+            println!("  = {} (skipped)", id);
+        }
+    }
+}
+
+/// Write the whole store into an empty data folder using the layout that
+/// StoreLocal reads, so that the snapshot can be run with `file://`.
+/// Settings, internals, history, trash and applied migrations are copied
+/// along with collections.
+pub async fn merge_to_local(st: &mut dyn Store, path: &str) -> StoreResult<()> {
+    let path = path.strip_prefix("file://").unwrap_or(path);
+    if let Ok(mut entries) = std::fs::read_dir(path) {
+        if entries.next().is_some() {
+            return Err(StoreError::Io(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{} is not empty", path),
+            )));
+        }
+    }
+
+    // Collection folders must exist before the store is connected
+    std::fs::create_dir_all(path.to_string() + "/collection")?;
+    let collections = st.get_collections().await;
+    for collection in &collections {
+        std::fs::create_dir_all(path.to_string() + "/collection/" + collection)?;
+    }
+
+    let mut local = StoreLocal::new();
+    local.path = path.to_string();
+    local.set_internals(st.get_internals().await).await?;
+    local.set_settings(st.get_settings().await).await?;
+    local.connect(path, "").await?;
+
+    merge_database(st, &mut local, MergeStrategy::Overwrite, false).await?;
+    for collection in &collections {
+        for entry in st.get_collection_history(collection).await? {
+            local.add_history(&entry).await?;
+        }
+        for entry in st.get_trash(collection).await? {
+            local.add_trash(&entry).await?;
+        }
+    }
+
+    // Snapshot must not run migrations applied to the data once again
+    for entry in st.get_migrations().await? {
+        local.add_migration(&entry).await?;
+    }
+    info!(
+        "Copied {} collections to local folder {}",
+        collections.len(),
+        path
+    );
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::store_sqlite::StoreSqlite;
+
+    fn item(id: u64, strs: &[(&str, &str)]) -> Item {
+        let mut itm = Item::new();
+        itm.id = id;
+        for (k, v) in strs {
+            itm.set_str(k, v);
+        }
+        return itm;
+    }
+
+    async fn store_with(items: &[Item]) -> StoreSqlite {
+        let mut st = StoreSqlite::new();
+        st.connect("sqlite://:memory:", "").await.unwrap();
+        st.collections.insert("foo".to_string(), 0);
+        for itm in items {
+            st.set_item("foo", itm, false).await.unwrap();
+        }
+        return st;
+    }
+
+    /// Source has one identical, one differing and one new item
+    async fn stores() -> (StoreSqlite, StoreSqlite) {
+        let src = store_with(&[
+            item(1, &[("name", "a")]),
+            item(2, &[("name", "b")]),
+            item(3, &[("name", "c")]),
+        ])
+        .await;
+        let dst = store_with(&[
+            item(1, &[("name", "a")]),
+            item(2, &[("name", "x"), ("extra", "y")]),
+        ])
+        .await;
+        return (src, dst);
+    }
+
+    async fn name_of(st: &mut StoreSqlite, id: u64) -> Option<(String, String)> {
+        let itm = st.get_item("foo", id).await.unwrap()?;
+        return Some((itm.safe_str("name", ""), itm.safe_str("extra", "")));
+    }
+
+    #[actix_rt::test]
+    async fn dry_run_reports_without_writing() {
+        let (mut src, mut dst) = stores().await;
+        let diffs = merge_database(&mut src, &mut dst, MergeStrategy::Overwrite, true)
+            .await
+            .unwrap();
+        assert_eq!(diffs.len(), 1);
+        assert_eq!(diffs[0].created, vec![3]);
+        assert_eq!(
+            diffs[0].changed,
+            vec![(2, vec!["strs.extra".to_string(), "strs.name".to_string()])]
+        );
+        assert_eq!(diffs[0].identical, vec![1]);
+        assert!(diffs[0].skipped.is_empty());
+        assert_eq!(name_of(&mut dst, 3).await, None);
+        assert_eq!(
+            name_of(&mut dst, 2).await,
+            Some(("x".to_string(), "y".to_string()))
+        );
+    }
+
+    #[actix_rt::test]
+    async fn strategies() {
+        let (mut src, mut dst) = stores().await;
+        let res = merge_database(&mut src, &mut dst, MergeStrategy::FailOnConflict, false).await;
+        assert!(matches!(res, Err(StoreError::Conflict(_))));
+        assert_eq!(name_of(&mut dst, 3).await, None);
+
+        let diffs = merge_database(&mut src, &mut dst, MergeStrategy::SkipExisting, false)
+            .await
+            .unwrap();
+        assert_eq!(diffs[0].skipped, vec![2]);
+        assert!(diffs[0].changed.is_empty());
+        assert_eq!(
+            name_of(&mut dst, 2).await,
+            Some(("x".to_string(), "y".to_string()))
+        );
+        assert_eq!(
+            name_of(&mut dst, 3).await,
+            Some(("c".to_string(), "".to_string()))
+        );
+
+        let (mut src, mut dst) = stores().await;
+        let diffs = merge_database(&mut src, &mut dst, MergeStrategy::Merge, false)
+            .await
+            .unwrap();
+        assert_eq!(diffs[0].changed, vec![(2, vec!["strs.name".to_string()])]);
+        assert_eq!(
+            name_of(&mut dst, 2).await,
+            Some(("b".to_string(), "y".to_string()))
+        );
+
+        let (mut src, mut dst) = stores().await;
+        merge_database(&mut src, &mut dst, MergeStrategy::Overwrite, false)
+            .await
+            .unwrap();
+        assert_eq!(
+            name_of(&mut dst, 2).await,
+            Some(("b".to_string(), "".to_string()))
+        );
+        assert_eq!(
+            name_of(&mut dst, 1).await,
+            Some(("a".to_string(), "".to_string()))
+        );
     }
 }
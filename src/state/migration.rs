@@ -0,0 +1,326 @@
+/*
+ * Isabelle project
+ *
+ * Copyright 2023-2025 Maxim Menshikov
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining
+ * a copy of this software and associated documentation files (the “Software”),
+ * to deal in the Software without restriction, including without limitation
+ * the rights to use, copy, modify, merge, publish, distribute, sublicense,
+ * and/or sell copies of the Software, and to permit persons to whom the
+ * Software is furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included
+ * in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS
+ * OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+ * DEALINGS IN THE SOFTWARE.
+ */
+use crate::state::filter::Filter;
+use crate::state::store::*;
+use isabelle_dm::data_model::item::Item;
+use log::{error, info};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+
+/// Lowest version of migrations declared in internals. Versions below are
+/// reserved for built-in migrations.
+pub const DECLARED_MIGRATION_BASE: u64 = 1000;
+
+/// Number of items written to the store at once
+const MIGRATION_BATCH: usize = 1000;
+
+/// Future returned by code migration
+pub type MigrationFuture<'a> = Pin<Box<dyn Future<Output = StoreResult<()>> + Send + 'a>>;
+
+/// Change done by migration
+pub enum MigrationKind {
+    /// Arbitrary code working with the store, for built-in migrations.
+    /// None is shipped yet.
+    #[allow(dead_code)]
+    Code(for<'a> fn(&'a mut dyn Store) -> MigrationFuture<'a>),
+
+    /// Rename field (like `strs.name`) of all items in the collection
+    Rename {
+        collection: String,
+        from: String,
+        to: String,
+    },
+
+    /// Set field of all items in the collection that don't have it
+    Default {
+        collection: String,
+        field: String,
+        value: String,
+    },
+}
+
+/// Named migration. Every migration runs once, in the order of versions.
+/// Interrupted migration runs again on the next start, so it must be
+/// idempotent.
+pub struct Migration {
+    /// Version of the migration
+    pub version: u64,
+
+    /// Name of the migration
+    pub name: String,
+
+    /// Change done by migration
+    pub kind: MigrationKind,
+}
+
+/// Built-in migrations. New ones go to the end with the next version below
+/// `DECLARED_MIGRATION_BASE`, usually as `MigrationKind::Code` like
+/// `|st| Box::pin(some_migration(st))`.
+pub fn builtin_migrations() -> Vec<Migration> {
+    return Vec::new();
+}
+
+/// Write changed items in batches
+async fn write_items(st: &mut dyn Store, collection: &str, items: &[Item]) -> StoreResult<()> {
+    for batch in items.chunks(MIGRATION_BATCH) {
+        st.set_items(collection, batch, false).await?;
+    }
+    return Ok(());
+}
+
+/// Move the value between fields of the item. Returns true if the item
+/// was changed.
+fn rename_field(itm: &mut Item, from: &str, to: &str) -> bool {
+    let (kind, from_name) = from.split_once('.').unwrap_or(("", from));
+    let to_name = to.split_once('.').map(|p| p.1).unwrap_or(to);
+    match kind {
+        "strs" => {
+            if let Some(v) = itm.strs.remove(from_name) {
+                itm.strs.insert(to_name.to_string(), v);
+                return true;
+            }
+        }
+        "bools" => {
+            if let Some(v) = itm.bools.remove(from_name) {
+                itm.bools.insert(to_name.to_string(), v);
+                return true;
+            }
+        }
+        "u64s" => {
+            if let Some(v) = itm.u64s.remove(from_name) {
+                itm.u64s.insert(to_name.to_string(), v);
+                return true;
+            }
+        }
+        "strstrs" => {
+            if let Some(v) = itm.strstrs.remove(from_name) {
+                itm.strstrs.insert(to_name.to_string(), v);
+                return true;
+            }
+        }
+        _ => {}
+    }
+    return false;
+}
+
+/// Set the field if the item doesn't have it. Returns true if the item
+/// was changed.
+fn default_field(itm: &mut Item, field: &str, value: &str) -> bool {
+    let (kind, name) = field.split_once('.').unwrap_or(("", field));
+    match kind {
+        "strs" => {
+            if !itm.strs.contains_key(name) {
+                itm.set_str(name, value);
+                return true;
+            }
+        }
+        "bools" => {
+            if !itm.bools.contains_key(name) {
+                itm.set_bool(name, value == "true");
+                return true;
+            }
+        }
+        "u64s" => {
+            if !itm.u64s.contains_key(name) {
+                itm.set_u64(name, value.parse::<u64>().unwrap_or(0));
+                return true;
+            }
+        }
+        _ => {}
+    }
+    return false;
+}
+
+/// Check field reference like `strs.name`
+fn check_field(field: &str, kinds: &[&str]) -> bool {
+    match field.split_once('.') {
+        Some((kind, name)) => {
+            return kinds.contains(&kind) && !name.is_empty();
+        }
+        None => {
+            return false;
+        }
+    }
+}
+
+/// Parse migrations declared in internals `migrations` string map.
+/// Every value is either `<version>:<name>:rename:<collection>:<from>:<to>`
+/// or `<version>:<name>:default:<collection>:<field>:<value>`.
+pub fn declared_migrations(internals: &Item) -> StoreResult<Vec<Migration>> {
+    let mut migrations: Vec<Migration> = Vec::new();
+    let declared = internals.safe_strstr("migrations", &HashMap::new());
+    for decl in declared.values() {
+        let parts: Vec<&str> = decl.splitn(6, ':').collect();
+        let bad = |reason: &str| {
+            return StoreError::Serialization(format!("migration '{}': {}", decl, reason));
+        };
+        if parts.len() != 6 {
+            return Err(bad("expected 6 parts"));
+        }
+        let version = match parts[0].parse::<u64>() {
+            Ok(v) => v,
+            Err(_) => {
+                return Err(bad("invalid version"));
+            }
+        };
+        if version < DECLARED_MIGRATION_BASE {
+            return Err(bad("version is reserved for built-in migrations"));
+        }
+
+        let collection = parts[3].to_string();
+        let kind = match parts[2] {
+            "rename" => {
+                let all = ["strs", "bools", "u64s", "strstrs"];
+                let same_kind =
+                    parts[4].split_once('.').map(|p| p.0) == parts[5].split_once('.').map(|p| p.0);
+                if !check_field(parts[4], &all) || !check_field(parts[5], &all) || !same_kind {
+                    return Err(bad("fields must be of the same kind"));
+                }
+                MigrationKind::Rename {
+                    collection,
+                    from: parts[4].to_string(),
+                    to: parts[5].to_string(),
+                }
+            }
+            "default" => {
+                if !check_field(parts[4], &["strs", "bools", "u64s"]) {
+                    return Err(bad("field must be strs, bools or u64s"));
+                }
+                if parts[4].starts_with("u64s.") && parts[5].parse::<u64>().is_err() {
+                    return Err(bad("invalid number"));
+                }
+                MigrationKind::Default {
+                    collection,
+                    field: parts[4].to_string(),
+                    value: parts[5].to_string(),
+                }
+            }
+            _ => {
+                return Err(bad("unknown kind"));
+            }
+        };
+        migrations.push(Migration {
+            version,
+            name: parts[1].to_string(),
+            kind,
+        });
+    }
+    return Ok(migrations);
+}
+
+/// Apply single migration
+async fn apply_migration(st: &mut dyn Store, migration: &Migration) -> StoreResult<()> {
+    match &migration.kind {
+        MigrationKind::Code(f) => {
+            return f(st).await;
+        }
+        MigrationKind::Rename {
+            collection,
+            from,
+            to,
+        } => {
+            let items = st.get_all_items(collection, "id", &Filter::all()).await?;
+            let mut changed: Vec<Item> = Vec::new();
+            for mut itm in items.map.into_values() {
+                if rename_field(&mut itm, from, to) {
+                    changed.push(itm);
+                }
+            }
+            info!("Renaming {} to {} in {} items", from, to, changed.len());
+            return write_items(st, collection, &changed).await;
+        }
+        MigrationKind::Default {
+            collection,
+            field,
+            value,
+        } => {
+            let items = st.get_all_items(collection, "id", &Filter::all()).await?;
+            let mut changed: Vec<Item> = Vec::new();
+            for mut itm in items.map.into_values() {
+                if default_field(&mut itm, field, value) {
+                    changed.push(itm);
+                }
+            }
+            info!("Setting default {} in {} items", field, changed.len());
+            return write_items(st, collection, &changed).await;
+        }
+    }
+}
+
+/// Run built-in and declared migrations that are not recorded in the
+/// database yet, in the order of versions. Stops on the first error, so
+/// that later migrations never see partially migrated data. Returns the
+/// number of applied migrations.
+pub async fn run_migrations(st: &mut dyn Store) -> StoreResult<u64> {
+    let internals = st.get_internals().await;
+    let mut migrations = builtin_migrations();
+    migrations.extend(declared_migrations(&internals)?);
+    migrations.sort_by_key(|m| m.version);
+    for pair in migrations.windows(2) {
+        if pair[0].version == pair[1].version {
+            return Err(StoreError::Serialization(format!(
+                "migrations {} and {} have the same version {}",
+                pair[0].name, pair[1].name, pair[0].version
+            )));
+        }
+    }
+
+    let applied: HashMap<u64, String> = st
+        .get_migrations()
+        .await?
+        .into_iter()
+        .map(|e| (e.version, e.name))
+        .collect();
+
+    let mut count = 0;
+    for migration in &migrations {
+        if let Some(name) = applied.get(&migration.version) {
+            if *name != migration.name {
+                error!(
+                    "Migration {} was applied as {}, now named {}",
+                    migration.version, name, migration.name
+                );
+            }
+            continue;
+        }
+
+        info!("Migration {}: {}", migration.version, migration.name);
+        if let Err(e) = apply_migration(st, migration).await {
+            error!(
+                "Migration {} ({}) failed: {}",
+                migration.version, migration.name, e
+            );
+            return Err(e);
+        }
+        st.add_migration(&MigrationEntry {
+            version: migration.version,
+            name: migration.name.clone(),
+            time: chrono::Utc::now().timestamp() as u64,
+        })
+        .await?;
+        count += 1;
+    }
+    return Ok(count);
+}
@@ -21,9 +21,15 @@
  * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
  * DEALINGS IN THE SOFTWARE.
  */
+pub mod archive;
 pub mod data;
+pub mod filter;
 pub mod merger;
+pub mod migration;
+pub mod schema;
+pub mod search;
 pub mod state;
 pub mod store;
 pub mod store_local;
 pub mod store_mongo;
+pub mod store_sqlite;
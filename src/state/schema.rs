@@ -0,0 +1,348 @@
+/*
+ * Isabelle project
+ *
+ * Copyright 2023-2025 Maxim Menshikov
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining
+ * a copy of this software and associated documentation files (the “Software”),
+ * to deal in the Software without restriction, including without limitation
+ * the rights to use, copy, modify, merge, publish, distribute, sublicense,
+ * and/or sell copies of the Software, and to permit persons to whom the
+ * Software is furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included
+ * in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS
+ * OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+ * DEALINGS IN THE SOFTWARE.
+ */
+use isabelle_dm::data_model::item::Item;
+use regex::Regex;
+use std::collections::HashMap;
+
+/// Field kinds that can be described by schema
+const SCHEMA_KINDS: [&str; 3] = ["strs", "bools", "u64s"];
+
+/// Single schema rule of the collection field
+#[derive(Clone, Debug)]
+pub struct SchemaRule {
+    /// Kind of the field: strs, bools or u64s
+    pub kind: String,
+
+    /// Field name
+    pub field: String,
+
+    /// Rule: required, regex, range or enum
+    pub rule: String,
+
+    /// Rule argument
+    pub arg: String,
+}
+
+impl SchemaRule {
+    /// Full field name like `strs.name`
+    pub fn path(&self) -> String {
+        return self.kind.clone() + "." + &self.field;
+    }
+}
+
+/// Read schema of the collection from internals `schema` string map.
+/// Every value is `<collection>:<kind>.<field>:<rule>[:<argument>]`, where
+/// rule is one of:
+///  - `required` - field must be present;
+///  - `regex:<expression>` - string must match the expression;
+///  - `range:<min>:<max>` - number (or string length) must be within bounds;
+///  - `enum:<a>,<b>,...` - value must be one of listed.
+/// Any field listed in schema must live in the given kind only. Malformed
+/// rules are skipped, `check_schema` reports them.
+pub fn collection_schema(internals: &Item, collection: &str) -> Vec<SchemaRule> {
+    let mut rules: Vec<SchemaRule> = Vec::new();
+    for (_decl, res) in declared_rules(internals) {
+        match res {
+            Ok(rule) if rule.collection == collection => rules.push(rule.rule),
+            _ => {}
+        }
+    }
+    return rules;
+}
+
+/// Find malformed rules in internals `schema`. Returns descriptions of
+/// the problems, one per declaration.
+pub fn check_schema(internals: &Item) -> Vec<String> {
+    let mut errors: Vec<String> = Vec::new();
+    for (decl, res) in declared_rules(internals) {
+        if let Err(e) = res {
+            errors.push(format!("{}: {}", decl, e));
+        }
+    }
+    return errors;
+}
+
+/// Rule declaration along with its collection
+struct DeclaredRule {
+    collection: String,
+    rule: SchemaRule,
+}
+
+/// Parse all rules declared in internals, in order of their keys
+fn declared_rules(internals: &Item) -> Vec<(String, Result<DeclaredRule, String>)> {
+    let declared = internals.safe_strstr("schema", &HashMap::new());
+    let mut keys: Vec<&String> = declared.keys().collect();
+    keys.sort();
+    return keys
+        .into_iter()
+        .map(|key| (declared[key].clone(), parse_rule(&declared[key])))
+        .collect();
+}
+
+/// Parse and check single rule declaration
+fn parse_rule(decl: &str) -> Result<DeclaredRule, String> {
+    let parts: Vec<&str> = decl.splitn(4, ':').collect();
+    if parts.len() < 3 || parts[0].is_empty() {
+        return Err("expected <collection>:<kind>.<field>:<rule>".to_string());
+    }
+    let (kind, field) = parts[1].split_once('.').unwrap_or(("", parts[1]));
+    if !SCHEMA_KINDS.contains(&kind) || field.is_empty() {
+        return Err(format!("invalid field {}", parts[1]));
+    }
+    let rule = SchemaRule {
+        kind: kind.to_string(),
+        field: field.to_string(),
+        rule: parts[2].to_string(),
+        arg: parts.get(3).unwrap_or(&"").to_string(),
+    };
+    match rule.rule.as_str() {
+        "required" | "enum" => {}
+        "regex" => {
+            if let Err(e) = Regex::new(&rule.arg) {
+                return Err(format!("invalid expression: {}", e));
+            }
+        }
+        "range" => {
+            if check_range(0, &rule.arg).is_err() {
+                return Err(format!("invalid range {}", rule.arg));
+            }
+        }
+        r => {
+            return Err(format!("unknown rule {}", r));
+        }
+    }
+    return Ok(DeclaredRule {
+        collection: parts[0].to_string(),
+        rule,
+    });
+}
+
+/// Check the value against range rule
+fn check_range(value: u64, arg: &str) -> Result<bool, ()> {
+    let (min, max) = arg.split_once(':').ok_or(())?;
+    let min = if min.is_empty() {
+        0
+    } else {
+        min.parse::<u64>().map_err(|_| ())?
+    };
+    let max = if max.is_empty() {
+        u64::MAX
+    } else {
+        max.parse::<u64>().map_err(|_| ())?
+    };
+    return Ok(value >= min && value <= max);
+}
+
+/// Check single rule. Returns description of the violation, if any.
+/// Malformed rules never fail, since they are reported on startup.
+fn check_rule(rule: &SchemaRule, itm: &Item) -> Option<String> {
+    // Field must not be stored in another kind
+    let kinds = [
+        ("strs", itm.strs.contains_key(&rule.field)),
+        ("bools", itm.bools.contains_key(&rule.field)),
+        ("u64s", itm.u64s.contains_key(&rule.field)),
+    ];
+    for (kind, present) in kinds {
+        if present && kind != rule.kind {
+            return Some(format!("must be in {}", rule.kind));
+        }
+    }
+
+    let present = kinds.iter().any(|k| k.1);
+    let str_val = itm.strs.get(&rule.field);
+    let u64_val = itm.u64s.get(&rule.field);
+    let bool_val = itm.bools.get(&rule.field);
+    match rule.rule.as_str() {
+        "required" => {
+            if !present {
+                return Some("is required".to_string());
+            }
+        }
+        "regex" => {
+            let re = match Regex::new(&rule.arg) {
+                Ok(re) => re,
+                Err(_) => {
+                    return None;
+                }
+            };
+            if let Some(v) = str_val {
+                if !re.is_match(v) {
+                    return Some(format!("must match {}", rule.arg));
+                }
+            }
+        }
+        "range" => {
+            let value = match (str_val, u64_val) {
+                (Some(v), _) => v.chars().count() as u64,
+                (_, Some(v)) => *v,
+                _ => {
+                    return None;
+                }
+            };
+            match check_range(value, &rule.arg) {
+                Ok(true) => {}
+                Ok(false) => {
+                    return Some(format!("must be within {}", rule.arg.replace(':', "..")));
+                }
+                Err(()) => {}
+            }
+        }
+        "enum" => {
+            let value = match (str_val, u64_val, bool_val) {
+                (Some(v), _, _) => v.to_string(),
+                (_, Some(v), _) => v.to_string(),
+                (_, _, Some(v)) => v.to_string(),
+                _ => {
+                    return None;
+                }
+            };
+            if !rule.arg.split(',').any(|v| v == value) {
+                return Some(format!("must be one of {}", rule.arg));
+            }
+        }
+        _ => {}
+    }
+    return None;
+}
+
+/// Validate the item against collection schema. Returns violations by
+/// field, several violations of the same field are joined.
+pub fn validate_item(rules: &[SchemaRule], itm: &Item) -> HashMap<String, String> {
+    let mut violations: HashMap<String, String> = HashMap::new();
+    for rule in rules {
+        if let Some(v) = check_rule(rule, itm) {
+            violations
+                .entry(rule.path())
+                .and_modify(|e| {
+                    if !e.split("; ").any(|x| x == v) {
+                        e.push_str("; ");
+                        e.push_str(&v);
+                    }
+                })
+                .or_insert(v);
+        }
+    }
+    return violations;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn internals(rules: &[&str]) -> Item {
+        let mut itm = Item::new();
+        let mut decl = HashMap::new();
+        for (i, rule) in rules.iter().enumerate() {
+            decl.insert(format!("{:02}", i), rule.to_string());
+        }
+        itm.strstrs.insert("schema".to_string(), decl);
+        return itm;
+    }
+
+    #[test]
+    fn parse_rules() {
+        let int = internals(&[
+            "job:strs.name:required",
+            "job:strs.code:regex:^[a-z]+:[0-9]+$",
+            "job:u64s.prio:range:1:",
+            "user:strs.login:required",
+        ]);
+        let rules = collection_schema(&int, "job");
+        assert_eq!(rules.len(), 3);
+        assert_eq!(rules[0].path(), "strs.name");
+        assert_eq!(rules[0].rule, "required");
+        assert_eq!(rules[1].rule, "regex");
+        assert_eq!(rules[1].arg, "^[a-z]+:[0-9]+$");
+        assert_eq!(rules[2].path(), "u64s.prio");
+        assert_eq!(rules[2].arg, "1:");
+        assert!(collection_schema(&int, "other").is_empty());
+        assert!(check_schema(&int).is_empty());
+    }
+
+    #[test]
+    fn violations() {
+        let int = internals(&[
+            "job:strs.name:required",
+            "job:strs.name:range:2:4",
+            "job:strs.state:enum:new,done",
+            "job:strs.state:regex:^n",
+            "job:u64s.prio:range::5",
+            "job:bools.urgent:required",
+        ]);
+        let rules = collection_schema(&int, "job");
+
+        let mut itm = Item::new();
+        itm.set_str("name", "abc");
+        itm.set_str("state", "new");
+        itm.set_u64("prio", 5);
+        itm.set_bool("urgent", false);
+        assert!(validate_item(&rules, &itm).is_empty());
+
+        let mut itm = Item::new();
+        itm.set_str("name", "abcde");
+        itm.set_str("state", "old");
+        itm.set_u64("prio", 6);
+        itm.set_str("urgent", "yes");
+        let v = validate_item(&rules, &itm);
+        assert_eq!(v.len(), 4);
+        assert_eq!(v["strs.name"], "must be within 2..4");
+        assert_eq!(v["strs.state"], "must be one of new,done; must match ^n");
+        assert_eq!(v["u64s.prio"], "must be within ..5");
+        assert_eq!(v["bools.urgent"], "must be in bools");
+
+        let v = validate_item(&rules, &Item::new());
+        assert_eq!(v.len(), 2);
+        assert_eq!(v["strs.name"], "is required");
+    }
+
+    #[test]
+    fn malformed_rules() {
+        let int = internals(&[
+            "job:strs.name:required",
+            "job:strs.code:regex:(",
+            "job:u64s.prio:range:a:5",
+            "job:u64s.prio:range:5",
+            "job:strs.name:unique",
+            "job:other.name:required",
+            "job:strs.name",
+        ]);
+        let errors = check_schema(&int);
+        assert_eq!(errors.len(), 6);
+        assert!(errors[0].starts_with("job:strs.code:regex:(: invalid expression"));
+        assert_eq!(errors[1], "job:u64s.prio:range:a:5: invalid range a:5");
+        assert_eq!(errors[3], "job:strs.name:unique: unknown rule unique");
+        assert_eq!(
+            errors[4],
+            "job:other.name:required: invalid field other.name"
+        );
+
+        // Clients only see violations of well-formed rules
+        let rules = collection_schema(&int, "job");
+        assert_eq!(rules.len(), 1);
+        let mut itm = Item::new();
+        itm.set_str("name", "a");
+        itm.set_str("code", "x");
+        assert!(validate_item(&rules, &itm).is_empty());
+    }
+}
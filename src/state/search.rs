@@ -0,0 +1,424 @@
+/*
+ * Isabelle project
+ *
+ * Copyright 2023-2025 Maxim Menshikov
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining
+ * a copy of this software and associated documentation files (the “Software”),
+ * to deal in the Software without restriction, including without limitation
+ * the rights to use, copy, modify, merge, publish, distribute, sublicense,
+ * and/or sell copies of the Software, and to permit persons to whom the
+ * Software is furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included
+ * in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS
+ * OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+ * DEALINGS IN THE SOFTWARE.
+ */
+use crate::state::filter::Filter;
+use crate::state::store::{Store, StoreError};
+use isabelle_dm::data_model::item::Item;
+use log::{error, info};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use tantivy::collector::TopDocs;
+use tantivy::query::{BooleanQuery, Occur, Query, QueryParser, TermQuery};
+use tantivy::schema::{
+    Field, IndexRecordOption, Schema, TantivyDocument, Value, INDEXED, STORED, STRING, TEXT,
+};
+use tantivy::snippet::SnippetGenerator;
+use tantivy::{Index, IndexReader, IndexWriter, ReloadPolicy, Term};
+
+/// Memory budget of the index writer
+const SEARCH_WRITER_HEAP: usize = 20_000_000;
+
+/// Maximum length of highlighted fragments
+const SEARCH_SNIPPET_CHARS: usize = 150;
+
+/// Largest number of hits to skip, as skipped hits are collected too
+pub const SEARCH_MAX_SKIP: u64 = 10_000;
+
+/// Largest number of hits to return at once
+pub const SEARCH_MAX_LIMIT: u64 = 100;
+
+/// Search error
+#[derive(Debug)]
+pub struct SearchError(pub String);
+
+impl fmt::Display for SearchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "search error: {}", self.0)
+    }
+}
+
+impl From<tantivy::TantivyError> for SearchError {
+    fn from(e: tantivy::TantivyError) -> Self {
+        return SearchError(e.to_string());
+    }
+}
+
+impl From<StoreError> for SearchError {
+    fn from(e: StoreError) -> Self {
+        return SearchError(e.to_string());
+    }
+}
+
+/// Collection whose fields are indexed for search
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchDef {
+    /// Collection name
+    pub collection: String,
+
+    /// Indexed `strs` keys
+    pub fields: Vec<String>,
+}
+
+/// Read searchable collections from internals `search` string map. Every
+/// value is `<collection>:<field>[,<field>...]` with `strs` fields, like
+/// `user:strs.name,strs.email`.
+pub fn declared_search(internals: &Item) -> Vec<SearchDef> {
+    let mut defs: Vec<SearchDef> = Vec::new();
+    let declared = internals.safe_strstr("search", &HashMap::new());
+    let mut keys: Vec<&String> = declared.keys().collect();
+    keys.sort();
+    for key in keys {
+        let (collection, list) = declared[key].split_once(':').unwrap_or(("", ""));
+        let mut fields: Vec<String> = Vec::new();
+        for field in list.split(',') {
+            match field.trim().strip_prefix("strs.") {
+                Some(f) if f != "" => fields.push(f.to_string()),
+                _ => {
+                    error!("Only strs fields can be searched: {}", declared[key]);
+                }
+            }
+        }
+        if collection == "" || fields.is_empty() {
+            continue;
+        }
+        match defs.iter_mut().find(|d| d.collection == collection) {
+            Some(d) => d.fields.extend(fields),
+            None => defs.push(SearchDef {
+                collection: collection.to_string(),
+                fields,
+            }),
+        }
+    }
+    return defs;
+}
+
+/// Single search result
+#[derive(Serialize, Debug, Clone)]
+pub struct SearchHit {
+    /// Collection of the item
+    pub collection: String,
+
+    /// Item ID
+    pub id: u64,
+
+    /// Relevance, higher is better
+    pub score: f32,
+
+    /// HTML fragments with matches in `<b>` tags, by field like `strs.name`
+    pub highlights: HashMap<String, String>,
+
+    /// Indexed texts of matching fields, by field like `strs.name`
+    #[serde(skip)]
+    pub texts: HashMap<String, String>,
+}
+
+impl SearchHit {
+    /// Keep highlights only of fields that the item as seen by the user
+    /// has with the indexed text, so that fields hidden or changed by list
+    /// filter hooks don't leak. Returns false if no visible field matches,
+    /// then the hit itself reveals hidden text and must be dropped.
+    pub fn restrict_to(&mut self, itm: &Item) -> bool {
+        let texts = &self.texts;
+        self.highlights.retain(|name, _| {
+            let key = name.strip_prefix("strs.").unwrap_or(name);
+            return itm.strs.get(key).is_some() && itm.strs.get(key) == texts.get(name);
+        });
+        return !self.highlights.is_empty();
+    }
+}
+
+/// Index and its fields
+struct SearchIndex {
+    index: Index,
+    writer: IndexWriter,
+    reader: IndexReader,
+
+    /// Collection of the item
+    collection: Field,
+
+    /// Item ID
+    id: Field,
+
+    /// `<collection>:<id>`, which identifies the document for updates
+    key: Field,
+
+    /// Text fields by `strs` keys
+    text: BTreeMap<String, Field>,
+}
+
+/// Full-text search over `strs` fields of collections declared in
+/// internals. Index lives in memory: it's built on startup and kept
+/// current as items change. Changes are committed in batches, right
+/// before the next search.
+pub struct Search {
+    /// Searchable collections
+    defs: Vec<SearchDef>,
+
+    /// Index, if anything is searchable
+    index: Option<SearchIndex>,
+
+    /// Index has changes which searches don't see yet
+    pending: bool,
+}
+
+impl Search {
+    pub fn new() -> Self {
+        Self {
+            defs: Vec::new(),
+            index: None,
+            pending: false,
+        }
+    }
+
+    /// Check if items of the collection are indexed
+    pub fn has_collection(&self, collection: &str) -> bool {
+        return self.defs.iter().any(|d| d.collection == collection);
+    }
+
+    /// Index all items of collections declared in internals. Returns the
+    /// number of indexed items.
+    pub async fn rebuild(&mut self, st: &mut dyn Store) -> Result<u64, SearchError> {
+        self.defs = declared_search(&st.get_internals().await);
+        self.index = None;
+        self.pending = false;
+        if self.defs.is_empty() {
+            return Ok(0);
+        }
+
+        let mut builder = Schema::builder();
+        let collection = builder.add_text_field("collection", STRING | STORED);
+        let id = builder.add_u64_field("id", INDEXED | STORED);
+        let key = builder.add_text_field("key", STRING);
+        let mut text: BTreeMap<String, Field> = BTreeMap::new();
+        for def in &self.defs {
+            for field in &def.fields {
+                if !text.contains_key(field) {
+                    let f = builder.add_text_field(&format!("f{}", text.len()), TEXT | STORED);
+                    text.insert(field.clone(), f);
+                }
+            }
+        }
+        let index = Index::create_in_ram(builder.build());
+        let writer = index.writer_with_num_threads(1, SEARCH_WRITER_HEAP)?;
+        let reader = index
+            .reader_builder()
+            .reload_policy(ReloadPolicy::Manual)
+            .try_into()?;
+        let mut si = SearchIndex {
+            index,
+            writer,
+            reader,
+            collection,
+            id,
+            key,
+            text,
+        };
+
+        let mut count: u64 = 0;
+        for def in &self.defs {
+            if !st.has_collection(&def.collection) {
+                error!("Search: unknown collection {}", def.collection);
+                continue;
+            }
+            let items = st
+                .get_all_items(&def.collection, "id", &Filter::all())
+                .await?;
+            for itm in items.map.values() {
+                si.add(def, itm)?;
+                count += 1;
+            }
+        }
+        si.commit()?;
+        info!("Search: indexed {} items", count);
+        self.index = Some(si);
+        return Ok(count);
+    }
+
+    /// Index the new version of the item
+    pub fn update(&mut self, collection: &str, itm: &Item) {
+        let def = match self.defs.iter().find(|d| d.collection == collection) {
+            Some(d) => d,
+            None => {
+                return;
+            }
+        };
+        if let Some(si) = self.index.as_mut() {
+            si.remove(collection, itm.id);
+            self.pending = true;
+            if let Err(e) = si.add(def, itm) {
+                error!(
+                    "Search: failed to index {} item {}: {}",
+                    collection, itm.id, e
+                );
+            }
+        }
+    }
+
+    /// Drop the item from the index
+    pub fn remove(&mut self, collection: &str, id: u64) {
+        if !self.has_collection(collection) {
+            return;
+        }
+        if let Some(si) = self.index.as_mut() {
+            si.remove(collection, id);
+            self.pending = true;
+        }
+    }
+
+    /// Make pending changes visible to searches
+    pub fn flush(&mut self) -> Result<(), SearchError> {
+        if !self.pending {
+            return Ok(());
+        }
+        if let Some(si) = self.index.as_mut() {
+            si.commit()?;
+        }
+        self.pending = false;
+        return Ok(());
+    }
+
+    /// Find items matching the query, best first. Query uses tantivy
+    /// syntax, parts it can't parse are ignored. Empty collection means
+    /// all searchable collections. Fails if `skip` is above
+    /// SEARCH_MAX_SKIP, `limit` is capped by SEARCH_MAX_LIMIT. Pending
+    /// changes are committed first.
+    pub fn search(
+        &mut self,
+        query: &str,
+        collection: &str,
+        skip: u64,
+        limit: u64,
+    ) -> Result<Vec<SearchHit>, SearchError> {
+        if skip > SEARCH_MAX_SKIP {
+            return Err(SearchError(format!(
+                "can't skip more than {} hits",
+                SEARCH_MAX_SKIP
+            )));
+        }
+        self.flush()?;
+
+        let si = match self.index.as_ref() {
+            Some(si) => si,
+            None => {
+                return Ok(Vec::new());
+            }
+        };
+
+        let fields: Vec<Field> = si.text.values().cloned().collect();
+        let parser = QueryParser::for_index(&si.index, fields);
+        let (text_query, _errors) = parser.parse_query_lenient(query);
+
+        // Queries without terms match everything, which isn't a search
+        let mut has_terms = false;
+        text_query.query_terms(&mut |_, _| has_terms = true);
+        if !has_terms {
+            return Ok(Vec::new());
+        }
+
+        let full_query: Box<dyn Query> = if collection != "" {
+            let term = Term::from_field_text(si.collection, collection);
+            Box::new(BooleanQuery::new(vec![
+                (Occur::Must, text_query.box_clone()),
+                (
+                    Occur::Must,
+                    Box::new(TermQuery::new(term, IndexRecordOption::Basic)),
+                ),
+            ]))
+        } else {
+            text_query.box_clone()
+        };
+
+        let searcher = si.reader.searcher();
+        let top = TopDocs::with_limit(limit.clamp(1, SEARCH_MAX_LIMIT) as usize)
+            .and_offset(skip as usize);
+        let found = searcher.search(&*full_query, &top)?;
+
+        let mut generators: Vec<(String, Field, SnippetGenerator)> = Vec::new();
+        for (name, field) in &si.text {
+            let mut gen = SnippetGenerator::create(&searcher, &*text_query, *field)?;
+            gen.set_max_num_chars(SEARCH_SNIPPET_CHARS);
+            generators.push(("strs.".to_owned() + name, *field, gen));
+        }
+
+        let mut hits: Vec<SearchHit> = Vec::new();
+        for (score, addr) in found {
+            let doc: TantivyDocument = searcher.doc(addr)?;
+            let mut highlights: HashMap<String, String> = HashMap::new();
+            let mut texts: HashMap<String, String> = HashMap::new();
+            for (name, field, gen) in &generators {
+                let snippet = gen.snippet_from_doc(&doc);
+                if !snippet.highlighted().is_empty() {
+                    highlights.insert(name.clone(), snippet.to_html());
+                    let text = doc.get_first(*field).and_then(|v| v.as_str());
+                    texts.insert(name.clone(), text.unwrap_or_default().to_string());
+                }
+            }
+            hits.push(SearchHit {
+                collection: doc
+                    .get_first(si.collection)
+                    .and_then(|v| v.as_str())
+                    .unwrap_or_default()
+                    .to_string(),
+                id: doc
+                    .get_first(si.id)
+                    .and_then(|v| v.as_u64())
+                    .unwrap_or(u64::MAX),
+                score,
+                highlights,
+                texts,
+            });
+        }
+        return Ok(hits);
+    }
+}
+
+impl SearchIndex {
+    /// Add the document of the item
+    fn add(&mut self, def: &SearchDef, itm: &Item) -> Result<(), SearchError> {
+        let mut doc = TantivyDocument::default();
+        doc.add_text(self.collection, &def.collection);
+        doc.add_u64(self.id, itm.id);
+        doc.add_text(self.key, format!("{}:{}", def.collection, itm.id));
+        for field in &def.fields {
+            if let (Some(f), Some(val)) = (self.text.get(field), itm.strs.get(field)) {
+                doc.add_text(*f, val);
+            }
+        }
+        self.writer.add_document(doc)?;
+        return Ok(());
+    }
+
+    /// Remove the document of the item
+    fn remove(&mut self, collection: &str, id: u64) {
+        let term = Term::from_field_text(self.key, &format!("{}:{}", collection, id));
+        self.writer.delete_term(term);
+    }
+
+    /// Make changes visible to searches
+    fn commit(&mut self) -> Result<(), SearchError> {
+        self.writer.commit()?;
+        self.reader.reload()?;
+        return Ok(());
+    }
+}
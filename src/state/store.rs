@@ -21,33 +21,546 @@
  * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
  * DEALINGS IN THE SOFTWARE.
  */
+use crate::state::filter::{aggregate_items, lookup_path, Filter};
+use crate::state::store_local::StoreLocal;
+use crate::state::store_mongo::StoreMongo;
+use crate::state::store_sqlite::StoreSqlite;
 use async_trait::async_trait;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
 use isabelle_dm::data_model::item::Item;
 use isabelle_dm::data_model::list_result::ListResult;
-use std::collections::HashMap;
+use log::error;
+use mongodb::error::{ErrorKind, WriteFailure};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// Storage error
+#[derive(Debug)]
+pub enum StoreError {
+    /// Store isn't connected to the database
+    NotConnected,
+    /// Collection isn't known to the store
+    UnknownCollection(String),
+    /// File system error
+    Io(std::io::Error),
+    /// Stored data can't be encoded or decoded
+    Serialization(String),
+    /// Error reported by the database itself
+    Backend(String),
+    /// Transaction can't be started, committed or aborted
+    Transaction(String),
+    /// Stored item was changed by someone else
+    Conflict(String),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::NotConnected => write!(f, "storage is not connected"),
+            StoreError::UnknownCollection(c) => write!(f, "unknown collection {}", c),
+            StoreError::Io(e) => write!(f, "I/O error: {}", e),
+            StoreError::Serialization(e) => write!(f, "serialization error: {}", e),
+            StoreError::Backend(e) => write!(f, "database error: {}", e),
+            StoreError::Transaction(e) => write!(f, "transaction error: {}", e),
+            StoreError::Conflict(e) => write!(f, "conflict: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<std::io::Error> for StoreError {
+    fn from(e: std::io::Error) -> Self {
+        return StoreError::Io(e);
+    }
+}
+
+impl From<serde_json::Error> for StoreError {
+    fn from(e: serde_json::Error) -> Self {
+        return StoreError::Serialization(e.to_string());
+    }
+}
+
+/// MongoDB error code of unique index violation
+const MONGO_DUPLICATE_KEY: i32 = 11000;
+
+impl From<mongodb::error::Error> for StoreError {
+    fn from(e: mongodb::error::Error) -> Self {
+        let duplicate = match e.kind.as_ref() {
+            ErrorKind::Write(WriteFailure::WriteError(we)) => we.code == MONGO_DUPLICATE_KEY,
+            ErrorKind::InsertMany(ie) => ie.write_errors.as_ref().map_or(false, |es| {
+                es.iter().any(|we| we.code == MONGO_DUPLICATE_KEY)
+            }),
+            ErrorKind::Command(ce) => ce.code == MONGO_DUPLICATE_KEY,
+            _ => false,
+        };
+        if duplicate {
+            return StoreError::Conflict(e.to_string());
+        }
+        return StoreError::Backend(e.to_string());
+    }
+}
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(e: rusqlite::Error) -> Self {
+        if let rusqlite::Error::SqliteFailure(err, _) = &e {
+            if err.extended_code == rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE {
+                return StoreError::Conflict(e.to_string());
+            }
+        }
+        return StoreError::Backend(e.to_string());
+    }
+}
+
+/// Result of storage operation
+pub type StoreResult<T> = Result<T, StoreError>;
+
+/// Key in item u64s holding revision maintained by the store
+pub const REVISION_KEY: &str = "__rev";
+
+/// Get revision of the stored item. Items written before revisions were
+/// introduced, as well as missing ones, have revision 0.
+pub fn item_revision(itm: Option<&Item>) -> u64 {
+    match itm {
+        Some(i) => {
+            return *i.u64s.get(REVISION_KEY).unwrap_or(&0);
+        }
+        None => {
+            return 0;
+        }
+    }
+}
+
+/// Check that the stored item has the expected revision and give the new
+/// item the next one. Revision sent by the client is never trusted.
+pub fn bump_revision(
+    old: Option<&Item>,
+    new_itm: &mut Item,
+    expected: Option<u64>,
+) -> StoreResult<()> {
+    let rev = item_revision(old);
+    if let Some(exp) = expected {
+        if exp != rev {
+            return Err(StoreError::Conflict(format!(
+                "item {} has revision {}, expected {}",
+                new_itm.id, rev, exp
+            )));
+        }
+    }
+    new_itm.u64s.insert(REVISION_KEY.to_string(), rev + 1);
+    return Ok(());
+}
+
+/// Single field of sorting
+#[derive(Debug, Clone, PartialEq)]
+pub struct SortSpec {
+    /// Field like `u64s.date`
+    pub field: String,
+
+    /// Sort from the highest value to the lowest
+    pub descending: bool,
+}
+
+/// Parse sort keys like `-u64s.date,strs.name`: fields are compared in
+/// order, `-` prefix means descending. Single field is plain sort key.
+pub fn parse_sort(sort_key: &str) -> Vec<SortSpec> {
+    let mut specs: Vec<SortSpec> = Vec::new();
+    for part in sort_key.split(',') {
+        let part = part.trim();
+        let (field, descending) = match part.strip_prefix('-') {
+            Some(f) => (f, true),
+            None => (part.strip_prefix('+').unwrap_or(part), false),
+        };
+        if field.is_empty() || field.starts_with('$') {
+            continue;
+        }
+        specs.push(SortSpec {
+            field: field.to_string(),
+            descending,
+        });
+    }
+    return specs;
+}
+
+/// Field kinds that can be selected with projection
+pub const PROJECTION_KINDS: [&str; 3] = ["strs", "bools", "u64s"];
+
+/// Parse projection fields like `strs.name,u64s.date`. Kind alone like
+/// `strs` selects all its keys. Returns None if some field can't be
+/// selected, empty list means all fields.
+pub fn parse_fields(fields: &str) -> Option<Vec<String>> {
+    let mut res: Vec<String> = Vec::new();
+    for part in fields.split(',') {
+        let part = part.trim();
+        if part == "" {
+            continue;
+        }
+        let (kind, key) = match part.split_once('.') {
+            Some((k, f)) => (k, Some(f)),
+            None => (part, None),
+        };
+        if !PROJECTION_KINDS.contains(&kind) || key.map_or(false, |f| f == "" || f.contains('$')) {
+            return None;
+        }
+        if !res.iter().any(|f| f == part) {
+            res.push(part.to_string());
+        }
+    }
+    return Some(res);
+}
+
+/// Keep only the given fields of the item, ID is always kept. Empty list
+/// keeps everything.
+pub fn project_item(itm: &mut Item, fields: &[String]) {
+    if fields.is_empty() {
+        return;
+    }
+    let keep = |kind: &str, key: &str| {
+        fields.iter().any(|f| match f.split_once('.') {
+            Some((k, name)) => k == kind && name == key,
+            None => f == kind,
+        })
+    };
+    itm.strs.retain(|k, _| keep("strs", k));
+    itm.bools.retain(|k, _| keep("bools", k));
+    itm.u64s.retain(|k, _| keep("u64s", k));
+    itm.strstrs.clear();
+}
+
+/// Function computed over a group of items
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AggregateOp {
+    Count,
+    Sum,
+    Min,
+    Max,
+}
+
+/// Single aggregate like `sum:u64s.amount`
+#[derive(Debug, Clone, PartialEq)]
+pub struct AggregateSpec {
+    /// Function
+    pub op: AggregateOp,
+
+    /// Field like `u64s.amount`, empty for count
+    pub field: String,
+}
+
+impl AggregateSpec {
+    /// Name of the value in results, the same as in the request
+    pub fn name(&self) -> String {
+        return match self.op {
+            AggregateOp::Count => "count".to_string(),
+            AggregateOp::Sum => "sum:".to_owned() + &self.field,
+            AggregateOp::Min => "min:".to_owned() + &self.field,
+            AggregateOp::Max => "max:".to_owned() + &self.field,
+        };
+    }
+}
+
+/// Parse aggregates like `count,sum:u64s.amount,max:u64s.date`. Sum, min
+/// and max are computed over `u64s` fields only. Returns None if some
+/// aggregate can't be computed, empty text means count.
+pub fn parse_aggregates(text: &str) -> Option<Vec<AggregateSpec>> {
+    let mut specs: Vec<AggregateSpec> = Vec::new();
+    for part in text.split(',') {
+        let part = part.trim();
+        if part == "" {
+            continue;
+        }
+        let (op, field) = part.split_once(':').unwrap_or((part, ""));
+        let op = match op {
+            "count" if field == "" => AggregateOp::Count,
+            "sum" => AggregateOp::Sum,
+            "min" => AggregateOp::Min,
+            "max" => AggregateOp::Max,
+            _ => {
+                return None;
+            }
+        };
+        if op != AggregateOp::Count && !is_plain_field(field, "u64s") {
+            return None;
+        }
+        let spec = AggregateSpec {
+            op,
+            field: field.to_string(),
+        };
+        if !specs.contains(&spec) {
+            specs.push(spec);
+        }
+    }
+    if specs.is_empty() {
+        specs.push(AggregateSpec {
+            op: AggregateOp::Count,
+            field: String::new(),
+        });
+    }
+    return Some(specs);
+}
+
+/// Check that the field is a key of the given kind, like `strs.name`
+pub fn is_plain_field(field: &str, kind: &str) -> bool {
+    return match field.split_once('.') {
+        Some((k, name)) => k == kind && name != "" && !name.contains(['.', '$']),
+        None => false,
+    };
+}
+
+/// Aggregate values of a group of items
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AggregateGroup {
+    /// Value of the group field, null for items without it or if items
+    /// aren't grouped
+    pub key: Option<String>,
+
+    /// Values by aggregate names. Minimum and maximum are missing if no
+    /// item of the group has the field.
+    pub values: HashMap<String, u64>,
+}
+
+/// Position after the last item of a page for keyset pagination
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ListCursor {
+    /// Sort keys the cursor was made for
+    pub sort: String,
+
+    /// Values of the sort fields, in order of sort keys
+    pub values: Vec<serde_json::Value>,
+
+    /// Item ID, which breaks ties between equal sort values
+    pub id: u64,
+}
+
+impl ListCursor {
+    /// Make the cursor pointing right after the item
+    pub fn after_item(sort_key: &str, itm: &Item) -> Self {
+        let doc = serde_json::to_value(itm).unwrap_or(serde_json::Value::Null);
+        let values = parse_sort(sort_key)
+            .iter()
+            .map(|spec| {
+                lookup_path(&doc, &spec.field)
+                    .cloned()
+                    .unwrap_or(serde_json::Value::Null)
+            })
+            .collect();
+        return Self {
+            sort: sort_key.to_string(),
+            values,
+            id: itm.id,
+        };
+    }
+
+    /// Opaque text form given to clients
+    pub fn encode(&self) -> String {
+        let data = serde_json::to_vec(self).unwrap_or_default();
+        return URL_SAFE_NO_PAD.encode(data);
+    }
+
+    /// Parse the text form. Cursors made for other sort keys are invalid.
+    /// Clients can forge cursors, so values other than numbers, strings,
+    /// booleans and nulls are rejected: stores put them into queries as is,
+    /// where objects would act as operators.
+    pub fn decode(text: &str, sort_key: &str) -> Option<Self> {
+        let data = URL_SAFE_NO_PAD.decode(text).ok()?;
+        let cursor: ListCursor = serde_json::from_slice(&data).ok()?;
+        if cursor.sort != sort_key || cursor.values.len() != parse_sort(sort_key).len() {
+            return None;
+        }
+        if cursor.values.iter().any(|v| v.is_object() || v.is_array()) {
+            return None;
+        }
+        return Some(cursor);
+    }
+}
+
+/// List of items that keeps the order requested from the store
+#[derive(Serialize)]
+pub struct OrderedList {
+    /// Items and total count
+    #[serde(flatten)]
+    pub list: ListResult,
+
+    /// Item IDs in the requested order
+    pub order: Vec<u64>,
+
+    /// Cursor of the next page, empty if there are no more items
+    pub next_cursor: String,
+}
+
+impl OrderedList {
+    pub fn new() -> Self {
+        Self {
+            list: ListResult {
+                map: HashMap::new(),
+                total_count: 0,
+            },
+            order: Vec::new(),
+            next_cursor: String::new(),
+        }
+    }
+
+    /// Add the item to the end
+    pub fn push(&mut self, itm: Item) {
+        if !self.list.map.contains_key(&itm.id) {
+            self.order.push(itm.id);
+        }
+        self.list.map.insert(itm.id, itm);
+    }
+
+    /// Make the order follow the items after hooks have changed them:
+    /// removed items are dropped, added ones go to the end by ID
+    pub fn sync_order(&mut self) {
+        let map = &self.list.map;
+        self.order.retain(|id| map.contains_key(id));
+        let known: HashSet<u64> = self.order.iter().cloned().collect();
+        let mut added: Vec<u64> = map
+            .keys()
+            .filter(|id| !known.contains(id))
+            .cloned()
+            .collect();
+        added.sort();
+        self.order.extend(added);
+    }
+}
+
+/// Secondary index of the collection declared in internals
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexDef {
+    /// Collection name
+    pub collection: String,
+
+    /// Indexed fields like `strs.login`, in order
+    pub fields: Vec<String>,
+
+    /// Two items can't have the same values of indexed fields
+    pub unique: bool,
+
+    /// Strings are compared ignoring case
+    pub case_insensitive: bool,
+}
+
+/// Read indexes declared in internals `indexes` string map. Every value is
+/// `<collection>:<field>[,<field>...][:unique|:unique_ci]`, where
+/// `unique_ci` ignores case of strings. Items that have none of the indexed
+/// fields are not indexed.
+pub fn declared_indexes(internals: &Item) -> Vec<IndexDef> {
+    let mut indexes: Vec<IndexDef> = Vec::new();
+    let declared = internals.safe_strstr("indexes", &HashMap::new());
+    let mut keys: Vec<&String> = declared.keys().collect();
+    keys.sort();
+    for key in keys {
+        let parts: Vec<&str> = declared[key].split(':').collect();
+        let fields: Vec<String> = parts
+            .get(1)
+            .unwrap_or(&"")
+            .split(',')
+            .filter(|f| !f.is_empty())
+            .map(|f| f.to_string())
+            .collect();
+        let case_insensitive = parts.get(2) == Some(&"unique_ci");
+        let unique = parts.get(2) == Some(&"unique") || case_insensitive;
+        if fields.is_empty() || parts.len() > 3 || (parts.len() == 3 && !unique) {
+            error!("Malformed index declaration: {}", declared[key]);
+            continue;
+        }
+        indexes.push(IndexDef {
+            collection: parts[0].to_string(),
+            fields,
+            unique,
+            case_insensitive,
+        });
+    }
+    return indexes;
+}
+
+/// Previous version of the item, recorded when the item is changed
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HistoryEntry {
+    /// Collection of the item
+    pub collection: String,
+
+    /// Item ID
+    pub id: u64,
+
+    /// Revision of the previous version
+    pub rev: u64,
+
+    /// Kind of change: create, modify, delete, revert or restore
+    pub action: String,
+
+    /// Login of the user who made the change
+    pub user: String,
+
+    /// Unix time of the change
+    pub time: u64,
+
+    /// Item before the change, None if it didn't exist
+    pub item: Option<Item>,
+}
+
+/// Migration applied to the database
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MigrationEntry {
+    /// Version of the migration
+    pub version: u64,
+
+    /// Name of the migration
+    pub name: String,
+
+    /// Unix time of applying
+    pub time: u64,
+}
+
+/// Item removed to trash, kept until restored or purged
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TrashEntry {
+    /// Collection of the item
+    pub collection: String,
+
+    /// Item ID
+    pub id: u64,
+
+    /// Login of the user who removed the item
+    pub user: String,
+
+    /// Unix time of removal
+    pub time: u64,
+
+    /// Removed item
+    pub item: Item,
+}
 
 /// Store implementation
 #[async_trait]
-pub trait Store {
+pub trait Store: Send {
     /// Connect the store to database
-    async fn connect(&mut self, addr: &str, altaddr: &str);
+    async fn connect(&mut self, addr: &str, altaddr: &str) -> StoreResult<()>;
 
     /// Disconnect the store
     #[allow(dead_code)]
     async fn disconnect(&mut self);
 
+    /// Check if collection is known to the store
+    fn has_collection(&self, collection: &str) -> bool;
+
     /// Get all collections
     async fn get_collections(&mut self) -> Vec<String>;
 
     /// Get all item IDs (can be exhausting)
-    async fn get_item_ids(&mut self, collection: &str) -> HashMap<u64, bool>;
+    async fn get_item_ids(&mut self, collection: &str) -> StoreResult<HashMap<u64, bool>>;
 
     /// Get all items (can be exhausting unless you provide filter)
-    async fn get_all_items(&mut self, collection: &str, sort_key: &str, filter: &str)
-        -> ListResult;
+    async fn get_all_items(
+        &mut self,
+        collection: &str,
+        sort_key: &str,
+        filter: &Filter,
+    ) -> StoreResult<ListResult>;
 
     /// Get item by specific ID
-    async fn get_item(&mut self, collection: &str, id: u64) -> Option<Item>;
+    async fn get_item(&mut self, collection: &str, id: u64) -> StoreResult<Option<Item>>;
 
     /// Get items by given parameters. Use u64::MAX for IDs you don't know.
     async fn get_items(
@@ -56,16 +569,194 @@ pub trait Store {
         id_min: u64,
         id_max: u64,
         sort_key: &str,
-        filter: &str,
+        filter: &Filter,
+        skip: u64,
+        limit: u64,
+    ) -> StoreResult<ListResult> {
+        let ol = self
+            .get_items_ordered(
+                collection,
+                id_min,
+                id_max,
+                sort_key,
+                filter,
+                None,
+                &[],
+                skip,
+                limit,
+            )
+            .await?;
+        return Ok(ol.list);
+    }
+
+    /// Get items like get_items() along with their order. Items with equal
+    /// sort keys go in the order of IDs, so pages don't overlap. With the
+    /// cursor only items after it are returned, skip applies after that.
+    /// Non-empty fields (see parse_fields()) limit what items contain.
+    async fn get_items_ordered(
+        &mut self,
+        collection: &str,
+        id_min: u64,
+        id_max: u64,
+        sort_key: &str,
+        filter: &Filter,
+        cursor: Option<&ListCursor>,
+        fields: &[String],
         skip: u64,
         limit: u64,
-    ) -> ListResult;
+    ) -> StoreResult<OrderedList>;
+
+    /// Compute aggregates over items matching the filter. Items are grouped
+    /// by the `strs` field if it's given, groups go in order of keys.
+    async fn aggregate(
+        &mut self,
+        collection: &str,
+        filter: &Filter,
+        group_by: &str,
+        specs: &[AggregateSpec],
+    ) -> StoreResult<Vec<AggregateGroup>> {
+        let ol = self
+            .get_items_ordered(
+                collection,
+                u64::MAX,
+                u64::MAX,
+                "",
+                filter,
+                None,
+                &[],
+                u64::MAX,
+                u64::MAX,
+            )
+            .await?;
+        return Ok(aggregate_items(ol.list.map.values(), group_by, specs));
+    }
+
+    /// Write the item to the database. Returns ID of the item.
+    async fn set_item(&mut self, collection: &str, itm: &Item, merge: bool) -> StoreResult<u64>;
+
+    /// Write the item only if the stored one still has the given revision,
+    /// otherwise fail with conflict. Revision of a missing item is 0.
+    async fn set_item_rev(
+        &mut self,
+        collection: &str,
+        itm: &Item,
+        merge: bool,
+        rev: u64,
+    ) -> StoreResult<u64>;
+
+    /// Remove the item from the database. Returns false if there was no
+    /// such item.
+    async fn del_item(&mut self, collection: &str, id: u64) -> StoreResult<bool>;
+
+    /// Write several items at once, all or nothing. Returns IDs of items
+    /// in the same order.
+    async fn set_items(
+        &mut self,
+        collection: &str,
+        itms: &[Item],
+        merge: bool,
+    ) -> StoreResult<Vec<u64>> {
+        let own_tx = !self.in_transaction();
+        if own_tx {
+            self.begin_transaction().await?;
+        }
 
-    /// Write the item to the database
-    async fn set_item(&mut self, collection: &str, itm: &Item, merge: bool) -> u64;
+        let mut ids: Vec<u64> = Vec::new();
+        for itm in itms {
+            match self.set_item(collection, itm, merge).await {
+                Ok(id) => ids.push(id),
+                Err(e) => {
+                    if own_tx {
+                        let _res = self.abort_transaction().await;
+                    }
+                    return Err(e);
+                }
+            }
+        }
 
-    /// Read the item from the database
-    async fn del_item(&mut self, collection: &str, id: u64) -> bool;
+        if own_tx {
+            self.commit_transaction().await?;
+        }
+        return Ok(ids);
+    }
+
+    /// Remove several items at once, all or nothing. Returns the number of
+    /// removed items.
+    #[allow(dead_code)]
+    async fn del_items(&mut self, collection: &str, ids: &[u64]) -> StoreResult<u64> {
+        let own_tx = !self.in_transaction();
+        if own_tx {
+            self.begin_transaction().await?;
+        }
+
+        let mut count = 0;
+        for id in ids {
+            match self.del_item(collection, *id).await {
+                Ok(removed) => {
+                    if removed {
+                        count += 1;
+                    }
+                }
+                Err(e) => {
+                    if own_tx {
+                        let _res = self.abort_transaction().await;
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        if own_tx {
+            self.commit_transaction().await?;
+        }
+        return Ok(count);
+    }
+
+    /// Start transaction. Writes done until commit are applied all together
+    /// or not at all. Reading a single item sees uncommitted writes, while
+    /// listing may not. Transactions don't nest.
+    async fn begin_transaction(&mut self) -> StoreResult<()>;
+
+    /// Apply writes done since the transaction start. If any of them failed,
+    /// nothing is applied and error is returned.
+    async fn commit_transaction(&mut self) -> StoreResult<()>;
+
+    /// Drop writes done since the transaction start
+    async fn abort_transaction(&mut self) -> StoreResult<()>;
+
+    /// Check if transaction is in progress
+    fn in_transaction(&self) -> bool;
+
+    /// Record previous version of the item. Inside transaction, the entry
+    /// is recorded only if the transaction is committed.
+    async fn add_history(&mut self, entry: &HistoryEntry) -> StoreResult<()>;
+
+    /// Get recorded versions of the item, oldest first
+    async fn get_history(&mut self, collection: &str, id: u64) -> StoreResult<Vec<HistoryEntry>>;
+
+    /// Get recorded versions of all items of the collection, including
+    /// removed ones, ordered by item ID and oldest first
+    async fn get_collection_history(&mut self, collection: &str) -> StoreResult<Vec<HistoryEntry>>;
+
+    /// Put removed item to trash. Item with the same ID already in trash
+    /// is replaced.
+    async fn add_trash(&mut self, entry: &TrashEntry) -> StoreResult<()>;
+
+    /// Get items of the collection in trash
+    async fn get_trash(&mut self, collection: &str) -> StoreResult<Vec<TrashEntry>>;
+
+    /// Remove the item from trash. Returns false if there was no such item.
+    async fn del_trash(&mut self, collection: &str, id: u64) -> StoreResult<bool>;
+
+    /// Remove items put to trash before the given time. Returns the number
+    /// of removed items.
+    async fn purge_trash(&mut self, before: u64) -> StoreResult<u64>;
+
+    /// Record applied migration
+    async fn add_migration(&mut self, entry: &MigrationEntry) -> StoreResult<()>;
+
+    /// Get applied migrations, ordered by version
+    async fn get_migrations(&mut self) -> StoreResult<Vec<MigrationEntry>>;
 
     /// Get credentials
     async fn get_credentials(&mut self) -> String;
@@ -79,6 +770,176 @@ pub trait Store {
     /// Read settings item
     async fn get_settings(&mut self) -> Item;
 
+    /// Write internal data. Collections listed there are registered on
+    /// the next connect or reload_internals().
+    async fn set_internals(&mut self, itm: Item) -> StoreResult<()>;
+
+    /// Register collections and indexes declared in internals, e.g. after
+    /// set_internals(). Already registered collections are kept.
+    async fn reload_internals(&mut self) -> StoreResult<()>;
+
     /// Write settings item
-    async fn set_settings(&mut self, itm: Item);
+    async fn set_settings(&mut self, itm: Item) -> StoreResult<()>;
+}
+
+/// Storage backend kind, selected by the database URL scheme
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StoreKind {
+    /// MongoDB server (`mongodb://` or `mongodb+srv://`)
+    Mongo,
+    /// Folder with JSON files (`file://`)
+    Local,
+    /// Single SQLite database file (`sqlite://`)
+    Sqlite,
+}
+
+impl StoreKind {
+    /// Detect storage kind from the database URL
+    pub fn from_url(url: &str) -> Option<StoreKind> {
+        if url.starts_with("mongodb://") || url.starts_with("mongodb+srv://") {
+            return Some(StoreKind::Mongo);
+        } else if url.starts_with("file://") {
+            return Some(StoreKind::Local);
+        } else if url.starts_with("sqlite://") {
+            return Some(StoreKind::Sqlite);
+        }
+        return None;
+    }
+}
+
+/// Create unconnected store of the given kind
+pub fn new_store(kind: StoreKind, database_name: &str) -> Box<dyn Store> {
+    match kind {
+        StoreKind::Mongo => {
+            let mut st = StoreMongo::new();
+            st.database_name = database_name.to_string();
+            return Box::new(st);
+        }
+        StoreKind::Local => {
+            return Box::new(StoreLocal::new());
+        }
+        StoreKind::Sqlite => {
+            return Box::new(StoreSqlite::new());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::filter::select_items;
+
+    fn item(id: u64, name: &str, date: u64) -> Item {
+        let mut itm = Item::new();
+        itm.id = id;
+        itm.set_str("name", name);
+        itm.set_u64("date", date);
+        return itm;
+    }
+
+    #[test]
+    fn cursor_round_trip() {
+        let sort_key = "-u64s.date,strs.name";
+        let cursor = ListCursor::after_item(sort_key, &item(7, "a", 3));
+        assert_eq!(
+            cursor.values,
+            vec![serde_json::json!(3), serde_json::json!("a")]
+        );
+        assert_eq!(cursor.id, 7);
+        assert_eq!(ListCursor::decode(&cursor.encode(), sort_key), Some(cursor));
+
+        let cursor = ListCursor::after_item("", &item(2, "a", 3));
+        assert!(cursor.values.is_empty());
+        assert_eq!(ListCursor::decode(&cursor.encode(), ""), Some(cursor));
+    }
+
+    #[test]
+    fn cursor_rejects_other_sort_keys() {
+        let cursor = ListCursor::after_item("strs.name", &item(1, "a", 3)).encode();
+        assert!(ListCursor::decode(&cursor, "strs.name").is_some());
+        assert!(ListCursor::decode(&cursor, "-strs.name").is_none());
+        assert!(ListCursor::decode(&cursor, "u64s.date").is_none());
+        assert!(ListCursor::decode("garbage", "strs.name").is_none());
+
+        let forged = ListCursor {
+            sort: "strs.name".to_string(),
+            values: vec![serde_json::json!({ "$gt": "" })],
+            id: 1,
+        };
+        assert!(ListCursor::decode(&forged.encode(), "strs.name").is_none());
+    }
+
+    #[test]
+    fn cursor_pages_through_equal_keys() {
+        let items = vec![
+            item(1, "a", 2),
+            item(2, "b", 1),
+            item(3, "c", 2),
+            item(4, "d", 2),
+            item(5, "e", 1),
+        ];
+        let sort_key = "-u64s.date";
+        let mut seen: Vec<u64> = Vec::new();
+        let mut cursor: Option<ListCursor> = None;
+        loop {
+            let ol = select_items(
+                items.clone(),
+                sort_key,
+                &Filter::all(),
+                cursor.as_ref(),
+                &[],
+                u64::MAX,
+                2,
+            );
+            assert_eq!(ol.list.total_count, 5);
+            seen.extend(ol.order.iter());
+            if ol.next_cursor == "" {
+                break;
+            }
+            cursor = ListCursor::decode(&ol.next_cursor, sort_key);
+            assert!(cursor.is_some());
+        }
+        // Items with equal dates follow in ID order, none is lost or repeated
+        assert_eq!(seen, vec![1, 3, 4, 2, 5]);
+    }
+
+    #[test]
+    fn index_declarations() {
+        let mut internals = Item::new();
+        let mut decl = HashMap::new();
+        decl.insert("1".to_string(), "user:strs.login:unique_ci".to_string());
+        decl.insert("2".to_string(), "user:strs.email:unique".to_string());
+        decl.insert("3".to_string(), "job:strs.state,u64s.date".to_string());
+        decl.insert("4".to_string(), "job:strs.state:other".to_string());
+        decl.insert("5".to_string(), "job".to_string());
+        decl.insert("6".to_string(), "job:strs.a:unique:x".to_string());
+        internals.strstrs.insert("indexes".to_string(), decl);
+
+        // Malformed declarations are skipped
+        let indexes = declared_indexes(&internals);
+        assert_eq!(
+            indexes,
+            vec![
+                IndexDef {
+                    collection: "user".to_string(),
+                    fields: vec!["strs.login".to_string()],
+                    unique: true,
+                    case_insensitive: true,
+                },
+                IndexDef {
+                    collection: "user".to_string(),
+                    fields: vec!["strs.email".to_string()],
+                    unique: true,
+                    case_insensitive: false,
+                },
+                IndexDef {
+                    collection: "job".to_string(),
+                    fields: vec!["strs.state".to_string(), "u64s.date".to_string()],
+                    unique: false,
+                    case_insensitive: false,
+                },
+            ]
+        );
+        assert!(declared_indexes(&Item::new()).is_empty());
+    }
 }
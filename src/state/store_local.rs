@@ -24,12 +24,94 @@
 use isabelle_dm::data_model::list_result::ListResult;
 use std::path::Path;
 
-use crate::state::store::Store;
+use crate::state::filter::{lookup_path, select_items, Filter, FilterOp};
+use crate::state::store::{
+    bump_revision, declared_indexes, HistoryEntry, IndexDef, ListCursor, MigrationEntry,
+    OrderedList, Store, StoreError, StoreResult, TrashEntry,
+};
+use crate::util::file::{sync_dir, write_atomic, TMP_SUFFIX};
 use async_trait::async_trait;
 use isabelle_dm::data_model::item::*;
-use log::{debug, error, trace};
-use std::collections::HashMap;
+use log::{debug, error, info, trace};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
 use std::fs;
+use std::io::Write;
+
+/// In-memory secondary index of the collection
+#[derive(Debug, Clone)]
+pub struct LocalIndex {
+    /// Index definition
+    pub def: IndexDef,
+
+    /// Item IDs by key
+    pub ids: HashMap<String, HashSet<u64>>,
+
+    /// Keys by item ID
+    pub keys: HashMap<u64, String>,
+}
+
+impl LocalIndex {
+    pub fn new(def: IndexDef) -> Self {
+        Self {
+            def,
+            ids: HashMap::new(),
+            keys: HashMap::new(),
+        }
+    }
+
+    /// Make the key from values of indexed fields. Filter conditions and
+    /// items converted to JSON give the same key for the same values.
+    /// Returns None if none of the fields is set.
+    pub fn key(&self, values: &[Option<&Value>]) -> Option<String> {
+        if values.iter().all(|v| v.map_or(true, |v| v.is_null())) {
+            return None;
+        }
+        let arr: Vec<Value> = values
+            .iter()
+            .map(|v| match v {
+                Some(Value::String(s)) if self.def.case_insensitive => {
+                    Value::String(s.to_lowercase())
+                }
+                Some(v) => (*v).clone(),
+                None => Value::Null,
+            })
+            .collect();
+        return Some(Value::Array(arr).to_string());
+    }
+
+    /// Make the key of the item converted to JSON
+    pub fn item_key(&self, doc: &Value) -> Option<String> {
+        let values: Vec<Option<&Value>> = self
+            .def
+            .fields
+            .iter()
+            .map(|f| lookup_path(doc, f))
+            .collect();
+        return self.key(&values);
+    }
+
+    /// Forget the item
+    pub fn remove(&mut self, id: u64) {
+        if let Some(key) = self.keys.remove(&id) {
+            if let Some(ids) = self.ids.get_mut(&key) {
+                ids.remove(&id);
+                if ids.is_empty() {
+                    self.ids.remove(&key);
+                }
+            }
+        }
+    }
+
+    /// Index the item converted to JSON
+    pub fn insert(&mut self, id: u64, doc: &Value) {
+        self.remove(id);
+        if let Some(key) = self.item_key(doc) {
+            self.ids.entry(key.clone()).or_default().insert(id);
+            self.keys.insert(id, key);
+        }
+    }
+}
 
 /// Local storage implementation
 #[derive(Debug, Clone)]
@@ -45,6 +127,22 @@ pub struct StoreLocal {
 
     /// Item counters
     pub items_count: HashMap<u64, u64>,
+
+    /// Writes staged by the current transaction: serialized item or None
+    /// for removal
+    pub staged: Option<HashMap<(String, u64), Option<String>>>,
+
+    /// Number of writes staged by the current transaction
+    pub staged_count: u64,
+
+    /// History recorded by the current transaction
+    pub staged_history: Vec<HistoryEntry>,
+
+    /// Some write inside the current transaction failed
+    pub tx_failed: bool,
+
+    /// Secondary indexes by collection
+    pub indexes: HashMap<String, Vec<LocalIndex>>,
 }
 
 unsafe impl Send for StoreLocal {}
@@ -56,61 +154,595 @@ impl StoreLocal {
             collections: HashMap::new(),
             items: HashMap::new(),
             items_count: HashMap::new(),
+            staged: None,
+            staged_count: 0,
+            staged_history: Vec::new(),
+            tx_failed: false,
+            indexes: HashMap::new(),
         }
     }
-}
 
-#[async_trait]
-impl Store for StoreLocal {
-    async fn connect(&mut self, url: &str, _alturl: &str) {
-        self.path = url.to_string();
-        let collections = fs::read_dir(self.path.to_string() + "/collection").unwrap();
+    /// Register collections listed in internals and all collection folders
+    /// that aren't registered yet. Collections listed in internals exist
+    /// even if nothing was written to them yet, like with other stores.
+    async fn register_collections(&mut self) -> StoreResult<()> {
+        if Path::new(&self.path).is_dir() {
+            let internals = self.get_internals().await;
+            for coll_name in internals.safe_strstr("collections", &HashMap::new()) {
+                let coll_path = self.collection_path(&coll_name.1);
+                if !Path::new(&coll_path).is_dir() {
+                    info!("Creating folder for collection {}", coll_name.1);
+                    fs::create_dir_all(&coll_path)?;
+                }
+            }
+        }
+
+        let collections = fs::read_dir(self.path.to_string() + "/collection")?;
         for coll in collections {
-            let idx = coll.as_ref().unwrap().file_name().into_string().unwrap();
-            let new_col: HashMap<u64, bool> = HashMap::new();
+            let coll = coll?;
+            let idx = coll.file_name().to_string_lossy().to_string();
+            if !coll.path().is_dir() || self.collections.contains_key(&idx) {
+                continue;
+            }
             let coll_index = self.items.len().try_into().unwrap();
-            self.items.insert(coll_index, new_col);
+            self.items.insert(coll_index, HashMap::new());
             self.collections.insert(idx.clone(), coll_index);
             trace!("New collection {}", idx.clone());
 
-            let cnt_str =
-                std::fs::read_to_string(self.path.clone() + "/collection/" + &idx + "/cnt");
-            if let Err(_e) = cnt_str {
-                error!("Failed to read counter");
+            self.recover_collection(&idx, coll_index);
+        }
+        return Ok(());
+    }
+
+    /// Build indexes declared in internals from stored items
+    fn build_indexes(&mut self, defs: Vec<IndexDef>) {
+        self.indexes = HashMap::new();
+        for def in defs {
+            if !self.collections.contains_key(&def.collection) {
+                error!("Index of unknown collection {}", def.collection);
                 continue;
             }
+            let collection = def.collection.clone();
+            let mut idx = LocalIndex::new(def);
+            let coll_id = self.collections[&collection];
+            let ids: Vec<u64> = self
+                .items
+                .get(&coll_id)
+                .map(|m| m.keys().cloned().collect())
+                .unwrap_or_default();
+            for id in ids {
+                match self.read_item(&collection, id) {
+                    Ok(Some(itm)) => match serde_json::to_value(&itm) {
+                        Ok(doc) => idx.insert(id, &doc),
+                        Err(e) => {
+                            error!("Failed to index {} item {}: {}", collection, id, e);
+                        }
+                    },
+                    Ok(None) => {}
+                    Err(e) => {
+                        error!("Failed to index {} item {}: {}", collection, id, e);
+                    }
+                }
+            }
+            info!(
+                "Index of {} on {} built: {} keys",
+                collection,
+                idx.def.fields.join(","),
+                idx.ids.len()
+            );
+            self.indexes.entry(collection).or_default().push(idx);
+        }
+    }
+
+    /// Update indexes of the collection after write (or removal if there's
+    /// no item)
+    fn update_indexes(&mut self, collection: &str, id: u64, doc: Option<&Value>) {
+        if let Some(indexes) = self.indexes.get_mut(collection) {
+            for idx in indexes {
+                match doc {
+                    Some(d) => idx.insert(id, d),
+                    None => idx.remove(id),
+                }
+            }
+        }
+    }
+
+    /// Check that no other item has the same values of uniquely indexed
+    /// fields. Indexes are updated only when staged writes are applied, so
+    /// writes staged by the current transaction are checked as well.
+    fn check_unique(&self, collection: &str, itm: &Item) -> StoreResult<()> {
+        let indexes = match self.indexes.get(collection) {
+            Some(i) => i,
+            None => {
+                return Ok(());
+            }
+        };
 
-            let parsed = cnt_str.as_ref().unwrap().trim().parse::<u64>();
-            if let Err(_e) = parsed {
-                error!("Failed to parse counter {}", cnt_str.as_ref().unwrap());
+        // Staged versions of other items replace the indexed ones
+        let mut staged_ids: HashSet<u64> = HashSet::new();
+        let mut staged_docs: Vec<(u64, Value)> = Vec::new();
+        for ((coll, id), text) in self.staged.iter().flatten() {
+            if coll != collection || *id == itm.id {
                 continue;
             }
+            staged_ids.insert(*id);
+            if let Some(t) = text {
+                staged_docs.push((*id, serde_json::from_str(t)?));
+            }
+        }
+
+        let doc = serde_json::to_value(itm)?;
+        for idx in indexes.iter().filter(|i| i.def.unique) {
+            let key = match idx.item_key(&doc) {
+                Some(k) => k,
+                None => continue,
+            };
+            let indexed = idx.ids.get(&key).and_then(|ids| {
+                ids.iter()
+                    .find(|id| **id != itm.id && !staged_ids.contains(id))
+                    .cloned()
+            });
+            let staged = staged_docs
+                .iter()
+                .find(|(_, d)| idx.item_key(d).as_ref() == Some(&key))
+                .map(|(id, _)| *id);
+            if let Some(other) = indexed.or(staged) {
+                return Err(StoreError::Conflict(format!(
+                    "{} of {} is already used by item {}",
+                    idx.def.fields.join(","),
+                    collection,
+                    other
+                )));
+            }
+        }
+        return Ok(());
+    }
+
+    /// Get IDs of items that may match the filter, using indexes for field
+    /// equality, `$and` and `$or`. Returns None if indexes don't help. The
+    /// filter still has to be applied to the candidates.
+    fn index_candidates(&self, collection: &str, filter: &Filter) -> Option<HashSet<u64>> {
+        return match filter {
+            Filter::And(list) => self.index_candidates_all(collection, list),
+            Filter::Cond { .. } => {
+                self.index_candidates_all(collection, std::slice::from_ref(filter))
+            }
+            Filter::Or(list) => {
+                let mut union: HashSet<u64> = HashSet::new();
+                for f in list {
+                    union.extend(self.index_candidates(collection, f)?);
+                }
+                Some(union)
+            }
+            _ => None,
+        };
+    }
+
+    /// Find candidates for conditions that must all hold
+    fn index_candidates_all(&self, collection: &str, list: &[Filter]) -> Option<HashSet<u64>> {
+        let indexes = self.indexes.get(collection)?;
+        let mut res: Option<HashSet<u64>> = None;
+        let narrow = |res: &mut Option<HashSet<u64>>, c: HashSet<u64>| {
+            *res = Some(match res.take() {
+                Some(r) => r.intersection(&c).cloned().collect(),
+                None => c,
+            });
+        };
+
+        // Equality conditions on single fields
+        let equal: HashMap<&str, &Value> = list
+            .iter()
+            .filter_map(|f| match f {
+                Filter::Cond {
+                    field,
+                    op: FilterOp::Eq,
+                    value,
+                } => Some((field.as_str(), value)),
+                _ => None,
+            })
+            .collect();
+        for idx in indexes {
+            let values: Vec<Option<&Value>> = idx
+                .def
+                .fields
+                .iter()
+                .map(|f| equal.get(f.as_str()).cloned())
+                .collect();
+            if values.iter().any(|v| v.is_none()) {
+                continue;
+            }
+            // Items without indexed fields aren't indexed
+            if let Some(key) = idx.key(&values) {
+                narrow(&mut res, idx.ids.get(&key).cloned().unwrap_or_default());
+            }
+        }
+
+        // Combinations
+        for f in list {
+            if let Filter::And(_) | Filter::Or(_) = f {
+                if let Some(c) = self.index_candidates(collection, f) {
+                    narrow(&mut res, c);
+                }
+            }
+        }
+        return res;
+    }
 
-            self.items_count
-                .insert(self.collections[&idx], *parsed.as_ref().unwrap());
-            trace!(" - index: {}", self.collections[&idx]);
-            trace!(" - counter: {}", parsed.as_ref().unwrap());
-
-            let data_files = fs::read_dir(self.path.to_string() + "/collection/" + &idx).unwrap();
-            for data_file in data_files {
-                let data_file_idx = data_file
-                    .as_ref()
-                    .unwrap()
-                    .file_name()
-                    .into_string()
-                    .unwrap();
-                let tmp_path = self.path.to_string() + "/collection/" + &idx + "/" + &data_file_idx;
-                if Path::new(&tmp_path).is_dir() {
-                    let m = self.items.get_mut(&coll_index).unwrap();
-                    (*m).insert(data_file_idx.parse::<u64>().unwrap(), true);
-                    trace!("{}: idx {}", &idx, &data_file_idx);
+    /// Path to the file with recorded versions of the item
+    fn history_path(&self, collection: &str, id: u64) -> String {
+        return self.path.to_string() + "/history/" + collection + "/" + &id.to_string() + ".jsonl";
+    }
+
+    /// Path to the file with the item in trash
+    fn trash_path(&self, collection: &str, id: u64) -> String {
+        return self.path.to_string() + "/trash/" + collection + "/" + &id.to_string() + ".js";
+    }
+
+    /// Path to the write-ahead staging folder
+    fn staging_path(&self) -> String {
+        return self.path.to_string() + "/.staging";
+    }
+
+    /// Path to collection folder
+    fn collection_path(&self, collection: &str) -> String {
+        return self.path.to_string() + "/collection/" + collection;
+    }
+
+    /// Write counter file. The file keeps the next free ID, while memory
+    /// keeps the highest used one.
+    fn write_counter(&self, collection: &str, max_id: u64) -> bool {
+        let cnt_path = self.collection_path(collection) + "/cnt";
+        if let Err(e) = write_atomic(&cnt_path, (max_id + 1).to_string().as_bytes()) {
+            error!("Failed to write counter {}: {}", cnt_path, e);
+            return false;
+        }
+        return true;
+    }
+
+    /// Read item from its folder, taking staged writes into account
+    fn read_item(&self, collection: &str, id: u64) -> StoreResult<Option<Item>> {
+        if let Some(staged) = &self.staged {
+            if let Some(text) = staged.get(&(collection.to_string(), id)) {
+                match text {
+                    Some(t) => {
+                        return Ok(Some(serde_json::from_str(t)?));
+                    }
+                    None => {
+                        return Ok(None);
+                    }
                 }
             }
         }
+
+        let tmp_path = self.collection_path(collection) + "/" + &id.to_string() + "/data.js";
+        if Path::new(&tmp_path).is_file() {
+            let text = std::fs::read_to_string(&tmp_path)?;
+            let itm: Item = serde_json::from_str(&text)?;
+            return Ok(Some(itm));
+        }
+        return Ok(None);
+    }
+
+    /// Prepare the item and either write it or stage it for the transaction
+    fn store_item(
+        &mut self,
+        collection: &str,
+        exp_itm: &Item,
+        merge: bool,
+        rev: Option<u64>,
+    ) -> StoreResult<u64> {
+        if !self.collections.contains_key(collection) {
+            return Err(StoreError::UnknownCollection(collection.to_string()));
+        }
+        let coll_id = self.collections[collection];
+        let mut itm = exp_itm.clone();
+
+        if itm.bools.contains_key("__security_preserve") {
+            itm.bools.remove("__security_preserve");
+        }
+
+        if itm.id == u64::MAX {
+            itm.id = *self.items_count.get(&coll_id).unwrap_or(&0) + 1;
+            self.items_count.insert(coll_id, itm.id);
+        }
+
+        let old_itm = self.read_item(collection, itm.id)?;
+        let mut new_itm = itm.clone();
+        if merge {
+            if let Some(o) = &old_itm {
+                new_itm = o.clone();
+                new_itm.merge(&itm);
+            }
+        }
+        bump_revision(old_itm.as_ref(), &mut new_itm, rev)?;
+        self.check_unique(collection, &new_itm)?;
+
+        let text = serde_json::to_string(&new_itm)?;
+        if self.staged.is_some() {
+            self.stage(collection, new_itm.id, Some(text))?;
+        } else {
+            self.write_item(collection, new_itm.id, &text)?;
+        }
+        return Ok(new_itm.id);
+    }
+
+    /// Remove the item or stage removal for the transaction
+    fn remove_item(&mut self, collection: &str, id: u64) -> StoreResult<bool> {
+        if !self.collections.contains_key(collection) {
+            return Err(StoreError::UnknownCollection(collection.to_string()));
+        }
+        if self.staged.is_some() {
+            let existed = self.read_item(collection, id)?.is_some();
+            self.stage(collection, id, None)?;
+            return Ok(existed);
+        }
+        return self.delete_item(collection, id);
+    }
+
+    /// Write serialized item to its folder and update counters
+    fn write_item(&mut self, collection: &str, id: u64, text: &str) -> StoreResult<()> {
+        let coll_path = self.collection_path(collection);
+        let tmp_path = coll_path.clone() + "/" + &id.to_string();
+
+        if !Path::new(&tmp_path).is_dir() {
+            std::fs::create_dir(&tmp_path)?;
+            sync_dir(&coll_path);
+        }
+
+        let tmp_data_path = tmp_path.clone() + "/data.js";
+        write_atomic(&tmp_data_path, text.as_bytes())?;
+        if self.indexes.contains_key(collection) {
+            let doc: Value = serde_json::from_str(text)?;
+            self.update_indexes(collection, id, Some(&doc));
+        }
+
+        if let Some(coll_id) = self.collections.get(collection).cloned() {
+            self.items.entry(coll_id).or_default().insert(id, true);
+            let cnt = *self.items_count.get(&coll_id).unwrap_or(&0);
+            if id >= cnt {
+                self.items_count.insert(coll_id, id);
+                self.write_counter(collection, id);
+            }
+        }
+        return Ok(());
+    }
+
+    /// Remove item folder
+    fn delete_item(&mut self, collection: &str, id: u64) -> StoreResult<bool> {
+        let tmp_path = self.collection_path(collection) + "/" + &id.to_string();
+        let path = Path::new(&tmp_path);
+        if path.exists() {
+            std::fs::remove_dir_all(&tmp_path)?;
+            sync_dir(&self.collection_path(collection));
+        }
+        self.update_indexes(collection, id, None);
+        if let Some(coll_id) = self.collections.get(collection) {
+            if let Some(coll) = self.items.get_mut(coll_id) {
+                return Ok(coll.remove(&id).is_some());
+            }
+        }
+        return Ok(false);
+    }
+
+    /// Put write into the staging folder before applying it, so that the
+    /// committed transaction can be completed after crash
+    fn stage(&mut self, collection: &str, id: u64, text: Option<String>) -> StoreResult<()> {
+        let record = serde_json::json!({
+            "collection": collection,
+            "id": id,
+            "item": text,
+        });
+        self.staged_count += 1;
+        let record_path = self.staging_path() + "/" + &self.staged_count.to_string() + ".js";
+        write_atomic(&record_path, record.to_string().as_bytes())?;
+        if let Some(staged) = self.staged.as_mut() {
+            staged.insert((collection.to_string(), id), text);
+        }
+        return Ok(());
+    }
+
+    /// Put history entry into the staging folder, so that it is recorded
+    /// only if the transaction is committed
+    fn stage_history(&mut self, entry: &HistoryEntry) -> StoreResult<()> {
+        let record = serde_json::json!({ "history": entry });
+        self.staged_count += 1;
+        let record_path = self.staging_path() + "/" + &self.staged_count.to_string() + ".js";
+        write_atomic(&record_path, record.to_string().as_bytes())?;
+        self.staged_history.push(entry.clone());
+        return Ok(());
+    }
+
+    /// Append version to the history file of the item
+    fn append_history(&mut self, entry: &HistoryEntry) -> StoreResult<()> {
+        let hist_dir = self.path.to_string() + "/history/" + &entry.collection;
+        fs::create_dir_all(&hist_dir)?;
+
+        // One version per line. Torn last line after crash is skipped on
+        // reading, so appending is enough.
+        let line = serde_json::to_string(entry)? + "\n";
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.history_path(&entry.collection, entry.id))?;
+        file.write_all(line.as_bytes())?;
+        file.sync_all()?;
+        return Ok(());
+    }
+
+    /// Apply single staged write
+    fn apply_staged(&mut self, collection: &str, id: u64, text: Option<String>) -> StoreResult<()> {
+        match text {
+            Some(t) => {
+                self.write_item(collection, id, &t)?;
+            }
+            None => {
+                self.delete_item(collection, id)?;
+            }
+        }
+        return Ok(());
+    }
+
+    /// Replay the committed transaction left in the staging folder, or drop
+    /// the uncommitted one
+    fn recover_staging(&mut self) -> StoreResult<()> {
+        let staging_path = self.staging_path();
+        if !Path::new(&staging_path).is_dir() {
+            return Ok(());
+        }
+
+        if Path::new(&(staging_path.clone() + "/commit")).is_file() {
+            info!("Replaying committed transaction from {}", staging_path);
+            let mut records: Vec<(u64, String)> = Vec::new();
+            for entry in fs::read_dir(&staging_path)?.flatten() {
+                let name = entry.file_name().to_string_lossy().to_string();
+                if let Some(seq) = name.strip_suffix(".js") {
+                    if let Ok(seq) = seq.parse::<u64>() {
+                        records.push((seq, staging_path.clone() + "/" + &name));
+                    }
+                }
+            }
+            records.sort();
+            for (_seq, record_path) in records {
+                let record: serde_json::Value =
+                    serde_json::from_str(&fs::read_to_string(&record_path)?)?;
+                if let Some(h) = record.get("history") {
+                    match serde_json::from_value::<HistoryEntry>(h.clone()) {
+                        Ok(entry) => self.append_history(&entry)?,
+                        Err(e) => {
+                            error!("Skipping bad staged history {}: {}", record_path, e);
+                        }
+                    }
+                    continue;
+                }
+                let collection = record["collection"].as_str().unwrap_or("").to_string();
+                let id = record["id"].as_u64().unwrap_or(u64::MAX);
+                if !self.collections.contains_key(&collection) || id == u64::MAX {
+                    error!("Skipping bad staged record {}", record_path);
+                    continue;
+                }
+                let text = record["item"].as_str().map(|t| t.to_string());
+                self.apply_staged(&collection, id, text)?;
+            }
+        } else {
+            info!("Dropping uncommitted transaction from {}", staging_path);
+        }
+
+        fs::remove_dir_all(&staging_path)?;
+        sync_dir(&self.path);
+        return Ok(());
+    }
+
+    /// Scan collection folder, register existing items and repair the
+    /// counter if it is missing, broken or behind the highest item.
+    fn recover_collection(&mut self, collection: &str, coll_index: u64) {
+        let coll_path = self.collection_path(collection);
+        let mut ids: HashMap<u64, bool> = HashMap::new();
+        let mut max_id = 0;
+
+        let data_files = match fs::read_dir(&coll_path) {
+            Ok(f) => f,
+            Err(e) => {
+                error!("Failed to read collection {}: {}", coll_path, e);
+                return;
+            }
+        };
+        for data_file in data_files.flatten() {
+            let data_file_idx = data_file.file_name().to_string_lossy().to_string();
+            let tmp_path = coll_path.clone() + "/" + &data_file_idx;
+
+            // Leftovers of interrupted writes
+            if data_file_idx.ends_with(TMP_SUFFIX) {
+                info!("Removing stale temporary file {}", tmp_path);
+                let _res = fs::remove_file(&tmp_path);
+                continue;
+            }
+            if !Path::new(&tmp_path).is_dir() {
+                continue;
+            }
+            let stale_data_path = tmp_path.clone() + "/data.js" + TMP_SUFFIX;
+            if Path::new(&stale_data_path).exists() {
+                info!("Removing stale temporary file {}", stale_data_path);
+                let _res = fs::remove_file(&stale_data_path);
+            }
+
+            let id = match data_file_idx.parse::<u64>() {
+                Ok(id) => id,
+                Err(_e) => {
+                    error!("Unexpected entry {} in {}", data_file_idx, collection);
+                    continue;
+                }
+            };
+            if !Path::new(&(tmp_path + "/data.js")).is_file() {
+                error!("Item {} in {} has no data, skipping", id, collection);
+                continue;
+            }
+
+            ids.insert(id, true);
+            if id > max_id {
+                max_id = id;
+            }
+            trace!("{}: idx {}", collection, &data_file_idx);
+        }
+
+        let cnt_str = fs::read_to_string(coll_path.clone() + "/cnt");
+        let stored = match &cnt_str {
+            Ok(s) => match s.trim().parse::<u64>() {
+                Ok(v) => Some(v),
+                Err(_e) => {
+                    error!("Failed to parse counter {}", s);
+                    None
+                }
+            },
+            Err(_e) => {
+                error!("Failed to read counter of {}", collection);
+                None
+            }
+        };
+
+        let cnt = match stored {
+            Some(v) if v > max_id => v - 1,
+            _ => {
+                info!("Repairing counter of {}: max ID {}", collection, max_id);
+                self.write_counter(collection, max_id);
+                max_id
+            }
+        };
+
+        self.items.insert(coll_index, ids);
+        self.items_count.insert(coll_index, cnt);
+        trace!(" - index: {}", coll_index);
+        trace!(" - counter: {}", cnt);
+    }
+}
+
+#[async_trait]
+impl Store for StoreLocal {
+    async fn connect(&mut self, url: &str, alturl: &str) -> StoreResult<()> {
+        // Accept both plain paths and file:// URLs. Empty path means the data
+        // folder passed as alternative address.
+        self.path = url.strip_prefix("file://").unwrap_or(url).to_string();
+        if self.path == "" {
+            self.path = alturl.to_string();
+        }
+
+        self.collections = HashMap::new();
+        self.items = HashMap::new();
+        self.register_collections().await?;
+
+        // Complete the transaction interrupted by crash, if any
+        self.recover_staging()?;
+
+        let internals = self.get_internals().await;
+        self.build_indexes(declared_indexes(&internals));
+        return Ok(());
     }
 
     async fn disconnect(&mut self) {}
 
+    fn has_collection(&self, collection: &str) -> bool {
+        return self.collections.contains_key(collection);
+    }
+
     async fn get_collections(&mut self) -> Vec<String> {
         let mut lst: Vec<String> = Vec::new();
 
@@ -121,21 +753,21 @@ impl Store for StoreLocal {
         return lst;
     }
 
-    async fn get_item_ids(&mut self, collection: &str) -> HashMap<u64, bool> {
+    async fn get_item_ids(&mut self, collection: &str) -> StoreResult<HashMap<u64, bool>> {
         if !self.collections.contains_key(collection) {
-            return HashMap::new();
+            return Err(StoreError::UnknownCollection(collection.to_string()));
         }
 
         let coll_id = self.collections[collection];
-        return self.items[&coll_id].clone();
+        return Ok(self.items.get(&coll_id).cloned().unwrap_or_default());
     }
 
     async fn get_all_items(
         &mut self,
         collection: &str,
         sort_key: &str,
-        filter: &str,
-    ) -> ListResult {
+        filter: &Filter,
+    ) -> StoreResult<ListResult> {
         return self
             .get_items(
                 collection,
@@ -149,150 +781,341 @@ impl Store for StoreLocal {
             .await;
     }
 
-    async fn get_item(&mut self, collection: &str, id: u64) -> Option<Item> {
-        let tmp_path = self.path.to_string()
-            + "/collection/"
-            + collection
-            + "/"
-            + &id.to_string()
-            + "/data.js";
-        if Path::new(&tmp_path).is_file() {
-            let text = std::fs::read_to_string(tmp_path).unwrap();
-            let itm: Item = serde_json::from_str(&text).unwrap();
-            return Some(itm);
-        }
-        return None;
+    async fn get_item(&mut self, collection: &str, id: u64) -> StoreResult<Option<Item>> {
+        return self.read_item(collection, id);
     }
 
-    async fn get_items(
+    async fn get_items_ordered(
         &mut self,
         collection: &str,
         id_min: u64,
         id_max: u64,
-        _sort_key: &str,
-        _filter: &str,
+        sort_key: &str,
+        filter: &Filter,
+        cursor: Option<&ListCursor>,
+        fields: &[String],
         skip: u64,
         limit: u64,
-    ) -> ListResult {
-        let mut lr = ListResult {
-            map: HashMap::new(),
-            total_count: 0,
-        };
-        let itms = self
-            .items
-            .get_mut(&self.collections[collection])
-            .unwrap()
-            .clone();
+    ) -> StoreResult<OrderedList> {
+        let itms = self.get_item_ids(collection).await?;
         let mut eff_id_min = id_min;
         let eff_id_max = id_max;
-        let mut count = 0;
-        let mut eff_skip = skip;
-
-        if eff_skip == u64::MAX {
-            eff_skip = 0;
-        }
 
         if eff_id_min == u64::MAX {
             eff_id_min = 0;
         }
 
         debug!(
-            "Getting {} in range {} - {} limit {}",
-            &collection, eff_id_min, eff_id_max, limit
+            "Getting {} in range {} - {} skip {} limit {} sort key {} filter {}",
+            &collection, eff_id_min, eff_id_max, skip, limit, sort_key, filter
         );
-        for itm in &itms {
-            if itm.0 >= &eff_id_min && itm.0 <= &eff_id_max {
-                let new_item = self.get_item(collection, *itm.0).await;
-                if !new_item.is_none() {
-                    if count >= eff_skip {
-                        lr.map.insert(*itm.0, new_item.unwrap());
-                    }
-                    count = count + 1;
-                    if count >= eff_skip && (count - eff_skip) >= limit {
-                        break;
-                    }
+
+        // Walk IDs in stable order so that pages don't depend on hash order
+        let mut ids: Vec<u64> = itms
+            .keys()
+            .filter(|id| **id >= eff_id_min && **id <= eff_id_max)
+            .cloned()
+            .collect();
+        ids.sort();
+
+        // Load only items that may match, if indexes allow
+        if let Some(candidates) = self.index_candidates(collection, filter) {
+            trace!(" - index candidates: {}", candidates.len());
+            ids.retain(|id| candidates.contains(id));
+        }
+
+        let mut loaded: Vec<Item> = Vec::new();
+        for id in ids {
+            // Single broken item shouldn't make the whole collection unusable
+            match self.get_item(collection, id).await {
+                Ok(Some(itm)) => loaded.push(itm),
+                Ok(None) => {}
+                Err(e) => {
+                    error!("Failed to load {} item {}: {}", collection, id, e);
                 }
             }
         }
-        debug!(" - result: {} items", count - eff_skip);
-        lr.total_count = itms.len() as u64;
 
-        return lr;
+        let ol = select_items(loaded, sort_key, filter, cursor, fields, skip, limit);
+        debug!(
+            " - result: {} items, total {}",
+            ol.order.len(),
+            ol.list.total_count
+        );
+
+        return Ok(ol);
     }
 
-    async fn set_item(&mut self, collection: &str, exp_itm: &Item, merge: bool) -> u64 {
-        let mut itm = exp_itm.clone();
+    async fn set_item(
+        &mut self,
+        collection: &str,
+        exp_itm: &Item,
+        merge: bool,
+    ) -> StoreResult<u64> {
+        let res = self.store_item(collection, exp_itm, merge, None);
+        if res.is_err() && self.staged.is_some() {
+            self.tx_failed = true;
+        }
+        return res;
+    }
 
-        if itm.bools.contains_key("__security_preserve") {
-            itm.bools.remove("__security_preserve");
+    async fn set_item_rev(
+        &mut self,
+        collection: &str,
+        exp_itm: &Item,
+        merge: bool,
+        rev: u64,
+    ) -> StoreResult<u64> {
+        let res = self.store_item(collection, exp_itm, merge, Some(rev));
+        if res.is_err() && self.staged.is_some() {
+            self.tx_failed = true;
         }
+        return res;
+    }
 
-        if itm.id == u64::MAX {
-            let coll_id = self.collections[collection];
-            if self.items.contains_key(&coll_id) {
-                itm.id = self.items_count[&coll_id] + 1;
+    async fn del_item(&mut self, collection: &str, id: u64) -> StoreResult<bool> {
+        let res = self.remove_item(collection, id);
+        if res.is_err() && self.staged.is_some() {
+            self.tx_failed = true;
+        }
+        return res;
+    }
+
+    async fn begin_transaction(&mut self) -> StoreResult<()> {
+        if self.staged.is_some() {
+            return Err(StoreError::Transaction("already in progress".to_string()));
+        }
+        // Finish or drop leftovers of the previous transaction first
+        self.recover_staging()?;
+        fs::create_dir_all(self.staging_path())?;
+        sync_dir(&self.path);
+        self.staged = Some(HashMap::new());
+        self.staged_count = 0;
+        self.staged_history = Vec::new();
+        self.tx_failed = false;
+        return Ok(());
+    }
+
+    async fn commit_transaction(&mut self) -> StoreResult<()> {
+        if self.staged.is_none() {
+            return Err(StoreError::Transaction("not started".to_string()));
+        }
+        if self.tx_failed {
+            self.abort_transaction().await?;
+            return Err(StoreError::Transaction(
+                "rolled back after failed write".to_string(),
+            ));
+        }
+
+        // Once the marker is there, the transaction is committed: staged
+        // writes are replayed on startup if applying them fails now.
+        let marker = self.staging_path() + "/commit";
+        if let Err(e) = write_atomic(&marker, b"") {
+            self.abort_transaction().await?;
+            return Err(e.into());
+        }
+
+        let staged = self.staged.take().unwrap_or_default();
+        for ((collection, id), text) in staged {
+            if let Err(e) = self.apply_staged(&collection, id, text) {
+                error!(
+                    "Failed to apply {} item {}, will retry on restart: {}",
+                    collection, id, e
+                );
+                return Err(e);
+            }
+        }
+        for entry in std::mem::take(&mut self.staged_history) {
+            if let Err(e) = self.append_history(&entry) {
+                error!(
+                    "Failed to record history of {} item {}, will retry on restart: {}",
+                    entry.collection, entry.id, e
+                );
+                return Err(e);
             }
         }
+        fs::remove_dir_all(self.staging_path())?;
+        sync_dir(&self.path);
+        return Ok(());
+    }
 
-        let old_itm = self.get_item(collection, itm.id).await;
-        let mut new_itm = itm.clone();
-        if !old_itm.is_none() && merge {
-            new_itm = old_itm.unwrap().clone();
-            new_itm.merge(&itm);
+    async fn abort_transaction(&mut self) -> StoreResult<()> {
+        if self.staged.is_none() {
+            return Err(StoreError::Transaction("not started".to_string()));
+        }
+        self.staged = None;
+        self.staged_history = Vec::new();
+        self.tx_failed = false;
+        fs::remove_dir_all(self.staging_path())?;
+        sync_dir(&self.path);
+        return Ok(());
+    }
+
+    fn in_transaction(&self) -> bool {
+        return self.staged.is_some();
+    }
+
+    async fn add_history(&mut self, entry: &HistoryEntry) -> StoreResult<()> {
+        if !self.collections.contains_key(&entry.collection) {
+            return Err(StoreError::UnknownCollection(entry.collection.clone()));
         }
-        let tmp_path =
-            self.path.to_string() + "/collection/" + collection + "/" + &new_itm.id.to_string();
 
-        let _dir_create_err = std::fs::create_dir(&tmp_path);
+        if self.staged.is_some() {
+            return self.stage_history(entry);
+        }
+        return self.append_history(entry);
+    }
 
-        let tmp_data_path = tmp_path.clone() + "/data.js";
-        let s = serde_json::to_string(&new_itm);
-        std::fs::write(tmp_data_path, s.unwrap()).expect("Couldn't write item");
+    async fn get_history(&mut self, collection: &str, id: u64) -> StoreResult<Vec<HistoryEntry>> {
+        if !self.collections.contains_key(collection) {
+            return Err(StoreError::UnknownCollection(collection.to_string()));
+        }
 
-        let coll_id = self.collections[collection];
-        if self.items.contains_key(&coll_id) {
-            let coll = self.items.get_mut(&coll_id).unwrap();
-            if coll.contains_key(&new_itm.id) {
-                *(coll.get_mut(&new_itm.id).unwrap()) = true;
-            } else {
-                coll.insert(new_itm.id, true);
-            }
-            if self.items_count.contains_key(&coll_id) {
-                let cnt = self.items_count.get_mut(&coll_id).unwrap();
-                if new_itm.id > *cnt {
-                    *cnt = new_itm.id;
-                    let _res = std::fs::write(
-                        self.path.to_string() + "/collection/" + collection + "/cnt",
-                        (new_itm.id + 1).to_string(),
-                    );
+        let mut entries: Vec<HistoryEntry> = Vec::new();
+        let path = self.history_path(collection, id);
+        if !Path::new(&path).is_file() {
+            return Ok(entries);
+        }
+
+        let text = fs::read_to_string(&path)?;
+        for line in text.lines() {
+            match serde_json::from_str::<HistoryEntry>(line) {
+                Ok(e) => entries.push(e),
+                Err(e) => {
+                    error!("Skipping corrupt history line in {}: {}", path, e);
                 }
-            } else {
-                self.items_count.insert(coll_id, new_itm.id + 1);
-                let _res = std::fs::write(
-                    self.path.to_string() + "/collection/" + collection + "/cnt",
-                    (new_itm.id + 1).to_string(),
-                );
             }
         }
+        return Ok(entries);
+    }
+
+    async fn get_collection_history(&mut self, collection: &str) -> StoreResult<Vec<HistoryEntry>> {
+        if !self.collections.contains_key(collection) {
+            return Err(StoreError::UnknownCollection(collection.to_string()));
+        }
+
+        let mut entries: Vec<HistoryEntry> = Vec::new();
+        let history_dir = self.path.to_string() + "/history/" + collection;
+        if !Path::new(&history_dir).is_dir() {
+            return Ok(entries);
+        }
 
-        return new_itm.id;
+        let mut ids: Vec<u64> = Vec::new();
+        for file in fs::read_dir(&history_dir)?.flatten() {
+            let name = file.file_name().to_string_lossy().to_string();
+            match name.strip_suffix(".jsonl").map(|n| n.parse::<u64>()) {
+                Some(Ok(id)) => ids.push(id),
+                _ => {
+                    error!("Skipping unknown history file {}/{}", history_dir, name);
+                }
+            }
+        }
+        ids.sort();
+        for id in ids {
+            entries.extend(self.get_history(collection, id).await?);
+        }
+        return Ok(entries);
     }
 
-    async fn del_item(&mut self, collection: &str, id: u64) -> bool {
-        let tmp_path = self.path.to_string() + "/" + collection + "/" + &id.to_string();
-        let path = Path::new(&tmp_path);
-        if path.exists() {
-            let _res = std::fs::remove_dir_all(tmp_path);
+    async fn add_trash(&mut self, entry: &TrashEntry) -> StoreResult<()> {
+        if !self.collections.contains_key(&entry.collection) {
+            return Err(StoreError::UnknownCollection(entry.collection.clone()));
         }
-        let coll_id = self.collections[collection];
-        if self.items.contains_key(&coll_id) {
-            let coll = self.items.get_mut(&coll_id).unwrap();
-            if coll.contains_key(&id) {
-                coll.remove(&id);
-                return true;
+
+        let trash_dir = self.path.to_string() + "/trash/" + &entry.collection;
+        fs::create_dir_all(&trash_dir)?;
+        let text = serde_json::to_string(entry)?;
+        write_atomic(
+            &self.trash_path(&entry.collection, entry.id),
+            text.as_bytes(),
+        )?;
+        return Ok(());
+    }
+
+    async fn get_trash(&mut self, collection: &str) -> StoreResult<Vec<TrashEntry>> {
+        if !self.collections.contains_key(collection) {
+            return Err(StoreError::UnknownCollection(collection.to_string()));
+        }
+
+        let mut entries: Vec<TrashEntry> = Vec::new();
+        let trash_dir = self.path.to_string() + "/trash/" + collection;
+        if !Path::new(&trash_dir).is_dir() {
+            return Ok(entries);
+        }
+
+        for file in fs::read_dir(&trash_dir)?.flatten() {
+            let name = file.file_name().to_string_lossy().to_string();
+            if !name.ends_with(".js") {
+                continue;
+            }
+            let text = fs::read_to_string(file.path())?;
+            match serde_json::from_str::<TrashEntry>(&text) {
+                Ok(e) => entries.push(e),
+                Err(e) => {
+                    error!("Skipping corrupt trash item {}/{}: {}", trash_dir, name, e);
+                }
+            }
+        }
+        entries.sort_by(|a, b| a.id.cmp(&b.id));
+        return Ok(entries);
+    }
+
+    async fn del_trash(&mut self, collection: &str, id: u64) -> StoreResult<bool> {
+        if !self.collections.contains_key(collection) {
+            return Err(StoreError::UnknownCollection(collection.to_string()));
+        }
+
+        let path = self.trash_path(collection, id);
+        if !Path::new(&path).is_file() {
+            return Ok(false);
+        }
+        fs::remove_file(&path)?;
+        sync_dir(&(self.path.to_string() + "/trash/" + collection));
+        return Ok(true);
+    }
+
+    async fn purge_trash(&mut self, before: u64) -> StoreResult<u64> {
+        let mut count = 0;
+        let collections: Vec<String> = self.collections.keys().cloned().collect();
+        for collection in collections {
+            for entry in self.get_trash(&collection).await? {
+                if entry.time < before && self.del_trash(&collection, entry.id).await? {
+                    count += 1;
+                }
             }
         }
-        return false;
+        return Ok(count);
+    }
+
+    async fn add_migration(&mut self, entry: &MigrationEntry) -> StoreResult<()> {
+        let line = serde_json::to_string(entry)? + "\n";
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path.to_string() + "/migrations.jsonl")?;
+        file.write_all(line.as_bytes())?;
+        file.sync_all()?;
+        return Ok(());
+    }
+
+    async fn get_migrations(&mut self) -> StoreResult<Vec<MigrationEntry>> {
+        let mut entries: Vec<MigrationEntry> = Vec::new();
+        let path = self.path.to_string() + "/migrations.jsonl";
+        if !Path::new(&path).is_file() {
+            return Ok(entries);
+        }
+
+        let text = fs::read_to_string(&path)?;
+        for line in text.lines() {
+            match serde_json::from_str::<MigrationEntry>(line) {
+                Ok(e) => entries.push(e),
+                Err(e) => {
+                    error!("Skipping corrupt migration line in {}: {}", path, e);
+                }
+            }
+        }
+        entries.sort_by_key(|e| e.version);
+        return Ok(entries);
     }
 
     async fn get_credentials(&mut self) -> String {
@@ -306,30 +1129,59 @@ impl Store for StoreLocal {
     async fn get_internals(&mut self) -> Item {
         let tmp_data_path = self.path.clone() + "/internals.js";
 
-        let read_data = std::fs::read_to_string(tmp_data_path);
+        let read_data = std::fs::read_to_string(&tmp_data_path);
         if let Err(_e) = read_data {
             return Item::new();
         }
         let text = read_data.unwrap();
-        let itm: Item = serde_json::from_str(&text).unwrap();
-        return itm;
+        match serde_json::from_str(&text) {
+            Ok(itm) => {
+                return itm;
+            }
+            Err(e) => {
+                error!("Failed to parse {}: {}", tmp_data_path, e);
+                return Item::new();
+            }
+        }
     }
 
     async fn get_settings(&mut self) -> Item {
         let tmp_data_path = self.path.clone() + "/settings.js";
 
-        let read_data = std::fs::read_to_string(tmp_data_path);
+        let read_data = std::fs::read_to_string(&tmp_data_path);
         if let Err(_e) = read_data {
             return Item::new();
         }
         let text = read_data.unwrap();
-        let itm: Item = serde_json::from_str(&text).unwrap();
-        return itm;
+        match serde_json::from_str(&text) {
+            Ok(itm) => {
+                return itm;
+            }
+            Err(e) => {
+                error!("Failed to parse {}: {}", tmp_data_path, e);
+                return Item::new();
+            }
+        }
     }
 
-    async fn set_settings(&mut self, itm: Item) {
+    async fn set_internals(&mut self, itm: Item) -> StoreResult<()> {
+        let tmp_data_path = self.path.clone() + "/internals.js";
+        let s = serde_json::to_string(&itm)?;
+        write_atomic(&tmp_data_path, s.as_bytes())?;
+        return Ok(());
+    }
+
+    async fn set_settings(&mut self, itm: Item) -> StoreResult<()> {
         let tmp_data_path = self.path.clone() + "/settings.js";
-        let s = serde_json::to_string(&itm);
-        std::fs::write(tmp_data_path, s.unwrap()).expect("Couldn't write item");
+        let s = serde_json::to_string(&itm)?;
+        write_atomic(&tmp_data_path, s.as_bytes())?;
+        return Ok(());
+    }
+
+    async fn reload_internals(&mut self) -> StoreResult<()> {
+        self.register_collections().await?;
+        let internals = self.get_internals().await;
+        self.build_indexes(declared_indexes(&internals));
+        return Ok(());
     }
 }
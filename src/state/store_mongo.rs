@@ -21,24 +21,52 @@
  * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
  * DEALINGS IN THE SOFTWARE.
  */
-use bson::Document;
+use bson::{Bson, Document};
 use futures_util::TryStreamExt;
 use isabelle_dm::data_model::list_result::ListResult;
 extern crate serde_json;
 
-use crate::state::store::Store;
+use crate::state::filter::Filter;
+use crate::state::store::{
+    bump_revision, declared_indexes, item_revision, parse_sort, project_item, AggregateGroup,
+    AggregateOp, AggregateSpec, HistoryEntry, ListCursor, MigrationEntry, OrderedList, SortSpec,
+    Store, StoreError, StoreResult, TrashEntry, REVISION_KEY,
+};
+use crate::util::file::write_atomic;
 use async_trait::async_trait;
 use isabelle_dm::data_model::item::*;
-use log::{debug, info, trace};
+use log::{debug, error, info, warn};
 use serde_json::Value;
 
-// use mongodb::{bson::doc, Client, Collection, IndexModel};
-use mongodb::{bson::doc, Client, Collection};
+use mongodb::options::{Collation, CollationStrength, IndexOptions, ReturnDocument};
+use mongodb::{bson::doc, Client, ClientSession, Collection, IndexModel};
 use std::collections::HashMap;
 use tokio::time::{sleep, Duration};
 
+/// Collection keeping ID counters, one document per collection
+const COUNTERS_COLLECTION: &str = "__counters";
+
+/// Collection keeping previous versions of items
+const HISTORY_COLLECTION: &str = "__history";
+
+/// Collection keeping removed items until they are restored or purged
+const TRASH_COLLECTION: &str = "__trash";
+
+/// Collection holding applied migrations
+const MIGRATION_COLLECTION: &str = "__migrations";
+
+/// Run Mongo action within the current transaction, if there is one
+macro_rules! in_session {
+    ($session:expr, $action:expr) => {
+        match $session.as_mut() {
+            Some(s) => $action.session(s).await,
+            None => $action.await,
+        }
+    };
+}
+
 /// Mongo storage implementation
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct StoreMongo {
     /// URL to Mongo database
     pub path: String,
@@ -60,12 +88,26 @@ pub struct StoreMongo {
 
     /// Database name
     pub database_name: String,
+
+    /// Session of the current transaction
+    pub session: Option<ClientSession>,
+
+    /// Some write inside the current transaction failed
+    pub tx_failed: bool,
+
+    /// Item map changes done by the current transaction: collection index,
+    /// item ID and whether the item was there before
+    pub tx_undo: Vec<(u64, u64, bool)>,
+
+    /// Fail batches if transactions aren't available, as on standalone
+    /// servers, instead of writing them item by item. Set by
+    /// `atomic_batches` of internals.
+    pub atomic_batches: bool,
 }
 
 unsafe impl Send for StoreMongo {}
 
 impl StoreMongo {
-    #[cfg(not(feature = "full_file_database"))]
     pub fn new() -> Self {
         Self {
             path: "".to_string(),
@@ -75,6 +117,10 @@ impl StoreMongo {
             items_count: HashMap::new(),
             client: None,
             database_name: "isabelle".to_string(),
+            session: None,
+            tx_failed: false,
+            tx_undo: Vec::new(),
+            atomic_batches: false,
         }
     }
 
@@ -121,36 +167,488 @@ impl StoreMongo {
         return true;
     }
 
-    pub async fn json_to_bson(&mut self, json_string: &str) -> Result<Document, bool> {
-        // Parse JSON string into serde_json::Value
-        let js_res = serde_json::from_str(json_string);
-        let js: Value;
-        match js_res {
-            Ok(tmp) => {
-                js = tmp;
+    /// Convert numeric BSON value to ID
+    fn bson_to_id(val: Option<&Bson>) -> Option<u64> {
+        match val {
+            Some(Bson::Int64(v)) if *v >= 0 => Some(*v as u64),
+            Some(Bson::Int32(v)) if *v >= 0 => Some(*v as u64),
+            Some(Bson::Double(v)) if *v >= 0.0 => Some(*v as u64),
+            _ => None,
+        }
+    }
+
+    /// Translate the filter to Mongo query
+    fn filter_to_bson(filter: &Filter) -> StoreResult<Document> {
+        return bson::to_document(&filter.to_json())
+            .map_err(|e| StoreError::Serialization(e.to_string()));
+    }
+
+    /// Projection selecting the fields along with ID and sort fields, which
+    /// cursors need. Returns None if all fields are needed.
+    fn projection(fields: &[String], specs: &[SortSpec]) -> Option<Document> {
+        if fields.is_empty() {
+            return None;
+        }
+        let mut paths: Vec<&str> = vec!["id"];
+        paths.extend(fields.iter().map(|f| f.as_str()));
+        paths.extend(specs.iter().map(|s| s.field.as_str()));
+
+        let mut proj = Document::new();
+        for path in &paths {
+            // Mongo rejects a path together with its parent
+            let covered = paths.iter().any(|p| path.starts_with(&format!("{}.", p)));
+            if !covered {
+                proj.insert(*path, 1);
+            }
+        }
+        return Some(proj);
+    }
+
+    /// Read the item from the document, which may lack whole field kinds
+    /// due to projection
+    fn item_from_doc(mut doc: Document) -> StoreResult<Item> {
+        for kind in ["strs", "bools", "u64s", "strstrs"] {
+            if !doc.contains_key(kind) {
+                doc.insert(kind, Document::new());
+            }
+        }
+        return bson::from_document(doc).map_err(|e| StoreError::Serialization(e.to_string()));
+    }
+
+    /// Join conditions with `$and`, skipping it for a single one
+    fn and_filter(conds: &[Document]) -> Document {
+        match conds.len() {
+            0 => Document::new(),
+            1 => conds[0].clone(),
+            _ => doc! { "$and": conds.to_vec() },
+        }
+    }
+
+    /// Condition selecting items after the cursor in sort order. Null goes
+    /// first in ascending order, the same as missing fields.
+    fn keyset_filter(specs: &[SortSpec], cursor: &ListCursor) -> StoreResult<Document> {
+        let mut branches: Vec<Document> = Vec::new();
+        let mut equal: Vec<Document> = Vec::new();
+        for (i, spec) in specs.iter().enumerate() {
+            let val = cursor.values.get(i).cloned().unwrap_or(Value::Null);
+            let val = bson::to_bson(&val).map_err(|e| StoreError::Serialization(e.to_string()))?;
+            let field = spec.field.as_str();
+            let after = match (spec.descending, val == Bson::Null) {
+                (false, true) => Some(doc! { field: { "$ne": Bson::Null } }),
+                (false, false) => Some(doc! { field: { "$gt": val.clone() } }),
+                (true, true) => None,
+                (true, false) => Some(doc! {
+                    "$or": [ { field: { "$lt": val.clone() } }, { field: Bson::Null } ]
+                }),
+            };
+            if let Some(after) = after {
+                let mut conds = equal.clone();
+                conds.push(after);
+                branches.push(Self::and_filter(&conds));
+            }
+            equal.push(doc! { field: val });
+        }
+        equal.push(doc! { "id": { "$gt": cursor.id.min(i64::MAX as u64) as i64 } });
+        branches.push(Self::and_filter(&equal));
+        return Ok(doc! { "$or": branches });
+    }
+
+    /// Get Mongo collection by name
+    fn collection<T: Send + Sync>(&self, collection: &str) -> StoreResult<Collection<T>> {
+        match self.client.as_ref() {
+            Some(cl) => {
+                return Ok(cl.database(&self.database_name).collection(collection));
+            }
+            None => {
+                return Err(StoreError::NotConnected);
+            }
+        }
+    }
+
+    /// Get collection keeping ID counters
+    fn counters(&self) -> StoreResult<Collection<Document>> {
+        return self.collection(COUNTERS_COLLECTION);
+    }
+
+    /// Get index of known collection
+    fn collection_index(&self, collection: &str) -> StoreResult<u64> {
+        match self.collections.get(collection) {
+            Some(idx) => {
+                return Ok(*idx);
+            }
+            None => {
+                return Err(StoreError::UnknownCollection(collection.to_string()));
+            }
+        }
+    }
+
+    /// Load IDs of all existing items and the maximum ID of collection
+    async fn load_item_ids(&mut self, collection: &str) -> (HashMap<u64, bool>, u64) {
+        let mut ids: HashMap<u64, bool> = HashMap::new();
+        let mut max_id = 0;
+        let coll: Collection<Document> = match self.collection(collection) {
+            Ok(c) => c,
+            Err(e) => {
+                error!("Failed to load IDs of {}: {}", collection, e);
+                return (ids, max_id);
+            }
+        };
+
+        let cursor = coll
+            .find(doc! {})
+            .projection(doc! { "id": 1, "_id": 0 })
+            .await;
+        match cursor {
+            Ok(mut c) => loop {
+                match c.try_next().await {
+                    Ok(Some(d)) => {
+                        if let Some(id) = Self::bson_to_id(d.get("id")) {
+                            ids.insert(id, true);
+                            if id > max_id {
+                                max_id = id;
+                            }
+                        }
+                    }
+                    Ok(None) => {
+                        break;
+                    }
+                    Err(e) => {
+                        error!("Failed to load IDs of {}: {}", collection, e);
+                        break;
+                    }
+                }
+            },
+            Err(e) => {
+                error!("Failed to load IDs of {}: {}", collection, e);
+            }
+        }
+
+        return (ids, max_id);
+    }
+
+    /// Filter matching the item only while it has the given revision
+    fn rev_filter(id: u64, rev: u64) -> Document {
+        let rev_key = "u64s.".to_string() + REVISION_KEY;
+        if rev == 0 {
+            return doc! { "id": id as i64, rev_key: { "$in": [Bson::Null, 0_i64] } };
+        }
+        return doc! { "id": id as i64, rev_key: rev as i64 };
+    }
+
+    /// Check if the ID is above anything the counter is known to cover:
+    /// IDs this instance allocated or saw stored
+    fn beyond_counter(&self, coll_id: u64, id: u64) -> bool {
+        return id > self.items_count.get(&coll_id).cloned().unwrap_or(0);
+    }
+
+    /// Make sure the stored counter is not behind the given ID
+    async fn raise_counter(&mut self, collection: &str, id: u64) -> StoreResult<()> {
+        self.counters()?
+            .update_one(
+                doc! { "_id": collection },
+                doc! { "$max": { "value": id as i64 } },
+            )
+            .upsert(true)
+            .await?;
+        return Ok(());
+    }
+
+    /// Atomically allocate `count` new IDs and return the last one. Several
+    /// instances working with the same database never get the same ID.
+    /// Counters are kept outside of transactions, so aborted transaction
+    /// leaves a gap instead of conflicting with concurrent ones.
+    async fn allocate_ids(&mut self, collection: &str, count: u64) -> StoreResult<u64> {
+        let res = self
+            .counters()?
+            .find_one_and_update(
+                doc! { "_id": collection },
+                doc! { "$inc": { "value": count as i64 } },
+            )
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .await?;
+        match res.as_ref().and_then(|d| Self::bson_to_id(d.get("value"))) {
+            Some(id) => {
+                return Ok(id);
+            }
+            None => {
+                return Err(StoreError::Backend(format!(
+                    "no counter for {}",
+                    collection
+                )));
+            }
+        }
+    }
+
+    /// Mark item as present in the item map
+    fn index_add(&mut self, coll_id: u64, id: u64) {
+        let coll = self.items.entry(coll_id).or_default();
+        if self.session.is_some() {
+            self.tx_undo.push((coll_id, id, coll.contains_key(&id)));
+        }
+        coll.insert(id, true);
+
+        let cnt = self.items_count.entry(coll_id).or_insert(0);
+        if id > *cnt {
+            *cnt = id;
+        }
+    }
+
+    /// Remove item from the item map
+    fn index_remove(&mut self, coll_id: u64, id: u64) {
+        if let Some(coll) = self.items.get_mut(&coll_id) {
+            let existed = coll.remove(&id).is_some();
+            if self.session.is_some() {
+                self.tx_undo.push((coll_id, id, existed));
             }
-            Err(_error) => {
-                return Err(false);
+        }
+    }
+
+    /// Revert item map changes done by the aborted transaction
+    fn index_undo(&mut self) {
+        while let Some((coll_id, id, existed)) = self.tx_undo.pop() {
+            if let Some(coll) = self.items.get_mut(&coll_id) {
+                if existed {
+                    coll.insert(id, true);
+                } else {
+                    coll.remove(&id);
+                }
+            }
+        }
+    }
+
+    /// Remember failed write, so that the current transaction is not
+    /// committed partially
+    fn note_result<T>(&mut self, res: &StoreResult<T>) {
+        if res.is_err() && self.session.is_some() {
+            self.tx_failed = true;
+        }
+    }
+
+    /// Find items by filter within the current transaction
+    async fn find_items(
+        &mut self,
+        coll: &Collection<Item>,
+        filter: Document,
+    ) -> StoreResult<HashMap<u64, Item>> {
+        let mut found: HashMap<u64, Item> = HashMap::new();
+        match self.session.as_mut() {
+            Some(s) => {
+                let mut cursor = coll.find(filter).session(&mut *s).await?;
+                while let Some(itm) = cursor.next(s).await {
+                    let itm = itm?;
+                    found.insert(itm.id, itm);
+                }
+            }
+            None => {
+                let mut cursor = coll.find(filter).await?;
+                while let Some(itm) = cursor.try_next().await? {
+                    found.insert(itm.id, itm);
+                }
+            }
+        }
+        return Ok(found);
+    }
+
+    /// Prepare and write the item
+    async fn store_item(
+        &mut self,
+        collection: &str,
+        exp_itm: &Item,
+        merge: bool,
+        rev: Option<u64>,
+    ) -> StoreResult<u64> {
+        let coll_id = self.collection_index(collection)?;
+        let mut itm = exp_itm.clone();
+        if itm.bools.contains_key("__security_preserve") {
+            itm.bools.remove("__security_preserve");
+        }
+
+        if itm.id == u64::MAX {
+            itm.id = self.allocate_ids(collection, 1).await?;
+        } else if self.beyond_counter(coll_id, itm.id) {
+            self.raise_counter(collection, itm.id).await?;
+        }
+
+        let coll: Collection<Item> = self.collection(collection)?;
+        let filter = doc! {
+            "id": itm.id as i64,
+        };
+
+        let old_itm = in_session!(self.session, coll.find_one(filter))?;
+        let mut new_itm = itm.clone();
+        if !old_itm.as_ref().is_none() && merge {
+            new_itm = old_itm.as_ref().unwrap().clone();
+            new_itm.merge(&itm);
+        }
+        bump_revision(old_itm.as_ref(), &mut new_itm, rev)?;
+
+        if old_itm.as_ref().is_none() {
+            if let Err(e) = in_session!(self.session, coll.insert_one(new_itm.clone())) {
+                error!(
+                    "MongoDB insert_one failed for {} id {}: {}",
+                    collection, new_itm.id, e
+                );
+                return Err(e.into());
+            }
+            debug!("Inserted {} id {} to MongoDB", collection, new_itm.id);
+        } else {
+            // Replace only the revision we've read, so that concurrent
+            // writer isn't overwritten silently
+            let rev_filter = Self::rev_filter(new_itm.id, item_revision(old_itm.as_ref()));
+
+            match in_session!(self.session, coll.replace_one(rev_filter, new_itm.clone())) {
+                Ok(res) => {
+                    if res.matched_count == 0 {
+                        return Err(StoreError::Conflict(format!(
+                            "item {} was changed concurrently",
+                            new_itm.id
+                        )));
+                    }
+                }
+                Err(e) => {
+                    error!(
+                        "MongoDB replace_one failed for {} id {}: {}",
+                        collection, new_itm.id, e
+                    );
+                    return Err(e.into());
+                }
             }
+            debug!("Replaced {} id {} in MongoDB", collection, new_itm.id);
         }
 
-        // Convert serde_json::Value into BSON Document
-        let bs_res = bson::ser::to_document(&js);
+        self.index_add(coll_id, new_itm.id);
+        return Ok(new_itm.id);
+    }
+
+    /// Remove the item
+    async fn remove_item(&mut self, collection: &str, id: u64) -> StoreResult<bool> {
+        let coll_id = self.collection_index(collection)?;
+        let coll: Collection<Item> = self.collection(collection)?;
+        let filter = doc! {
+            "id": id as i64,
+        };
+
+        let res = in_session!(self.session, coll.delete_one(filter))?;
 
-        match bs_res {
-            Ok(tmp) => {
-                return Ok(tmp);
+        self.index_remove(coll_id, id);
+        return Ok(res.deleted_count > 0);
+    }
+
+    /// Write the batch with a few bulk operations. Must run inside
+    /// transaction, since existing items are removed and inserted again.
+    async fn store_items(
+        &mut self,
+        collection: &str,
+        itms: &[Item],
+        merge: bool,
+    ) -> StoreResult<Vec<u64>> {
+        let coll_id = self.collection_index(collection)?;
+
+        // Assign IDs to new items with one counter update
+        let new_count = itms.iter().filter(|i| i.id == u64::MAX).count() as u64;
+        let mut next_id = if new_count > 0 {
+            self.allocate_ids(collection, new_count).await? + 1 - new_count
+        } else {
+            0
+        };
+
+        let mut ids: Vec<u64> = Vec::new();
+        let mut order: Vec<u64> = Vec::new();
+        let mut batch: HashMap<u64, Item> = HashMap::new();
+        let mut max_id = 0;
+        for exp_itm in itms {
+            let mut itm = exp_itm.clone();
+            itm.bools.remove("__security_preserve");
+            if itm.id == u64::MAX {
+                itm.id = next_id;
+                next_id += 1;
+            } else if itm.id > max_id {
+                max_id = itm.id;
             }
-            Err(_error) => {
-                return Err(false);
+            ids.push(itm.id);
+
+            // The same item may come several times, the last one wins
+            match batch.get_mut(&itm.id) {
+                Some(prev) => {
+                    if merge {
+                        prev.merge(&itm);
+                    } else {
+                        *prev = itm;
+                    }
+                }
+                None => {
+                    order.push(itm.id);
+                    batch.insert(itm.id, itm);
+                }
+            }
+        }
+        if self.beyond_counter(coll_id, max_id) {
+            self.raise_counter(collection, max_id).await?;
+        }
+
+        let coll: Collection<Item> = self.collection(collection)?;
+        let id_list: Vec<i64> = order.iter().map(|id| *id as i64).collect();
+        let filter = doc! { "id": { "$in": id_list } };
+
+        let old = self.find_items(&coll, filter).await?;
+        for (id, itm) in batch.iter_mut() {
+            let old_itm = old.get(id);
+            if merge {
+                if let Some(o) = old_itm {
+                    let mut new_itm = o.clone();
+                    new_itm.merge(itm);
+                    *itm = new_itm;
+                }
+            }
+            bump_revision(old_itm, itm, None)?;
+        }
+
+        // Remove only the revisions we've read, like single writes do.
+        // Unique indexes are checked when items are inserted back.
+        if !old.is_empty() {
+            let conds: Vec<Document> = old
+                .values()
+                .map(|o| Self::rev_filter(o.id, item_revision(Some(o))))
+                .collect();
+            let res = in_session!(self.session, coll.delete_many(doc! { "$or": conds }))?;
+            if res.deleted_count != old.len() as u64 {
+                return Err(StoreError::Conflict(format!(
+                    "items of {} were changed concurrently",
+                    collection
+                )));
             }
         }
+        let new_itms: Vec<Item> = order.iter().filter_map(|id| batch.remove(id)).collect();
+        in_session!(self.session, coll.insert_many(new_itms))?;
+
+        for id in &order {
+            self.index_add(coll_id, *id);
+        }
+        return Ok(ids);
+    }
+
+    /// Remove the batch with one bulk operation
+    async fn remove_items(&mut self, collection: &str, ids: &[u64]) -> StoreResult<u64> {
+        let coll_id = self.collection_index(collection)?;
+        let coll: Collection<Item> = self.collection(collection)?;
+        let id_list: Vec<i64> = ids.iter().map(|id| *id as i64).collect();
+
+        let res = in_session!(
+            self.session,
+            coll.delete_many(doc! { "id": { "$in": id_list } })
+        )?;
+
+        for id in ids {
+            self.index_remove(coll_id, *id);
+        }
+        return Ok(res.deleted_count);
     }
 }
 
 #[async_trait]
 impl Store for StoreMongo {
-    async fn connect(&mut self, url: &str, alturl: &str) {
+    async fn connect(&mut self, url: &str, alturl: &str) -> StoreResult<()> {
         // Preserve parameters
         self.path = url.to_string();
         self.local_path = alturl.to_string();
@@ -160,53 +658,36 @@ impl Store for StoreMongo {
         if res {
             // If successful, create all collections
             info!("Connected {} / {}!", url, self.database_name);
-            let internals = self.get_internals().await;
-            let collections = internals.safe_strstr("collections", &HashMap::new());
-            debug!("Collections: {}", collections.len());
-            // let db = self.client.as_ref().unwrap().database(&self.database_name);
-            for coll_name in collections {
-                info!("Registering collection: {}", &coll_name.1);
-
-                // Don't create collection explicitly - MongoDB will create it lazily
-                // when first document is inserted during merge_database
-                
-                let coll_idx = self.collections.len().try_into().unwrap();
-                self.collections.insert(coll_name.1.to_string(), coll_idx);
-                
-                // Initialize empty item tracking - will be populated during merge
-                self.items.insert(coll_idx, HashMap::new());
-                self.items_count.insert(coll_idx, 0);
-                
-                info!("Collection {} registered", &coll_name.1);
-            }
+            return self.reload_internals().await;
         } else {
             info!("Not connected");
+            return Err(StoreError::NotConnected);
         }
     }
 
     async fn disconnect(&mut self) {}
 
+    fn has_collection(&self, collection: &str) -> bool {
+        return self.collections.contains_key(collection);
+    }
+
     async fn get_collections(&mut self) -> Vec<String> {
         // Return collections already registered during connect()
         // This avoids querying MongoDB which may fail or hang
         self.collections.keys().map(|k| k.clone()).collect()
     }
 
-    async fn get_item_ids(&mut self, collection: &str) -> HashMap<u64, bool> {
-        if !self.collections.contains_key(collection) {
-            return HashMap::new();
-        }
-
-        let coll_id = self.collections[collection];
-        return self.items[&coll_id].clone();
+    async fn get_item_ids(&mut self, collection: &str) -> StoreResult<HashMap<u64, bool>> {
+        let coll_id = self.collection_index(collection)?;
+        return Ok(self.items.get(&coll_id).cloned().unwrap_or_default());
     }
 
     async fn get_all_items(
         &mut self,
         collection: &str,
         sort_key: &str,
-        filter: &str,
-    ) -> ListResult {
+        filter: &Filter,
+    ) -> StoreResult<ListResult> {
         return self
             .get_items(
                 collection,
@@ -220,55 +701,32 @@ impl Store for StoreMongo {
             .await;
     }
 
-    async fn get_item(&mut self, collection: &str, id: u64) -> Option<Item> {
-        let coll = self
-            .client
-            .as_ref()
-            .unwrap()
-            .database(&self.database_name)
-            .collection(collection);
+    async fn get_item(&mut self, collection: &str, id: u64) -> StoreResult<Option<Item>> {
+        let coll: Collection<Item> = self.collection(collection)?;
         let filter = doc! {
             "id": id as i64,
         };
 
-        let result = coll.find_one(filter).await;
-
-        match result {
-            Ok(r) => {
-                if r.is_none() {
-                    return None;
-                }
-                return Some(r.unwrap());
-            }
-            Err(_e) => {}
-        };
-        return None;
+        return Ok(in_session!(self.session, coll.find_one(filter))?);
     }
 
-    async fn get_items(
+    async fn get_items_ordered(
         &mut self,
         collection: &str,
         id_min: u64,
         id_max: u64,
         sort_key: &str,
-        filter: &str,
+        filter: &Filter,
+        cursor: Option<&ListCursor>,
+        fields: &[String],
         skip: u64,
         limit: u64,
-    ) -> ListResult {
-        let mut lr = ListResult {
-            map: HashMap::new(),
-            total_count: 0,
-        };
-        let itms = self
-            .items
-            .get_mut(&self.collections[collection])
-            .unwrap()
-            .clone();
+    ) -> StoreResult<OrderedList> {
+        let mut ol = OrderedList::new();
+        self.collection_index(collection)?;
         let mut eff_id_min = id_min;
         let eff_id_max = id_max;
-        let mut count = 0;
         let mut eff_skip = skip;
-        let mut care_about_sort = false;
         let eff_limit: i64;
 
         if eff_skip == u64::MAX {
@@ -277,9 +735,6 @@ impl Store for StoreMongo {
 
         if eff_id_min == u64::MAX {
             eff_id_min = 0;
-            if sort_key != "" {
-                care_about_sort = true;
-            }
         }
 
         if limit > (i64::MAX as u64) {
@@ -289,7 +744,7 @@ impl Store for StoreMongo {
         }
 
         debug!(
-            "Getting {} in range {} - {} ({}-{}) skip {} limit {} sort key {} (care {}) filter {}",
+            "Getting {} in range {} - {} ({}-{}) skip {} limit {} sort key {} filter {}",
             &collection,
             eff_id_min,
             eff_id_max,
@@ -298,179 +753,417 @@ impl Store for StoreMongo {
             eff_skip,
             eff_limit,
             sort_key,
-            care_about_sort,
             filter
         );
-        if care_about_sort {
-            let coll: Collection<Item> = self
-                .client
-                .as_ref()
-                .unwrap()
-                .database(&self.database_name)
-                .collection(collection);
-
-            let json_bson: Document = if filter != "" {
-                debug!("Using real filter: {}", filter);
-                let bson_document = self.json_to_bson(filter).await;
-                match bson_document {
-                    Ok(d) => d,
-                    Err(_err) => {
-                        trace!("Using empty filter due to error");
-                        Document::new()
-                    }
+        let coll: Collection<Item> = self.collection(collection)?;
+
+        let json_bson = Self::filter_to_bson(filter)?;
+
+        let mut conds: Vec<Document> = Vec::new();
+        if !json_bson.is_empty() {
+            conds.push(json_bson);
+        }
+        if eff_id_min > 0 || eff_id_max < (i64::MAX as u64) {
+            conds.push(doc! {
+                "id": {
+                    "$gte": eff_id_min.min(i64::MAX as u64) as i64,
+                    "$lte": eff_id_max.min(i64::MAX as u64) as i64,
                 }
-            } else {
-                trace!("Using empty filter");
-                Document::new()
-            };
+            });
+        }
+        ol.list.total_count = coll.count_documents(Self::and_filter(&conds)).await?;
 
-            let count = coll.count_documents(json_bson.clone()).await;
-            lr.total_count = count.unwrap_or(0);
+        let specs = parse_sort(sort_key);
+        if let Some(c) = cursor {
+            conds.push(Self::keyset_filter(&specs, c)?);
+        }
 
-            let mut cursor = coll
-                .find(json_bson)
-                .sort(doc! { sort_key: 1 })
-                .skip(eff_skip)
-                .limit(eff_limit)
-                .await;
-            loop {
-                let result = cursor.as_mut().unwrap().try_next().await;
-                match result {
-                    Ok(r) => {
-                        let c = r.clone();
-                        if c.is_none() {
-                            break;
-                        }
+        // Equal sort keys go in the order of IDs, so that pages are stable
+        let mut sort = Document::new();
+        for spec in &specs {
+            sort.insert(spec.field.clone(), if spec.descending { -1 } else { 1 });
+        }
+        if !sort.contains_key("id") {
+            sort.insert("id", 1);
+        }
 
-                        lr.map
-                            .insert(c.as_ref().unwrap().id, c.as_ref().unwrap().clone());
-                    }
-                    Err(_e) => {
-                        debug!("Error: {}", _e);
-                        break;
-                    }
-                };
+        // One more item tells whether the next page exists
+        let docs: Collection<Document> = self.collection(collection)?;
+        let mut find = docs
+            .find(Self::and_filter(&conds))
+            .sort(sort)
+            .skip(eff_skip)
+            .limit(eff_limit.saturating_add(1));
+        if let Some(proj) = Self::projection(fields, &specs) {
+            find = find.projection(proj);
+        }
+        let mut found = find.await?;
+        let mut more = false;
+        while let Some(doc) = found.try_next().await? {
+            if ol.order.len() as i64 >= eff_limit {
+                more = true;
+                break;
             }
-        } else {
-            for itm in &itms {
-                if itm.0 >= &eff_id_min && itm.0 <= &eff_id_max {
-                    let new_item = self.get_item(collection, *itm.0).await;
-                    if !new_item.is_none() {
-                        if count >= eff_skip {
-                            lr.map.insert(*itm.0, new_item.unwrap());
-                        }
-                        count = count + 1;
-                        if count >= eff_skip && (count - eff_skip) >= eff_limit as u64 {
-                            break;
-                        }
-                    }
-                }
+            ol.push(Self::item_from_doc(doc)?);
+        }
+        if more {
+            if let Some(last) = ol.order.last().and_then(|id| ol.list.map.get(id)) {
+                ol.next_cursor = ListCursor::after_item(sort_key, last).encode();
             }
-
-            lr.total_count = itms.len() as u64;
+        }
+        for itm in ol.list.map.values_mut() {
+            project_item(itm, fields);
         }
 
         debug!(
             " - result: {} items, total {}",
-            lr.map.len(),
-            lr.total_count
+            ol.order.len(),
+            ol.list.total_count
         );
-        return lr;
+        return Ok(ol);
     }
 
-    async fn set_item(&mut self, collection: &str, exp_itm: &Item, merge: bool) -> u64 {
-        let mut itm = exp_itm.clone();
-        if itm.bools.contains_key("__security_preserve") {
-            itm.bools.remove("__security_preserve");
+    async fn aggregate(
+        &mut self,
+        collection: &str,
+        filter: &Filter,
+        group_by: &str,
+        specs: &[AggregateSpec],
+    ) -> StoreResult<Vec<AggregateGroup>> {
+        self.collection_index(collection)?;
+        let coll: Collection<Document> = self.collection(collection)?;
+
+        // Group fields can't have dots, so values go by their positions
+        let mut group = Document::new();
+        if group_by == "" {
+            group.insert("_id", Bson::Null);
+        } else {
+            group.insert("_id", format!("${}", group_by));
+        }
+        for (i, spec) in specs.iter().enumerate() {
+            let field = format!("${}", spec.field);
+            let acc = match spec.op {
+                AggregateOp::Count => doc! { "$sum": 1 },
+                AggregateOp::Sum => doc! { "$sum": field },
+                AggregateOp::Min => doc! { "$min": field },
+                AggregateOp::Max => doc! { "$max": field },
+            };
+            group.insert(format!("v{}", i), acc);
         }
+        let pipeline = vec![
+            doc! { "$match": Self::filter_to_bson(filter)? },
+            doc! { "$group": group },
+            doc! { "$sort": { "_id": 1 } },
+        ];
+        debug!(
+            "Aggregating {} by {} filter {}: {:?}",
+            collection, group_by, filter, pipeline
+        );
 
-        if itm.id == u64::MAX {
-            let coll_id = self.collections[collection];
-            if self.items.contains_key(&coll_id) {
-                itm.id = self.items_count[&coll_id] + 1;
+        let mut groups: Vec<AggregateGroup> = Vec::new();
+        let mut found = coll.aggregate(pipeline).await?;
+        while let Some(d) = found.try_next().await? {
+            let key = match d.get("_id") {
+                Some(Bson::String(k)) => Some(k.clone()),
+                _ => None,
+            };
+            let mut values: HashMap<String, u64> = HashMap::new();
+            for (i, spec) in specs.iter().enumerate() {
+                let val = match d.get(&format!("v{}", i)) {
+                    Some(Bson::Int32(v)) => *v as u64,
+                    Some(Bson::Int64(v)) => *v as u64,
+                    Some(Bson::Double(v)) => *v as u64,
+                    _ => {
+                        continue;
+                    }
+                };
+                values.insert(spec.name(), val);
             }
+            groups.push(AggregateGroup { key, values });
         }
+        return Ok(groups);
+    }
 
-        let old_itm = if itm.id != u64::MAX {
-            self.get_item(collection, itm.id).await
-        } else {
-            None
-        };
-        let mut new_itm = itm.clone();
-        if !old_itm.as_ref().is_none() && merge {
-            new_itm = old_itm.as_ref().unwrap().clone();
-            new_itm.merge(&itm);
+    async fn set_item(
+        &mut self,
+        collection: &str,
+        exp_itm: &Item,
+        merge: bool,
+    ) -> StoreResult<u64> {
+        let res = self.store_item(collection, exp_itm, merge, None).await;
+        self.note_result(&res);
+        return res;
+    }
+
+    async fn set_item_rev(
+        &mut self,
+        collection: &str,
+        exp_itm: &Item,
+        merge: bool,
+        rev: u64,
+    ) -> StoreResult<u64> {
+        let res = self.store_item(collection, exp_itm, merge, Some(rev)).await;
+        self.note_result(&res);
+        return res;
+    }
+
+    async fn del_item(&mut self, collection: &str, id: u64) -> StoreResult<bool> {
+        let res = self.remove_item(collection, id).await;
+        self.note_result(&res);
+        return res;
+    }
+
+    async fn set_items(
+        &mut self,
+        collection: &str,
+        itms: &[Item],
+        merge: bool,
+    ) -> StoreResult<Vec<u64>> {
+        if itms.is_empty() {
+            return Ok(Vec::new());
         }
 
-        let coll: Collection<Item> = self
-            .client
-            .as_ref()
-            .unwrap()
-            .database(&self.database_name)
-            .collection(collection);
-        let filter = doc! {
-            "id": itm.id as i64,
-        };
+        let own_tx = self.session.is_none();
+        if own_tx {
+            if let Err(e) = self.begin_transaction().await {
+                if self.atomic_batches {
+                    error!("Can't write {} all or nothing: {}", collection, e);
+                    return Err(e);
+                }
+                // Write one by one, so that a failure never loses items
+                // already stored
+                warn!("Writing {} without transaction: {}", collection, e);
+                let mut ids: Vec<u64> = Vec::new();
+                for itm in itms {
+                    ids.push(self.set_item(collection, itm, merge).await?);
+                }
+                return Ok(ids);
+            }
+        }
 
-        if old_itm.as_ref().is_none() {
-            let res = coll.insert_one(new_itm.clone()).await;
-            if let Err(e) = res {
-                info!("MongoDB insert_one failed for {} id {}: {}", collection, new_itm.id, e);
+        let res = self.store_items(collection, itms, merge).await;
+        self.note_result(&res);
+        if own_tx {
+            if res.is_ok() {
+                self.commit_transaction().await?;
             } else {
-                info!("Successfully inserted {} id {} to MongoDB", collection, new_itm.id);
+                let _res = self.abort_transaction().await;
             }
-        } else {
-            let res = coll.replace_one(filter, new_itm.clone()).await;
-            if let Err(e) = res {
-                info!("MongoDB replace_one failed for {} id {}: {}", collection, new_itm.id, e);
-            } else {
-                info!("Successfully replaced {} id {} in MongoDB", collection, new_itm.id);
+        }
+        return res;
+    }
+
+    async fn del_items(&mut self, collection: &str, ids: &[u64]) -> StoreResult<u64> {
+        if ids.is_empty() {
+            return Ok(0);
+        }
+
+        let own_tx = self.session.is_none();
+        if own_tx {
+            if let Err(e) = self.begin_transaction().await {
+                if self.atomic_batches {
+                    error!("Can't remove from {} all or nothing: {}", collection, e);
+                    return Err(e);
+                }
+                warn!("Removing from {} without transaction: {}", collection, e);
+                let mut count = 0;
+                for id in ids {
+                    if self.del_item(collection, *id).await? {
+                        count += 1;
+                    }
+                }
+                return Ok(count);
             }
         }
 
-        let coll_id = self.collections[collection];
-        if self.items.contains_key(&coll_id) {
-            let coll = self.items.get_mut(&coll_id).unwrap();
-            if coll.contains_key(&new_itm.id) {
-                *(coll.get_mut(&new_itm.id).unwrap()) = true;
+        let res = self.remove_items(collection, ids).await;
+        self.note_result(&res);
+        if own_tx {
+            if res.is_ok() {
+                self.commit_transaction().await?;
             } else {
-                coll.insert(new_itm.id, true);
+                let _res = self.abort_transaction().await;
             }
-            if self.items_count.contains_key(&coll_id) {
-                let cnt = self.items_count.get_mut(&coll_id).unwrap();
-                if new_itm.id > *cnt {
-                    *cnt = new_itm.id;
-                }
-            } else {
-                self.items_count.insert(coll_id, new_itm.id + 1);
+        }
+        return res;
+    }
+
+    async fn begin_transaction(&mut self) -> StoreResult<()> {
+        if self.session.is_some() {
+            return Err(StoreError::Transaction("already in progress".to_string()));
+        }
+        let client = self.client.as_ref().ok_or(StoreError::NotConnected)?;
+        let mut session = client.start_session().await?;
+        session.start_transaction().await?;
+        self.session = Some(session);
+        self.tx_failed = false;
+        self.tx_undo.clear();
+        return Ok(());
+    }
+
+    async fn commit_transaction(&mut self) -> StoreResult<()> {
+        if self.session.is_none() {
+            return Err(StoreError::Transaction("not started".to_string()));
+        }
+        if self.tx_failed {
+            self.abort_transaction().await?;
+            return Err(StoreError::Transaction(
+                "rolled back after failed write".to_string(),
+            ));
+        }
+
+        let mut session = self.session.take().unwrap();
+        if let Err(e) = session.commit_transaction().await {
+            error!("MongoDB commit failed: {}", e);
+            self.index_undo();
+            return Err(e.into());
+        }
+        self.tx_undo.clear();
+        return Ok(());
+    }
+
+    async fn abort_transaction(&mut self) -> StoreResult<()> {
+        let mut session = match self.session.take() {
+            Some(s) => s,
+            None => {
+                return Err(StoreError::Transaction("not started".to_string()));
             }
+        };
+        self.index_undo();
+        self.tx_failed = false;
+        session.abort_transaction().await?;
+        return Ok(());
+    }
+
+    fn in_transaction(&self) -> bool {
+        return self.session.is_some();
+    }
+
+    async fn add_history(&mut self, entry: &HistoryEntry) -> StoreResult<()> {
+        self.collection_index(&entry.collection)?;
+
+        // Entries of the same second are ordered by sequence, as ObjectId
+        // order isn't reliable across instances
+        let seq = self.allocate_ids(HISTORY_COLLECTION, 1).await?;
+        let mut doc =
+            bson::to_document(entry).map_err(|e| StoreError::Serialization(e.to_string()))?;
+        doc.insert("seq", seq as i64);
+        let coll: Collection<Document> = self.collection(HISTORY_COLLECTION)?;
+        let res = in_session!(self.session, coll.insert_one(doc))
+            .map(|_| ())
+            .map_err(StoreError::from);
+        self.note_result(&res);
+        return res;
+    }
+
+    async fn get_history(&mut self, collection: &str, id: u64) -> StoreResult<Vec<HistoryEntry>> {
+        self.collection_index(collection)?;
+        let coll: Collection<HistoryEntry> = self.collection(HISTORY_COLLECTION)?;
+        let filter = doc! {
+            "collection": collection,
+            "id": id as i64,
+        };
+
+        let mut entries: Vec<HistoryEntry> = Vec::new();
+        let mut cursor = coll
+            .find(filter)
+            .sort(doc! { "time": 1, "seq": 1, "_id": 1 })
+            .await?;
+        while let Some(entry) = cursor.try_next().await? {
+            entries.push(entry);
+        }
+        return Ok(entries);
+    }
+
+    async fn get_collection_history(&mut self, collection: &str) -> StoreResult<Vec<HistoryEntry>> {
+        self.collection_index(collection)?;
+        let coll: Collection<HistoryEntry> = self.collection(HISTORY_COLLECTION)?;
+
+        let mut entries: Vec<HistoryEntry> = Vec::new();
+        let mut cursor = coll
+            .find(doc! { "collection": collection })
+            .sort(doc! { "id": 1, "time": 1, "seq": 1, "_id": 1 })
+            .await?;
+        while let Some(entry) = cursor.try_next().await? {
+            entries.push(entry);
         }
+        return Ok(entries);
+    }
+
+    async fn add_trash(&mut self, entry: &TrashEntry) -> StoreResult<()> {
+        self.collection_index(&entry.collection)?;
+        let coll: Collection<TrashEntry> = self.collection(TRASH_COLLECTION)?;
+        let filter = doc! {
+            "collection": &entry.collection,
+            "id": entry.id as i64,
+        };
+        let res = in_session!(
+            self.session,
+            coll.replace_one(filter, entry.clone()).upsert(true)
+        )
+        .map(|_| ())
+        .map_err(StoreError::from);
+        self.note_result(&res);
+        return res;
+    }
 
-        return new_itm.id;
+    async fn get_trash(&mut self, collection: &str) -> StoreResult<Vec<TrashEntry>> {
+        self.collection_index(collection)?;
+        let coll: Collection<TrashEntry> = self.collection(TRASH_COLLECTION)?;
+        let filter = doc! {
+            "collection": collection,
+        };
+
+        let mut entries: Vec<TrashEntry> = Vec::new();
+        let mut cursor = coll.find(filter).sort(doc! { "id": 1 }).await?;
+        while let Some(entry) = cursor.try_next().await? {
+            entries.push(entry);
+        }
+        return Ok(entries);
     }
 
-    async fn del_item(&mut self, collection: &str, id: u64) -> bool {
-        let coll: Collection<Item> = self
-            .client
-            .as_ref()
-            .unwrap()
-            .database(&self.database_name)
-            .collection(collection);
+    async fn del_trash(&mut self, collection: &str, id: u64) -> StoreResult<bool> {
+        let coll: Collection<TrashEntry> = self.collection(TRASH_COLLECTION)?;
         let filter = doc! {
+            "collection": collection,
             "id": id as i64,
         };
+        let res = in_session!(self.session, coll.delete_one(filter))
+            .map(|r| r.deleted_count > 0)
+            .map_err(StoreError::from);
+        self.note_result(&res);
+        return res;
+    }
 
-        let _res = coll.delete_one(filter).await;
+    async fn purge_trash(&mut self, before: u64) -> StoreResult<u64> {
+        let coll: Collection<TrashEntry> = self.collection(TRASH_COLLECTION)?;
+        let filter = doc! {
+            "time": { "$lt": before as i64 },
+        };
+        let res = in_session!(self.session, coll.delete_many(filter))
+            .map(|r| r.deleted_count)
+            .map_err(StoreError::from);
+        self.note_result(&res);
+        return res;
+    }
 
-        let coll_id = self.collections[collection];
-        if self.items.contains_key(&coll_id) {
-            let coll = self.items.get_mut(&coll_id).unwrap();
-            if coll.contains_key(&id) {
-                coll.remove(&id);
-                return true;
-            }
+    async fn add_migration(&mut self, entry: &MigrationEntry) -> StoreResult<()> {
+        let coll: Collection<MigrationEntry> = self.collection(MIGRATION_COLLECTION)?;
+        let res = in_session!(self.session, coll.insert_one(entry.clone()))
+            .map(|_| ())
+            .map_err(StoreError::from);
+        self.note_result(&res);
+        return res;
+    }
+
+    async fn get_migrations(&mut self) -> StoreResult<Vec<MigrationEntry>> {
+        let coll: Collection<MigrationEntry> = self.collection(MIGRATION_COLLECTION)?;
+        let mut entries: Vec<MigrationEntry> = Vec::new();
+        let mut cursor = coll.find(doc! {}).sort(doc! { "version": 1 }).await?;
+        while let Some(entry) = cursor.try_next().await? {
+            entries.push(entry);
         }
-        return false;
+        return Ok(entries);
     }
 
     async fn get_credentials(&mut self) -> String {
@@ -484,30 +1177,160 @@ impl Store for StoreMongo {
     async fn get_internals(&mut self) -> Item {
         let tmp_data_path = self.local_path.clone() + "/internals.js";
 
-        let read_data = std::fs::read_to_string(tmp_data_path);
+        let read_data = std::fs::read_to_string(&tmp_data_path);
         if let Err(_e) = read_data {
             return Item::new();
         }
         let text = read_data.unwrap();
-        let itm: Item = serde_json::from_str(&text).unwrap();
-        return itm;
+        match serde_json::from_str(&text) {
+            Ok(itm) => {
+                return itm;
+            }
+            Err(e) => {
+                error!("Failed to parse {}: {}", tmp_data_path, e);
+                return Item::new();
+            }
+        }
     }
 
     async fn get_settings(&mut self) -> Item {
         let tmp_data_path = self.local_path.clone() + "/settings.js";
 
-        let read_data = std::fs::read_to_string(tmp_data_path);
+        let read_data = std::fs::read_to_string(&tmp_data_path);
         if let Err(_e) = read_data {
             return Item::new();
         }
         let text = read_data.unwrap();
-        let itm: Item = serde_json::from_str(&text).unwrap();
-        return itm;
+        match serde_json::from_str(&text) {
+            Ok(itm) => {
+                return itm;
+            }
+            Err(e) => {
+                error!("Failed to parse {}: {}", tmp_data_path, e);
+                return Item::new();
+            }
+        }
     }
 
-    async fn set_settings(&mut self, itm: Item) {
+    async fn set_internals(&mut self, itm: Item) -> StoreResult<()> {
+        let tmp_data_path = self.local_path.clone() + "/internals.js";
+        let s = serde_json::to_string(&itm)?;
+        write_atomic(&tmp_data_path, s.as_bytes())?;
+        return Ok(());
+    }
+
+    async fn set_settings(&mut self, itm: Item) -> StoreResult<()> {
         let tmp_data_path = self.local_path.clone() + "/settings.js";
-        let s = serde_json::to_string(&itm);
-        std::fs::write(tmp_data_path, s.unwrap()).expect("Couldn't write item");
+        let s = serde_json::to_string(&itm)?;
+        write_atomic(&tmp_data_path, s.as_bytes())?;
+        return Ok(());
+    }
+
+    async fn reload_internals(&mut self) -> StoreResult<()> {
+        let internals = self.get_internals().await;
+        self.atomic_batches = internals.safe_bool("atomic_batches", false);
+        let collections = internals.safe_strstr("collections", &HashMap::new());
+        debug!("Collections: {}", collections.len());
+        // let db = self.client.as_ref().unwrap().database(&self.database_name);
+        for coll_name in collections {
+            info!("Registering collection: {}", &coll_name.1);
+
+            // Don't create collection explicitly - MongoDB will create it lazily
+            // when first document is inserted during merge_database
+
+            // Registered collections keep their index, as items map is
+            // keyed by it
+            let coll_idx = match self.collections.get(&coll_name.1) {
+                Some(idx) => *idx,
+                None => self.collections.len().try_into().unwrap(),
+            };
+            self.collections.insert(coll_name.1.to_string(), coll_idx);
+
+            // Rebuild item index from existing documents and make sure
+            // the counter doesn't hand out IDs that are already taken
+            let (ids, max_id) = self.load_item_ids(&coll_name.1).await;
+            if let Err(e) = self.raise_counter(&coll_name.1, max_id).await {
+                error!("Failed to update counter of {}: {}", &coll_name.1, e);
+            }
+            self.items.insert(coll_idx, ids);
+            self.items_count.insert(coll_idx, max_id);
+
+            // Items are looked up by ID, and concurrent inserts of the
+            // same ID must not both succeed
+            let idx = IndexModel::builder()
+                .keys(doc! { "id": 1 })
+                .options(IndexOptions::builder().unique(true).build())
+                .build();
+            let coll: Collection<Document> = self.collection(&coll_name.1)?;
+            if let Err(e) = coll.create_index(idx).await {
+                error!("Failed to create ID index of {}: {}", &coll_name.1, e);
+                return Err(e.into());
+            }
+
+            info!(
+                "Collection {} registered: {} items, max ID {}",
+                &coll_name.1,
+                self.items[&coll_idx].len(),
+                max_id
+            );
+        }
+
+        // Secondary indexes declared in internals. Items without any of
+        // the indexed fields aren't indexed, like with local storage.
+        for def in declared_indexes(&internals) {
+            if !self.collections.contains_key(&def.collection) {
+                error!("Index of unknown collection {}", def.collection);
+                continue;
+            }
+            let mut keys = Document::new();
+            for field in &def.fields {
+                keys.insert(field.as_str(), 1);
+            }
+            // Case is ignored by comparing strings on secondary level
+            let mut collation: Option<Collation> = None;
+            if def.case_insensitive {
+                collation = Some(
+                    Collation::builder()
+                        .locale("en")
+                        .strength(CollationStrength::Secondary)
+                        .build(),
+                );
+            }
+            let idx = IndexModel::builder()
+                .keys(keys)
+                .options(
+                    IndexOptions::builder()
+                        .unique(def.unique)
+                        .sparse(true)
+                        .collation(collation)
+                        .build(),
+                )
+                .build();
+            // Don't run without the index: unique fields wouldn't be
+            // checked at all, e.g. when stored items already have duplicates
+            let coll: Collection<Document> = self.collection(&def.collection)?;
+            if let Err(e) = coll.create_index(idx).await {
+                error!(
+                    "Failed to create index of {} on {}: {}",
+                    def.collection,
+                    def.fields.join(","),
+                    e
+                );
+                return Err(e.into());
+            }
+        }
+
+        // History and trash are looked up by item
+        for name in [HISTORY_COLLECTION, TRASH_COLLECTION] {
+            if let Ok(coll) = self.collection::<Document>(name) {
+                let idx = IndexModel::builder()
+                    .keys(doc! { "collection": 1, "id": 1 })
+                    .build();
+                if let Err(e) = coll.create_index(idx).await {
+                    error!("Failed to create index of {}: {}", name, e);
+                }
+            }
+        }
+        return Ok(());
     }
 }
@@ -0,0 +1,1260 @@
+/*
+ * Isabelle project
+ *
+ * Copyright 2023-2025 Maxim Menshikov
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining
+ * a copy of this software and associated documentation files (the “Software”),
+ * to deal in the Software without restriction, including without limitation
+ * the rights to use, copy, modify, merge, publish, distribute, sublicense,
+ * and/or sell copies of the Software, and to permit persons to whom the
+ * Software is furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included
+ * in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS
+ * OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+ * DEALINGS IN THE SOFTWARE.
+ */
+use crate::state::filter::{select_items, Filter, FilterOp};
+use crate::state::store::{
+    bump_revision, declared_indexes, parse_sort, HistoryEntry, IndexDef, ListCursor,
+    MigrationEntry, OrderedList, SortSpec, Store, StoreError, StoreResult, TrashEntry,
+};
+use crate::util::file::write_atomic;
+use async_trait::async_trait;
+use isabelle_dm::data_model::item::*;
+use isabelle_dm::data_model::list_result::ListResult;
+use log::{debug, error, info};
+use rusqlite::types::Value as SqlValue;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension};
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Database schema. Items are kept as JSON, one row per item.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS items (
+        collection TEXT NOT NULL,
+        id INTEGER NOT NULL,
+        data TEXT NOT NULL,
+        PRIMARY KEY (collection, id)
+    );
+    CREATE TABLE IF NOT EXISTS counters (
+        collection TEXT PRIMARY KEY,
+        value INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS history (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        collection TEXT NOT NULL,
+        id INTEGER NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS history_item ON history (collection, id);
+    CREATE TABLE IF NOT EXISTS trash (
+        collection TEXT NOT NULL,
+        id INTEGER NOT NULL,
+        time INTEGER NOT NULL,
+        data TEXT NOT NULL,
+        PRIMARY KEY (collection, id)
+    );
+    CREATE TABLE IF NOT EXISTS migrations (
+        version INTEGER PRIMARY KEY,
+        data TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS settings (
+        name TEXT PRIMARY KEY,
+        data TEXT NOT NULL
+    );
+";
+
+/// SQLite storage implementation
+#[derive(Debug)]
+pub struct StoreSqlite {
+    /// Path to SQLite database file
+    pub path: String,
+
+    /// Local settings path (like for Local storage)
+    pub local_path: String,
+
+    /// Collection hash map
+    pub collections: HashMap<String, u64>,
+
+    /// Actual SQLite connection
+    pub conn: Option<Connection>,
+
+    /// Transaction is in progress
+    pub in_tx: bool,
+
+    /// Some write inside the current transaction failed
+    pub tx_failed: bool,
+}
+
+unsafe impl Send for StoreSqlite {}
+
+impl StoreSqlite {
+    pub fn new() -> Self {
+        Self {
+            path: "".to_string(),
+            local_path: "".to_string(),
+            collections: HashMap::new(),
+            conn: None,
+            in_tx: false,
+            tx_failed: false,
+        }
+    }
+
+    /// Convert `sqlite://` URL to the file path
+    pub fn url_to_path(url: &str) -> String {
+        return url.strip_prefix("sqlite://").unwrap_or(url).to_string();
+    }
+
+    /// Clamp ID to the range SQLite integers can hold
+    fn to_sql_id(id: u64) -> i64 {
+        if id > i64::MAX as u64 {
+            return i64::MAX;
+        }
+        return id as i64;
+    }
+
+    /// JSON path of the item field as SQL literal, like `'$.strs.login'`.
+    /// Returns None for names that can't be put into SQL as is.
+    fn json_path(field: &str) -> Option<String> {
+        let valid = field
+            .split('.')
+            .all(|p| !p.is_empty() && p.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'));
+        if !valid {
+            return None;
+        }
+        return Some(format!("'$.{}'", field));
+    }
+
+    /// Add the parameter and get its placeholder
+    fn bind(params: &mut Vec<SqlValue>, val: SqlValue) -> String {
+        params.push(val);
+        return format!("?{}", params.len());
+    }
+
+    /// Condition that the field has one of JSON types, `missing` stands for
+    /// absent field. Never NULL, so that it can be negated.
+    fn type_is(path: &str, types: &[&str]) -> String {
+        let list: Vec<String> = types.iter().map(|t| format!("'{}'", t)).collect();
+        return format!(
+            "coalesce(json_type(data, {}), 'missing') IN ({})",
+            path,
+            list.join(", ")
+        );
+    }
+
+    /// SQL value of the scalar along with JSON types it is compared with.
+    /// Booleans are JSON `true` and `false`, which SQLite reads as 1 and 0.
+    fn sql_scalar(val: &Value) -> Option<(&'static [&'static str], SqlValue)> {
+        match val {
+            Value::String(s) => Some((&["text"], SqlValue::Text(s.clone()))),
+            Value::Bool(b) => Some((&["true", "false"], SqlValue::Integer(*b as i64))),
+            Value::Number(n) => {
+                if let Some(i) = n.as_i64() {
+                    return Some((&["integer", "real"], SqlValue::Integer(i)));
+                }
+                if n.is_u64() {
+                    return None;
+                }
+                return Some((&["integer", "real"], SqlValue::Real(n.as_f64()?)));
+            }
+            _ => None,
+        }
+    }
+
+    /// Compare the field with the value like the in-memory filter does:
+    /// values of other types never match, missing field equals null
+    fn compare_to_sql(
+        path: &str,
+        sign: &str,
+        val: &Value,
+        params: &mut Vec<SqlValue>,
+    ) -> Option<String> {
+        if val.is_null() {
+            return match sign {
+                "=" | ">=" | "<=" => Some(Self::type_is(path, &["missing", "null"])),
+                _ => Some("0".to_string()),
+            };
+        }
+        let (types, sql_val) = Self::sql_scalar(val)?;
+        return Some(format!(
+            "({} AND json_extract(data, {}) {} {})",
+            Self::type_is(path, types),
+            path,
+            sign,
+            Self::bind(params, sql_val)
+        ));
+    }
+
+    /// Join conditions, `empty` is used for an empty list
+    fn join_sql(list: Vec<String>, sep: &str, empty: &str) -> String {
+        if list.is_empty() {
+            return empty.to_string();
+        }
+        return format!("({})", list.join(sep));
+    }
+
+    /// Translate the filter to SQL condition over item JSON. Returns None if
+    /// some part can't be done in SQL, then items are filtered in memory.
+    fn filter_to_sql(filter: &Filter, params: &mut Vec<SqlValue>) -> Option<String> {
+        let list_to_sql = |list: &[Filter], params: &mut Vec<SqlValue>| {
+            let mut conds: Vec<String> = Vec::new();
+            for f in list {
+                conds.push(Self::filter_to_sql(f, params)?);
+            }
+            return Some(conds);
+        };
+        match filter {
+            Filter::And(l) => Some(Self::join_sql(list_to_sql(l, params)?, " AND ", "1")),
+            Filter::Or(l) => Some(Self::join_sql(list_to_sql(l, params)?, " OR ", "0")),
+            Filter::Nor(l) => Some(format!(
+                "NOT {}",
+                Self::join_sql(list_to_sql(l, params)?, " OR ", "0")
+            )),
+            Filter::Not(f) => Some(format!("NOT {}", Self::filter_to_sql(f, params)?)),
+            Filter::Cond { field, op, value } => {
+                let path = Self::json_path(field)?;
+                match op {
+                    FilterOp::Eq => Self::compare_to_sql(&path, "=", value, params),
+                    FilterOp::Ne => Some(format!(
+                        "NOT {}",
+                        Self::compare_to_sql(&path, "=", value, params)?
+                    )),
+                    FilterOp::Gt => Self::compare_to_sql(&path, ">", value, params),
+                    FilterOp::Gte => Self::compare_to_sql(&path, ">=", value, params),
+                    FilterOp::Lt => Self::compare_to_sql(&path, "<", value, params),
+                    FilterOp::Lte => Self::compare_to_sql(&path, "<=", value, params),
+                    FilterOp::In | FilterOp::Nin => {
+                        let mut alts: Vec<String> = Vec::new();
+                        for v in value.as_array()? {
+                            alts.push(Self::compare_to_sql(&path, "=", v, params)?);
+                        }
+                        let any = Self::join_sql(alts, " OR ", "0");
+                        if *op == FilterOp::Nin {
+                            return Some(format!("NOT {}", any));
+                        }
+                        return Some(any);
+                    }
+                    FilterOp::Exists => match value {
+                        Value::Bool(true) => Some(format!("json_type(data, {}) IS NOT NULL", path)),
+                        Value::Bool(false) => Some(format!("json_type(data, {}) IS NULL", path)),
+                        _ => None,
+                    },
+                }
+            }
+        }
+    }
+
+    /// Rank of the field type in Mongo comparison order, as in filter.rs
+    fn rank_sql(path: &str) -> String {
+        return format!(
+            "CASE coalesce(json_type(data, {}), 'null') WHEN 'null' THEN 0 \
+             WHEN 'integer' THEN 1 WHEN 'real' THEN 1 WHEN 'text' THEN 2 \
+             WHEN 'object' THEN 3 WHEN 'array' THEN 4 ELSE 5 END",
+            path
+        );
+    }
+
+    /// Value of the field for sorting within its type. Objects and arrays
+    /// have none, so that they are all equal.
+    fn sort_value_sql(path: &str) -> String {
+        return format!(
+            "CASE WHEN {} THEN NULL ELSE json_extract(data, {}) END",
+            Self::type_is(path, &["object", "array"]),
+            path
+        );
+    }
+
+    /// Order of items: by type rank and value of every sort field, then by
+    /// ID, the same as select_items() does
+    fn order_to_sql(specs: &[SortSpec]) -> Option<String> {
+        let mut terms: Vec<String> = Vec::new();
+        for spec in specs {
+            let path = Self::json_path(&spec.field)?;
+            let dir = if spec.descending { " DESC" } else { "" };
+            terms.push(Self::rank_sql(&path) + dir);
+            terms.push(Self::sort_value_sql(&path) + dir);
+        }
+        terms.push("id".to_string());
+        return Some(terms.join(", "));
+    }
+
+    /// Condition selecting items after the cursor in the order of
+    /// order_to_sql()
+    fn keyset_to_sql(
+        specs: &[SortSpec],
+        cursor: &ListCursor,
+        params: &mut Vec<SqlValue>,
+    ) -> Option<String> {
+        let mut branches: Vec<String> = Vec::new();
+        let mut equal: Vec<String> = Vec::new();
+        for (i, spec) in specs.iter().enumerate() {
+            let path = Self::json_path(&spec.field)?;
+            let rank = Self::rank_sql(&path);
+            let value = Self::sort_value_sql(&path);
+            let sign = if spec.descending { "<" } else { ">" };
+            let (cur_rank, cur_val) = match cursor.values.get(i).unwrap_or(&Value::Null) {
+                Value::Null => (0, None),
+                v @ Value::Number(_) => (1, Some(Self::sql_scalar(v)?.1)),
+                v @ Value::String(_) => (2, Some(Self::sql_scalar(v)?.1)),
+                v @ Value::Bool(_) => (5, Some(Self::sql_scalar(v)?.1)),
+                _ => {
+                    return None;
+                }
+            };
+            let (after, same) = match cur_val {
+                None => (format!("{} {} 0", rank, sign), format!("{} = 0", rank)),
+                Some(v) => {
+                    let p = Self::bind(params, v);
+                    (
+                        format!(
+                            "({r} {s} {cr} OR ({r} = {cr} AND {v} {s} {p}))",
+                            r = rank,
+                            s = sign,
+                            cr = cur_rank,
+                            v = value,
+                            p = p
+                        ),
+                        format!("({} = {} AND {} = {})", rank, cur_rank, value, p),
+                    )
+                }
+            };
+            let mut conds = equal.clone();
+            conds.push(after);
+            branches.push(Self::join_sql(conds, " AND ", "1"));
+            equal.push(same);
+        }
+        let id = Self::bind(params, SqlValue::Integer(Self::to_sql_id(cursor.id)));
+        equal.push(format!("id > {}", id));
+        branches.push(Self::join_sql(equal, " AND ", "1"));
+        return Some(Self::join_sql(branches, " OR ", "0"));
+    }
+
+    /// Read items selected by the query, skipping broken ones
+    fn query_items(
+        &self,
+        collection: &str,
+        sql: &str,
+        params: &[SqlValue],
+    ) -> StoreResult<Vec<Item>> {
+        let mut loaded: Vec<Item> = Vec::new();
+        let mut stmt = self.conn()?.prepare(sql)?;
+        let rows = stmt.query_map(params_from_iter(params.iter()), |row| {
+            row.get::<_, String>(0)
+        })?;
+        for text in rows {
+            // Single broken item shouldn't make the whole collection unusable
+            match serde_json::from_str::<Item>(&text?) {
+                Ok(itm) => loaded.push(itm),
+                Err(e) => error!("Failed to parse {} item: {}", collection, e),
+            }
+        }
+        return Ok(loaded);
+    }
+
+    /// Register collection in the internal map
+    fn register_collection(&mut self, name: &str) {
+        if !self.collections.contains_key(name) {
+            let coll_idx = self.collections.len().try_into().unwrap();
+            self.collections.insert(name.to_string(), coll_idx);
+            info!("Collection {} registered", name);
+        }
+    }
+
+    /// Create indexes declared in internals over item JSON. They are
+    /// partial indexes of the collection, which list queries select with
+    /// the collection name as literal.
+    fn create_indexes(&self, defs: &[IndexDef]) -> StoreResult<()> {
+        for def in defs {
+            let paths: Option<Vec<String>> =
+                def.fields.iter().map(|f| Self::json_path(f)).collect();
+            let valid_coll = !def.collection.is_empty()
+                && def
+                    .collection
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_');
+            let paths = match paths {
+                Some(p) if valid_coll => p,
+                _ => {
+                    error!(
+                        "Skipping index of {} on {}: unsupported name",
+                        def.collection,
+                        def.fields.join(",")
+                    );
+                    continue;
+                }
+            };
+
+            let exprs: Vec<String> = paths
+                .iter()
+                .map(|p| {
+                    let expr = format!("json_extract(data, {})", p);
+                    if def.case_insensitive {
+                        return format!("lower({})", expr);
+                    }
+                    return expr;
+                })
+                .collect();
+            let prefix = if def.unique { "unique_" } else { "index_" };
+            let mut name = prefix.to_owned() + &def.collection + "_" + &def.fields.join("_");
+            if def.case_insensitive {
+                name += "_ci";
+            }
+            let sql = format!(
+                "CREATE {}INDEX IF NOT EXISTS \"{}\" ON items ({}) WHERE collection = '{}'",
+                if def.unique { "UNIQUE " } else { "" },
+                name.replace('.', "_"),
+                exprs.join(", "),
+                def.collection
+            );
+            if let Err(e) = self.conn()?.execute_batch(&sql) {
+                error!(
+                    "Failed to create index of {} on {}: {}",
+                    def.collection,
+                    def.fields.join(","),
+                    e
+                );
+            }
+        }
+        return Ok(());
+    }
+
+    /// Get open connection
+    fn conn(&self) -> StoreResult<&Connection> {
+        return self.conn.as_ref().ok_or(StoreError::NotConnected);
+    }
+
+    /// Read the item stored in the given collection
+    fn read_item(&self, collection: &str, id: u64) -> StoreResult<Option<Item>> {
+        let text: Option<String> = self
+            .conn()?
+            .query_row(
+                "SELECT data FROM items WHERE collection = ?1 AND id = ?2",
+                params![collection, Self::to_sql_id(id)],
+                |row| row.get(0),
+            )
+            .optional()?;
+        match text {
+            Some(t) => {
+                return Ok(Some(serde_json::from_str::<Item>(&t)?));
+            }
+            None => {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Remember failed write, so that the current transaction is not
+    /// committed partially
+    fn note_result<T>(&mut self, res: &StoreResult<T>) {
+        if res.is_err() && self.in_tx {
+            self.tx_failed = true;
+        }
+    }
+
+    /// Write the item and update counter atomically. Savepoint works both
+    /// inside and outside of the explicit transaction.
+    fn write_item(&mut self, collection: &str, itm: &mut Item) -> StoreResult<()> {
+        let conn = self.conn.as_mut().ok_or(StoreError::NotConnected)?;
+        let tx = conn.savepoint()?;
+
+        if itm.id == u64::MAX {
+            tx.execute(
+                "INSERT INTO counters (collection, value) VALUES (?1, 1)
+                 ON CONFLICT (collection) DO UPDATE SET value = value + 1",
+                params![collection],
+            )?;
+            let value: i64 = tx.query_row(
+                "SELECT value FROM counters WHERE collection = ?1",
+                params![collection],
+                |row| row.get(0),
+            )?;
+            itm.id = value as u64;
+        } else {
+            tx.execute(
+                "INSERT INTO counters (collection, value) VALUES (?1, ?2)
+                 ON CONFLICT (collection) DO UPDATE SET value = max(value, excluded.value)",
+                params![collection, Self::to_sql_id(itm.id)],
+            )?;
+        }
+
+        let text = serde_json::to_string(itm)?;
+        tx.execute(
+            "INSERT INTO items (collection, id, data) VALUES (?1, ?2, ?3)
+             ON CONFLICT (collection, id) DO UPDATE SET data = excluded.data",
+            params![collection, Self::to_sql_id(itm.id), text],
+        )?;
+        tx.commit()?;
+        return Ok(());
+    }
+
+    /// Prepare and write the item. Outside of the explicit transaction,
+    /// reading the old item and writing the new one are done in their own
+    /// one, so that revision check and merge see the latest item.
+    fn store_item(
+        &mut self,
+        collection: &str,
+        exp_itm: &Item,
+        merge: bool,
+        rev: Option<u64>,
+    ) -> StoreResult<u64> {
+        if !self.collections.contains_key(collection) {
+            return Err(StoreError::UnknownCollection(collection.to_string()));
+        }
+        if self.in_tx {
+            return self.merge_and_write(collection, exp_itm, merge, rev);
+        }
+
+        self.conn()?.execute_batch("BEGIN IMMEDIATE")?;
+        let res = self.merge_and_write(collection, exp_itm, merge, rev);
+        let end = if res.is_ok() { "COMMIT" } else { "ROLLBACK" };
+        if let Err(e) = self.conn()?.execute_batch(end) {
+            error!("SQLite {} failed: {}", end, e);
+            let _res = self.conn()?.execute_batch("ROLLBACK");
+            return Err(e.into());
+        }
+        return res;
+    }
+
+    /// Merge the item with the stored one, check revision and write it
+    fn merge_and_write(
+        &mut self,
+        collection: &str,
+        exp_itm: &Item,
+        merge: bool,
+        rev: Option<u64>,
+    ) -> StoreResult<u64> {
+        let mut itm = exp_itm.clone();
+        if itm.bools.contains_key("__security_preserve") {
+            itm.bools.remove("__security_preserve");
+        }
+
+        let old_itm = if itm.id != u64::MAX {
+            self.read_item(collection, itm.id)?
+        } else {
+            None
+        };
+        let mut new_itm = itm.clone();
+        if merge {
+            if let Some(o) = &old_itm {
+                new_itm = o.clone();
+                new_itm.merge(&itm);
+            }
+        }
+        bump_revision(old_itm.as_ref(), &mut new_itm, rev)?;
+
+        if let Err(e) = self.write_item(collection, &mut new_itm) {
+            error!(
+                "SQLite write failed for {} id {}: {}",
+                collection, new_itm.id, e
+            );
+            return Err(e);
+        }
+
+        return Ok(new_itm.id);
+    }
+
+    /// Read item stored inside local data folder
+    fn read_local_item(&self, name: &str) -> Option<Item> {
+        let tmp_data_path = self.local_path.clone() + "/" + name;
+
+        let read_data = std::fs::read_to_string(tmp_data_path);
+        if let Err(_e) = read_data {
+            return None;
+        }
+        let text = read_data.unwrap();
+        return serde_json::from_str(&text).ok();
+    }
+}
+
+#[async_trait]
+impl Store for StoreSqlite {
+    async fn connect(&mut self, url: &str, alturl: &str) -> StoreResult<()> {
+        // Preserve parameters
+        self.path = Self::url_to_path(url);
+        self.local_path = alturl.to_string();
+
+        let conn = match Connection::open(&self.path) {
+            Ok(c) => c,
+            Err(e) => {
+                error!("Failed to open SQLite database {}: {}", self.path, e);
+                return Err(e.into());
+            }
+        };
+        if let Err(e) = conn.execute_batch(&("PRAGMA journal_mode = WAL;".to_owned() + SCHEMA)) {
+            error!("Failed to set up SQLite database {}: {}", self.path, e);
+            return Err(e.into());
+        }
+        self.conn = Some(conn);
+        info!("Connected {}!", self.path);
+
+        // Register all collections from internals and all stored ones
+        self.reload_internals().await?;
+        let stored: Vec<String> = {
+            let mut stmt = self
+                .conn()?
+                .prepare("SELECT DISTINCT collection FROM items")?;
+            let rows = stmt.query_map([], |row| row.get(0))?;
+            rows.collect::<rusqlite::Result<Vec<String>>>()?
+        };
+        for coll_name in stored {
+            self.register_collection(&coll_name);
+        }
+        return Ok(());
+    }
+
+    async fn disconnect(&mut self) {
+        self.conn = None;
+    }
+
+    fn has_collection(&self, collection: &str) -> bool {
+        return self.collections.contains_key(collection);
+    }
+
+    async fn get_collections(&mut self) -> Vec<String> {
+        self.collections.keys().map(|k| k.clone()).collect()
+    }
+
+    async fn get_item_ids(&mut self, collection: &str) -> StoreResult<HashMap<u64, bool>> {
+        let mut ids = HashMap::new();
+        if !self.collections.contains_key(collection) {
+            return Err(StoreError::UnknownCollection(collection.to_string()));
+        }
+
+        let mut stmt = self
+            .conn()?
+            .prepare("SELECT id FROM items WHERE collection = ?1")?;
+        let rows = stmt.query_map(params![collection], |row| row.get::<_, i64>(0))?;
+        for id in rows {
+            ids.insert(id? as u64, true);
+        }
+        return Ok(ids);
+    }
+
+    async fn get_all_items(
+        &mut self,
+        collection: &str,
+        sort_key: &str,
+        filter: &Filter,
+    ) -> StoreResult<ListResult> {
+        return self
+            .get_items(
+                collection,
+                u64::MAX,
+                u64::MAX,
+                sort_key,
+                filter,
+                u64::MAX,
+                u64::MAX,
+            )
+            .await;
+    }
+
+    async fn get_item(&mut self, collection: &str, id: u64) -> StoreResult<Option<Item>> {
+        return self.read_item(collection, id);
+    }
+
+    async fn get_items_ordered(
+        &mut self,
+        collection: &str,
+        id_min: u64,
+        id_max: u64,
+        sort_key: &str,
+        filter: &Filter,
+        cursor: Option<&ListCursor>,
+        fields: &[String],
+        skip: u64,
+        limit: u64,
+    ) -> StoreResult<OrderedList> {
+        let mut eff_id_min = id_min;
+        if eff_id_min == u64::MAX {
+            eff_id_min = 0;
+        }
+
+        debug!(
+            "Getting {} in range {} - {} skip {} limit {} sort key {} filter {}",
+            &collection, eff_id_min, id_max, skip, limit, sort_key, filter
+        );
+
+        if !self.collections.contains_key(collection) {
+            return Err(StoreError::UnknownCollection(collection.to_string()));
+        }
+
+        // Filter, order, cursor and paging are done by SQLite if they can be
+        // translated. Otherwise the ID range is loaded and selected in memory.
+        let specs = parse_sort(sort_key);
+        let mut params: Vec<SqlValue> = vec![
+            SqlValue::Integer(Self::to_sql_id(eff_id_min)),
+            SqlValue::Integer(Self::to_sql_id(id_max)),
+        ];
+        let filter_sql = Self::filter_to_sql(filter, &mut params);
+        let count_params = params.len();
+        let keyset_sql = match cursor {
+            Some(c) => Self::keyset_to_sql(&specs, c, &mut params),
+            None => Some("1".to_string()),
+        };
+        let order_sql = Self::order_to_sql(&specs);
+        let coll_cond = format!(
+            "collection = '{}' AND id >= ?1 AND id <= ?2",
+            collection.replace('\'', "''")
+        );
+
+        let ol = match (filter_sql, keyset_sql, order_sql) {
+            (Some(filter_sql), Some(keyset_sql), Some(order_sql)) => {
+                let cond = format!("{} AND {}", coll_cond, filter_sql);
+                let total_count: i64 = self.conn()?.query_row(
+                    &format!("SELECT COUNT(*) FROM items WHERE {}", cond),
+                    params_from_iter(params[..count_params].iter()),
+                    |row| row.get(0),
+                )?;
+
+                // One more item tells whether there is the next page
+                let sql_limit = if limit == u64::MAX {
+                    -1
+                } else {
+                    Self::to_sql_id(limit.saturating_add(1))
+                };
+                let sql_offset = if skip == u64::MAX {
+                    0
+                } else {
+                    Self::to_sql_id(skip)
+                };
+                let limit_param = Self::bind(&mut params, SqlValue::Integer(sql_limit));
+                let offset_param = Self::bind(&mut params, SqlValue::Integer(sql_offset));
+                let sql = format!(
+                    "SELECT data FROM items WHERE {} AND {} ORDER BY {} LIMIT {} OFFSET {}",
+                    cond, keyset_sql, order_sql, limit_param, offset_param
+                );
+                let loaded = self.query_items(collection, &sql, &params)?;
+                let mut ol = select_items(
+                    loaded,
+                    sort_key,
+                    &Filter::all(),
+                    None,
+                    fields,
+                    u64::MAX,
+                    limit,
+                );
+                ol.list.total_count = total_count as u64;
+                ol
+            }
+            _ => {
+                debug!(" - selecting in memory");
+                let loaded = self.query_items(
+                    collection,
+                    &format!("SELECT data FROM items WHERE {} ORDER BY id", coll_cond),
+                    &params[..2],
+                )?;
+                select_items(loaded, sort_key, filter, cursor, fields, skip, limit)
+            }
+        };
+        debug!(
+            " - result: {} items, total {}",
+            ol.order.len(),
+            ol.list.total_count
+        );
+        return Ok(ol);
+    }
+
+    async fn set_item(
+        &mut self,
+        collection: &str,
+        exp_itm: &Item,
+        merge: bool,
+    ) -> StoreResult<u64> {
+        let res = self.store_item(collection, exp_itm, merge, None);
+        self.note_result(&res);
+        return res;
+    }
+
+    async fn set_item_rev(
+        &mut self,
+        collection: &str,
+        exp_itm: &Item,
+        merge: bool,
+        rev: u64,
+    ) -> StoreResult<u64> {
+        let res = self.store_item(collection, exp_itm, merge, Some(rev));
+        self.note_result(&res);
+        return res;
+    }
+
+    async fn del_item(&mut self, collection: &str, id: u64) -> StoreResult<bool> {
+        if !self.collections.contains_key(collection) {
+            return Err(StoreError::UnknownCollection(collection.to_string()));
+        }
+        let res = self.conn()?.execute(
+            "DELETE FROM items WHERE collection = ?1 AND id = ?2",
+            params![collection, Self::to_sql_id(id)],
+        );
+        let res = res.map(|cnt| cnt > 0).map_err(StoreError::from);
+        self.note_result(&res);
+        return res;
+    }
+
+    async fn begin_transaction(&mut self) -> StoreResult<()> {
+        if self.in_tx {
+            return Err(StoreError::Transaction("already in progress".to_string()));
+        }
+        self.conn()?.execute_batch("BEGIN IMMEDIATE")?;
+        self.in_tx = true;
+        self.tx_failed = false;
+        return Ok(());
+    }
+
+    async fn commit_transaction(&mut self) -> StoreResult<()> {
+        if !self.in_tx {
+            return Err(StoreError::Transaction("not started".to_string()));
+        }
+        if self.tx_failed {
+            self.abort_transaction().await?;
+            return Err(StoreError::Transaction(
+                "rolled back after failed write".to_string(),
+            ));
+        }
+        let res = self.conn()?.execute_batch("COMMIT");
+        if let Err(e) = res {
+            error!("SQLite commit failed: {}", e);
+            self.abort_transaction().await?;
+            return Err(e.into());
+        }
+        self.in_tx = false;
+        return Ok(());
+    }
+
+    async fn abort_transaction(&mut self) -> StoreResult<()> {
+        if !self.in_tx {
+            return Err(StoreError::Transaction("not started".to_string()));
+        }
+        self.in_tx = false;
+        self.tx_failed = false;
+        self.conn()?.execute_batch("ROLLBACK")?;
+        return Ok(());
+    }
+
+    fn in_transaction(&self) -> bool {
+        return self.in_tx;
+    }
+
+    async fn add_history(&mut self, entry: &HistoryEntry) -> StoreResult<()> {
+        if !self.collections.contains_key(&entry.collection) {
+            return Err(StoreError::UnknownCollection(entry.collection.clone()));
+        }
+        let text = serde_json::to_string(entry)?;
+        let res = self.conn()?.execute(
+            "INSERT INTO history (collection, id, data) VALUES (?1, ?2, ?3)",
+            params![entry.collection, Self::to_sql_id(entry.id), text],
+        );
+        let res = res.map(|_| ()).map_err(StoreError::from);
+        self.note_result(&res);
+        return res;
+    }
+
+    async fn get_history(&mut self, collection: &str, id: u64) -> StoreResult<Vec<HistoryEntry>> {
+        if !self.collections.contains_key(collection) {
+            return Err(StoreError::UnknownCollection(collection.to_string()));
+        }
+
+        let texts: Vec<String> = {
+            let mut stmt = self.conn()?.prepare(
+                "SELECT data FROM history WHERE collection = ?1 AND id = ?2 ORDER BY seq",
+            )?;
+            let rows =
+                stmt.query_map(params![collection, Self::to_sql_id(id)], |row| row.get(0))?;
+            rows.collect::<rusqlite::Result<Vec<String>>>()?
+        };
+
+        let mut entries: Vec<HistoryEntry> = Vec::new();
+        for text in texts {
+            match serde_json::from_str::<HistoryEntry>(&text) {
+                Ok(e) => entries.push(e),
+                Err(e) => {
+                    error!(
+                        "Skipping corrupt history of {} id {}: {}",
+                        collection, id, e
+                    );
+                }
+            }
+        }
+        return Ok(entries);
+    }
+
+    async fn get_collection_history(&mut self, collection: &str) -> StoreResult<Vec<HistoryEntry>> {
+        if !self.collections.contains_key(collection) {
+            return Err(StoreError::UnknownCollection(collection.to_string()));
+        }
+
+        let texts: Vec<String> = {
+            let mut stmt = self
+                .conn()?
+                .prepare("SELECT data FROM history WHERE collection = ?1 ORDER BY id, seq")?;
+            let rows = stmt.query_map(params![collection], |row| row.get(0))?;
+            rows.collect::<rusqlite::Result<Vec<String>>>()?
+        };
+
+        let mut entries: Vec<HistoryEntry> = Vec::new();
+        for text in texts {
+            match serde_json::from_str::<HistoryEntry>(&text) {
+                Ok(e) => entries.push(e),
+                Err(e) => {
+                    error!("Skipping corrupt history of {}: {}", collection, e);
+                }
+            }
+        }
+        return Ok(entries);
+    }
+
+    async fn add_trash(&mut self, entry: &TrashEntry) -> StoreResult<()> {
+        if !self.collections.contains_key(&entry.collection) {
+            return Err(StoreError::UnknownCollection(entry.collection.clone()));
+        }
+        let text = serde_json::to_string(entry)?;
+        let res = self.conn()?.execute(
+            "INSERT INTO trash (collection, id, time, data) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (collection, id) DO UPDATE SET time = excluded.time, data = excluded.data",
+            params![
+                entry.collection,
+                Self::to_sql_id(entry.id),
+                Self::to_sql_id(entry.time),
+                text
+            ],
+        );
+        let res = res.map(|_| ()).map_err(StoreError::from);
+        self.note_result(&res);
+        return res;
+    }
+
+    async fn get_trash(&mut self, collection: &str) -> StoreResult<Vec<TrashEntry>> {
+        if !self.collections.contains_key(collection) {
+            return Err(StoreError::UnknownCollection(collection.to_string()));
+        }
+
+        let texts: Vec<String> = {
+            let mut stmt = self
+                .conn()?
+                .prepare("SELECT data FROM trash WHERE collection = ?1 ORDER BY id")?;
+            let rows = stmt.query_map(params![collection], |row| row.get(0))?;
+            rows.collect::<rusqlite::Result<Vec<String>>>()?
+        };
+
+        let mut entries: Vec<TrashEntry> = Vec::new();
+        for text in texts {
+            match serde_json::from_str::<TrashEntry>(&text) {
+                Ok(e) => entries.push(e),
+                Err(e) => {
+                    error!("Skipping corrupt trash item of {}: {}", collection, e);
+                }
+            }
+        }
+        return Ok(entries);
+    }
+
+    async fn del_trash(&mut self, collection: &str, id: u64) -> StoreResult<bool> {
+        if !self.collections.contains_key(collection) {
+            return Err(StoreError::UnknownCollection(collection.to_string()));
+        }
+        let res = self.conn()?.execute(
+            "DELETE FROM trash WHERE collection = ?1 AND id = ?2",
+            params![collection, Self::to_sql_id(id)],
+        );
+        let res = res.map(|cnt| cnt > 0).map_err(StoreError::from);
+        self.note_result(&res);
+        return res;
+    }
+
+    async fn purge_trash(&mut self, before: u64) -> StoreResult<u64> {
+        let res = self.conn()?.execute(
+            "DELETE FROM trash WHERE time < ?1",
+            params![Self::to_sql_id(before)],
+        );
+        let res = res.map(|cnt| cnt as u64).map_err(StoreError::from);
+        self.note_result(&res);
+        return res;
+    }
+
+    async fn add_migration(&mut self, entry: &MigrationEntry) -> StoreResult<()> {
+        let text = serde_json::to_string(entry)?;
+        let res = self.conn()?.execute(
+            "INSERT INTO migrations (version, data) VALUES (?1, ?2)",
+            params![Self::to_sql_id(entry.version), text],
+        );
+        let res = res.map(|_| ()).map_err(StoreError::from);
+        self.note_result(&res);
+        return res;
+    }
+
+    async fn get_migrations(&mut self) -> StoreResult<Vec<MigrationEntry>> {
+        let texts: Vec<String> = {
+            let mut stmt = self
+                .conn()?
+                .prepare("SELECT data FROM migrations ORDER BY version")?;
+            let rows = stmt.query_map([], |row| row.get(0))?;
+            rows.collect::<rusqlite::Result<Vec<String>>>()?
+        };
+
+        let mut entries: Vec<MigrationEntry> = Vec::new();
+        for text in texts {
+            match serde_json::from_str::<MigrationEntry>(&text) {
+                Ok(e) => entries.push(e),
+                Err(e) => {
+                    error!("Skipping corrupt migration: {}", e);
+                }
+            }
+        }
+        return Ok(entries);
+    }
+
+    async fn get_credentials(&mut self) -> String {
+        return self.local_path.clone() + "/credentials.json";
+    }
+
+    async fn get_pickle(&mut self) -> String {
+        return self.local_path.clone() + "/token.pickle";
+    }
+
+    async fn get_internals(&mut self) -> Item {
+        return self.read_local_item("internals.js").unwrap_or(Item::new());
+    }
+
+    async fn get_settings(&mut self) -> Item {
+        // Settings live in the database once written. Until then, fall back
+        // to the settings file from the data folder.
+        if let Some(conn) = self.conn.as_ref() {
+            let res: rusqlite::Result<Option<String>> = conn
+                .query_row(
+                    "SELECT data FROM settings WHERE name = 'settings'",
+                    [],
+                    |row| row.get(0),
+                )
+                .optional();
+            if let Ok(Some(text)) = res {
+                if let Ok(itm) = serde_json::from_str::<Item>(&text) {
+                    return itm;
+                }
+            }
+        }
+        return self.read_local_item("settings.js").unwrap_or(Item::new());
+    }
+
+    async fn set_internals(&mut self, itm: Item) -> StoreResult<()> {
+        let tmp_data_path = self.local_path.clone() + "/internals.js";
+        let s = serde_json::to_string(&itm)?;
+        write_atomic(&tmp_data_path, s.as_bytes())?;
+        return Ok(());
+    }
+
+    async fn set_settings(&mut self, itm: Item) -> StoreResult<()> {
+        let s = serde_json::to_string(&itm)?;
+        self.conn()?.execute(
+            "INSERT INTO settings (name, data) VALUES ('settings', ?1)
+             ON CONFLICT (name) DO UPDATE SET data = excluded.data",
+            params![s],
+        )?;
+        return Ok(());
+    }
+
+    async fn reload_internals(&mut self) -> StoreResult<()> {
+        let internals = self.get_internals().await;
+        let collections = internals.safe_strstr("collections", &HashMap::new());
+        debug!("Collections: {}", collections.len());
+        for coll_name in collections {
+            self.register_collection(&coll_name.1);
+        }
+        self.create_indexes(&declared_indexes(&internals))?;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn store_with(items: &[Item]) -> StoreSqlite {
+        let mut st = StoreSqlite::new();
+        st.connect("sqlite://:memory:", "").await.unwrap();
+        st.collections.insert("foo".to_string(), 0);
+        for itm in items {
+            st.set_item("foo", itm, false).await.unwrap();
+        }
+        return st;
+    }
+
+    fn item(name: Option<&str>, date: Option<u64>, done: Option<bool>) -> Item {
+        let mut itm = Item::new();
+        itm.id = u64::MAX;
+        if let Some(n) = name {
+            itm.set_str("name", n);
+        }
+        if let Some(d) = date {
+            itm.set_u64("date", d);
+        }
+        if let Some(b) = done {
+            itm.set_bool("done", b);
+        }
+        return itm;
+    }
+
+    fn sample() -> Vec<Item> {
+        return vec![
+            item(Some("b"), Some(3), Some(true)),
+            item(Some("a"), Some(1), None),
+            item(None, Some(3), Some(false)),
+            item(Some("c"), None, Some(true)),
+            item(Some("a"), Some(2), Some(false)),
+            item(Some("B"), Some(3), None),
+            item(Some(""), Some(0), Some(true)),
+        ];
+    }
+
+    #[actix_rt::test]
+    async fn sql_selection_matches_memory() {
+        let mut st = store_with(&sample()).await;
+        let all: Vec<Item> = st
+            .get_all_items("foo", "", &Filter::all())
+            .await
+            .unwrap()
+            .map
+            .into_values()
+            .collect();
+        let mut all_by_id = all.clone();
+        all_by_id.sort_by_key(|i| i.id);
+
+        let filters = [
+            "",
+            r#"{"strs.name":"a"}"#,
+            r#"{"strs.name":{"$ne":"a"}}"#,
+            r#"{"strs.name":null}"#,
+            r#"{"u64s.date":{"$gte":2,"$lt":4}}"#,
+            r#"{"u64s.date":{"$lte":null}}"#,
+            r#"{"strs.name":{"$in":["a","c",null]}}"#,
+            r#"{"strs.name":{"$nin":["a","b"]}}"#,
+            r#"{"bools.done":true}"#,
+            r#"{"bools.done":{"$gt":false}}"#,
+            r#"{"bools.done":{"$exists":false}}"#,
+            r#"{"u64s.date":{"$not":{"$gt":1}}}"#,
+            r#"{"$or":[{"strs.name":"c"},{"u64s.date":3}]}"#,
+            r#"{"$nor":[{"strs.name":"a"},{"bools.done":false}]}"#,
+            r#"{"strs.name":3}"#,
+            r#"{"strs.odd-name":{"$exists":false}}"#,
+        ];
+        let sorts = ["", "strs.name", "-u64s.date,strs.name", "-bools.done", "id"];
+        for text in filters {
+            let filter = Filter::parse(text).unwrap();
+            for sort_key in sorts {
+                let expected = select_items(all_by_id.clone(), sort_key, &filter, None, &[], 1, 3);
+                let got = st
+                    .get_items_ordered(
+                        "foo",
+                        u64::MAX,
+                        u64::MAX,
+                        sort_key,
+                        &filter,
+                        None,
+                        &[],
+                        1,
+                        3,
+                    )
+                    .await
+                    .unwrap();
+                assert_eq!(got.order, expected.order, "{} sorted by {}", text, sort_key);
+                assert_eq!(got.list.total_count, expected.list.total_count);
+                assert_eq!(got.next_cursor, expected.next_cursor);
+
+                // Paging with cursors goes through the same items
+                let mut seen: Vec<u64> = Vec::new();
+                let mut cursor: Option<ListCursor> = None;
+                loop {
+                    let ol = st
+                        .get_items_ordered(
+                            "foo",
+                            u64::MAX,
+                            u64::MAX,
+                            sort_key,
+                            &filter,
+                            cursor.as_ref(),
+                            &[],
+                            u64::MAX,
+                            2,
+                        )
+                        .await
+                        .unwrap();
+                    seen.extend(ol.order.iter());
+                    if ol.next_cursor == "" {
+                        break;
+                    }
+                    cursor = ListCursor::decode(&ol.next_cursor, sort_key);
+                }
+                let expected = select_items(
+                    all_by_id.clone(),
+                    sort_key,
+                    &filter,
+                    None,
+                    &[],
+                    u64::MAX,
+                    u64::MAX,
+                );
+                assert_eq!(seen, expected.order, "{} paged by {}", text, sort_key);
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn sql_uses_declared_indexes() {
+        let st = store_with(&sample()).await;
+        st.create_indexes(&[IndexDef {
+            collection: "foo".to_string(),
+            fields: vec!["strs.name".to_string()],
+            unique: false,
+            case_insensitive: false,
+        }])
+        .unwrap();
+
+        let mut params: Vec<SqlValue> = Vec::new();
+        let cond = StoreSqlite::filter_to_sql(&Filter::eq("strs.name", "a"), &mut params).unwrap();
+        let plan: Vec<String> = {
+            let mut stmt = st
+                .conn()
+                .unwrap()
+                .prepare(&format!(
+                    "EXPLAIN QUERY PLAN SELECT data FROM items WHERE collection = 'foo' AND {}",
+                    cond
+                ))
+                .unwrap();
+            let rows = stmt
+                .query_map(params_from_iter(params.iter()), |row| {
+                    row.get::<_, String>(3)
+                })
+                .unwrap();
+            rows.map(|r| r.unwrap()).collect()
+        };
+        assert!(
+            plan.iter().any(|p| p.contains("index_foo_strs_name")),
+            "{:?}",
+            plan
+        );
+    }
+
+    #[actix_rt::test]
+    async fn revision_check() {
+        let mut st = store_with(&[item(Some("a"), None, None)]).await;
+        let mut itm = st.get_item("foo", 1).await.unwrap().unwrap();
+        itm.set_str("name", "b");
+        assert!(st.set_item_rev("foo", &itm, true, 1).await.is_ok());
+        assert!(matches!(
+            st.set_item_rev("foo", &itm, true, 1).await,
+            Err(StoreError::Conflict(_))
+        ));
+        assert!(!st.in_transaction());
+        assert_eq!(
+            st.get_item("foo", 1).await.unwrap().unwrap().strs["name"],
+            "b"
+        );
+    }
+}
@@ -0,0 +1,56 @@
+/*
+ * Isabelle project
+ *
+ * Copyright 2023-2025 Maxim Menshikov
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining
+ * a copy of this software and associated documentation files (the “Software”),
+ * to deal in the Software without restriction, including without limitation
+ * the rights to use, copy, modify, merge, publish, distribute, sublicense,
+ * and/or sell copies of the Software, and to permit persons to whom the
+ * Software is furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included
+ * in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS
+ * OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+ * FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+ * DEALINGS IN THE SOFTWARE.
+ */
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+
+/// Suffix of temporary files used while writing
+pub const TMP_SUFFIX: &str = ".tmp";
+
+/// Flush directory entries (new files, renames) to disk
+pub fn sync_dir(path: &str) {
+    if let Ok(dir) = File::open(path) {
+        let _res = dir.sync_all();
+    }
+}
+
+/// Write file atomically: the data goes to a temporary file first, which
+/// is synced and then renamed over the target. Readers see either the old
+/// or the new contents, never a truncated file.
+pub fn write_atomic(path: &str, data: &[u8]) -> std::io::Result<()> {
+    let tmp_path = path.to_string() + TMP_SUFFIX;
+    {
+        let mut f = File::create(&tmp_path)?;
+        f.write_all(data)?;
+        f.sync_all()?;
+    }
+    if let Err(e) = fs::rename(&tmp_path, path) {
+        let _res = fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+    if let Some(parent) = Path::new(path).parent() {
+        sync_dir(&parent.to_string_lossy());
+    }
+    return Ok(());
+}
@@ -22,3 +22,4 @@
  * DEALINGS IN THE SOFTWARE.
  */
 pub mod crypto;
+pub mod file;